@@ -1,31 +1,173 @@
 use anyhow::Result;
-use bytes::Bytes;
-use clap::Parser;
-use quic3::encode_header;
-use s2n_quic::client::{Client, Connect};
+use clap::{Parser, Subcommand, ValueEnum};
+use quic3::compression::Compression;
+use quic3::sender::{Delivery, SendOptions, Sender, SyncOptions, Upload, collect_uploads};
+use quic3::source::ReaderSource;
+use quic3::{FileEntry, NegotiationStatus, Placement, sanitize_relative_path};
+use s2n_quic::client::Client;
+use s2n_quic::provider::tls::default as tls;
+use std::collections::HashSet;
 use std::net::SocketAddr;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+use time::OffsetDateTime;
+use time::format_description::well_known::Rfc3339;
 use tokio::fs;
-use tokio::io::{AsyncReadExt, BufReader};
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
 use tracing_subscriber::{EnvFilter, fmt};
 
+/// Send files to a quic3 server, keep a directory in step with `sync`, fetch
+/// files it exports with `get`, or see what it stores with `list` and `stat`.
 #[derive(Parser, Debug)]
+#[command(subcommand_negates_reqs = true)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     /// QUIC server address (e.g. 127.0.0.1:4433)
-    #[arg(long, default_value = "127.0.0.1:4433")]
+    #[arg(long, global = true, default_value = "127.0.0.1:4433")]
     server: SocketAddr,
 
     /// Expected server name for TLS validation.
-    #[arg(long, default_value = "localhost")]
+    #[arg(long, global = true, default_value = "localhost")]
     server_name: String,
 
-    /// Path to the server certificate used for validation.
-    #[arg(long, default_value = "certs/server-cert.pem")]
+    /// CA certificate (or the self-signed server certificate) used to validate the server.
+    #[arg(long, global = true, default_value = "certs/server-cert.pem")]
     ca_cert: PathBuf,
 
-    /// Path to the file that should be sent.
+    /// Certificate presented to servers that require client authentication.
+    #[arg(long, global = true, requires = "client_key")]
+    client_cert: Option<PathBuf>,
+
+    /// Private key matching `--client-cert`.
+    #[arg(long, global = true, requires = "client_cert")]
+    client_key: Option<PathBuf>,
+
+    /// Path to a file to send; may be repeated. Directories are sent
+    /// recursively and glob patterns are expanded.
+    #[arg(long = "file", required_unless_present_any = ["manifest", "stdin"])]
+    files: Vec<PathBuf>,
+
+    /// File listing one path or glob pattern to send per line; blank lines and
+    /// lines starting with '#' are ignored.
+    #[arg(long)]
+    manifest: Option<PathBuf>,
+
+    /// Send standard input, read until end of file, and store it under this
+    /// name. It is sent in a single attempt, without resuming or retries.
+    #[arg(long, value_name = "NAME")]
+    stdin: Option<String>,
+
+    /// Maximum number of files transferred concurrently over the connection.
+    #[arg(long, global = true, default_value_t = 4)]
+    concurrency: usize,
+
+    /// How many times to reconnect and resume after a failed attempt.
+    #[arg(long, global = true, default_value_t = 5)]
+    retries: u32,
+
+    /// Discard any partial upload the server holds and start from byte zero.
+    #[arg(long)]
+    restart: bool,
+
+    /// Number of concurrent streams a large file is split across.
+    #[arg(long, default_value_t = 1)]
+    streams: usize,
+
+    /// For files of 1 MiB or more that the server already holds a copy of,
+    /// send only the blocks that changed. Such files use a single stream and
+    /// start over after a lost connection.
+    #[arg(long, global = true)]
+    delta: bool,
+
+    /// Replace files the server already stores under the same name, even if
+    /// its conflict policy would refuse or rename them.
     #[arg(long)]
-    file: PathBuf,
+    overwrite: bool,
+
+    /// Send each file's permissions, times, owner and extended attributes.
+    /// The server applies those it is configured to keep.
+    #[arg(long, global = true)]
+    preserve: bool,
+
+    /// Compress file contents on the wire. Files that are compressed
+    /// already are sent as they are.
+    #[arg(long, global = true, value_enum)]
+    compress: Option<Codec>,
+
+    /// Zstandard compression level, from 1 (fastest) to 22 (smallest).
+    #[arg(long, global = true, default_value_t = 3, value_parser = clap::value_parser!(i32).range(1..=22))]
+    compress_level: i32,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum Codec {
+    Zstd,
+    Lz4,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Send only the files of a directory that the server is missing or
+    /// holds an older or different copy of.
+    Sync {
+        /// File or directory to sync; it is stored under its own name, as
+        /// with --file.
+        path: PathBuf,
+
+        /// Remove stored files below the directory that no longer exist
+        /// locally. The server must be started with --allow-delete.
+        #[arg(long)]
+        delete: bool,
+
+        /// Compare the checksums of files whose size matches instead of their
+        /// modification times, which reads every such file on both sides.
+        #[arg(long)]
+        checksum: bool,
+    },
+    /// Fetch files from the server's export directory.
+    Get {
+        /// Names of the files to fetch, relative to the export directory.
+        #[arg(required = true)]
+        names: Vec<String>,
+
+        /// Directory the files are written to, under their relative names.
+        #[arg(long, default_value = ".")]
+        output: PathBuf,
+    },
+    /// List the files the server has stored.
+    List {
+        /// Only list files below this directory.
+        #[arg(default_value = "")]
+        prefix: String,
+
+        #[command(flatten)]
+        format: Format,
+    },
+    /// Describe stored files; exits non-zero if any of them does not exist.
+    Stat {
+        /// Names of the files, relative to the server's output directory.
+        #[arg(required = true)]
+        names: Vec<String>,
+
+        #[command(flatten)]
+        format: Format,
+    },
+}
+
+/// How `list` and `stat` print files.
+#[derive(clap::Args, Debug)]
+struct Format {
+    /// Print JSON instead of a table.
+    #[arg(long)]
+    json: bool,
+
+    /// Have the server compute BLAKE3 checksums, which reads every file.
+    #[arg(long)]
+    checksum: bool,
 }
 
 #[tokio::main]
@@ -41,18 +183,36 @@ async fn main() -> Result<()> {
         anyhow::bail!("CA certificate not found at {}", args.ca_cert.display());
     }
 
-    let metadata = fs::metadata(&args.file).await?;
-    let file_name = args
-        .file
-        .file_name()
-        .ok_or_else(|| anyhow::anyhow!("file path missing file name"))?
-        .to_string_lossy()
-        .to_string();
-
-    let header = encode_header(&file_name, metadata.len())?;
+    match &args.command {
+        Some(Command::Sync {
+            path,
+            delete,
+            checksum,
+        }) => {
+            let options = SyncOptions {
+                checksum: *checksum,
+                delete: *delete,
+            };
+            sync(&args, path, &options).await
+        }
+        Some(Command::Get { names, output }) => get(&args, names, output).await,
+        Some(Command::List { prefix, format }) => list(&args, prefix, format).await,
+        Some(Command::Stat { names, format }) => stat(&args, names, format).await,
+        None => send(&args).await,
+    }
+}
 
+/// Connect to the server named on the command line.
+fn connect(args: &Args) -> Result<Arc<Sender>> {
+    let mut tls = tls::Client::builder().with_certificate(args.ca_cert.as_path())?;
+    if let (Some(cert), Some(key)) = (&args.client_cert, &args.client_key) {
+        tls = tls.with_client_identity(cert.as_path(), key.as_path())?;
+        // Present the certificate when asked, but still talk to servers that do not ask.
+        tls.config_mut()
+            .set_client_auth_type(tls::enums::ClientAuthType::Optional)?;
+    }
     let client = Client::builder()
-        .with_tls(args.ca_cert.as_path())?
+        .with_tls(tls.build()?)?
         .with_io("0.0.0.0:0")?
         .start()?;
 
@@ -61,33 +221,316 @@ async fn main() -> Result<()> {
         args.server, args.server_name
     );
 
-    let connect = Connect::new(args.server).with_server_name(args.server_name.clone());
-    let mut connection = client.connect(connect).await?;
+    let options = SendOptions {
+        retries: args.retries,
+        restart: args.restart,
+        streams: args.streams,
+        delta: args.delta,
+        metadata: args.preserve,
+        compression: args.compress.map(|codec| match codec {
+            Codec::Zstd => Compression::Zstd {
+                level: args.compress_level,
+            },
+            Codec::Lz4 => Compression::Lz4,
+        }),
+    };
+    Ok(Arc::new(Sender::new(
+        client,
+        args.server,
+        args.server_name.clone(),
+        options,
+    )))
+}
 
-    let mut stream = connection.open_bidirectional_stream().await?;
-    stream.send(Bytes::from(header)).await?;
+async fn send(args: &Args) -> Result<()> {
+    let mut uploads = Vec::new();
+    let mut seen = HashSet::new();
+    for path in expand_inputs(&args.files, args.manifest.as_deref()).await? {
+        for upload in collect_uploads(&path).await? {
+            if seen.insert(upload.transfer_id) {
+                uploads.push(upload);
+            }
+        }
+    }
+    if let Some(name) = &args.stdin {
+        uploads.push(Upload::new(name.clone(), ReaderSource::stdin()));
+    }
+    for upload in &mut uploads {
+        upload.overwrite = args.overwrite;
+    }
+    if uploads.is_empty() {
+        anyhow::bail!("no files to send");
+    }
 
-    let mut reader = BufReader::new(fs::File::open(&args.file).await?);
-    let mut buffer = vec![0u8; 64 * 1024];
-    let mut total_sent: u64 = 0;
+    let sender = connect(args)?;
+    let total = uploads.len();
+    let results = sender.send_all(uploads, args.concurrency).await?;
 
-    loop {
-        let bytes_read = reader.read(&mut buffer).await?;
-        if bytes_read == 0 {
-            break;
-        }
+    println!("\nSummary:");
+    let failed = print_deliveries(args, &results);
+    println!("{} of {total} file(s) sent", total - failed);
 
-        stream
-            .send(Bytes::copy_from_slice(&buffer[..bytes_read]))
-            .await?;
-        total_sent += bytes_read as u64;
+    if failed > 0 {
+        anyhow::bail!("{failed} of {total} file(s) could not be sent");
     }
+    Ok(())
+}
+
+async fn sync(args: &Args, path: &Path, options: &SyncOptions) -> Result<()> {
+    let sender = connect(args)?;
+    let report = sender.sync(path, options, args.concurrency).await?;
 
-    stream.close().await?;
+    println!("\nSummary:");
+    let failed = print_deliveries(args, &report.sent);
+    for name in &report.removed {
+        println!("  removed {:>14}  {name}", "-");
+    }
+    let total = report.sent.len();
     println!(
-        "Sent '{}' ({} bytes) to {}",
-        file_name, total_sent, args.server
+        "{} of {total} file(s) sent, {} unchanged, {} removed",
+        total - failed,
+        report.unchanged,
+        report.removed.len()
     );
 
+    if failed > 0 {
+        anyhow::bail!("{failed} of {total} file(s) could not be sent");
+    }
     Ok(())
 }
+
+/// Print one summary line per upload and return how many failed.
+fn print_deliveries(args: &Args, results: &[(Upload, quic3::Result<Delivery>)]) -> usize {
+    let mut failed = 0;
+    for (upload, result) in results {
+        match result {
+            Ok(delivery) => {
+                let note = match delivery.response.placement {
+                    Some(Placement::Overwritten) => " (replaced)",
+                    Some(Placement::Renamed) => " (renamed, name taken)",
+                    Some(Placement::Versioned) => " (previous version kept)",
+                    Some(Placement::Created) | None => "",
+                };
+                println!(
+                    "  ok      {:>14}  {} -> {}{note}",
+                    delivery.response.bytes_stored, upload.file_name, delivery.response.detail
+                );
+            }
+            Err(err) => {
+                failed += 1;
+                let hint = match err {
+                    quic3::Error::Rejected {
+                        status: NegotiationStatus::InvalidRange,
+                        ..
+                    } if args.streams > 1 => "; retry with --streams 1",
+                    quic3::Error::Rejected {
+                        status: NegotiationStatus::AlreadyExists,
+                        ..
+                    } if !upload.overwrite => "; pass --overwrite to replace it",
+                    _ => "",
+                };
+                println!("  failed  {:>14}  {}: {err}{hint}", "-", upload.file_name);
+            }
+        }
+    }
+    failed
+}
+
+async fn get(args: &Args, names: &[String], output: &Path) -> Result<()> {
+    let sender = connect(args)?;
+    let limit = Arc::new(Semaphore::new(args.concurrency.max(1)));
+    let mut tasks = JoinSet::new();
+    for (index, name) in names.iter().cloned().enumerate() {
+        let permit = Arc::clone(&limit).acquire_owned().await?;
+        let sender = Arc::clone(&sender);
+        let dest = sanitize_relative_path(&name).map(|relative| output.join(relative));
+        tasks.spawn(async move {
+            let result = match dest {
+                Ok(dest) => sender.get(&name, &dest).await,
+                Err(err) => Err(err.into()),
+            };
+            drop(permit);
+            (index, name, result)
+        });
+    }
+    let mut results = Vec::new();
+    while let Some(joined) = tasks.join_next().await {
+        results.push(joined?);
+    }
+    results.sort_by_key(|(index, ..)| *index);
+
+    println!("\nSummary:");
+    let total = results.len();
+    let mut failed = 0;
+    for (_, name, result) in &results {
+        match result {
+            Ok(download) => println!(
+                "  ok      {:>14}  {name} -> {}",
+                download.size,
+                download.path.display()
+            ),
+            Err(err) => {
+                failed += 1;
+                println!("  failed  {:>14}  {name}: {err}", "-");
+            }
+        }
+    }
+    println!("{} of {total} file(s) fetched", total - failed);
+
+    if failed > 0 {
+        anyhow::bail!("{failed} of {total} file(s) could not be fetched");
+    }
+    Ok(())
+}
+
+async fn list(args: &Args, prefix: &str, format: &Format) -> Result<()> {
+    let entries = connect(args)?.list(prefix, format.checksum).await?;
+    if format.json {
+        let entries: Vec<_> = entries.iter().map(entry_json).collect();
+        println!("{}", serde_json::to_string_pretty(&entries)?);
+    } else {
+        let rows = entries
+            .iter()
+            .map(|entry| (entry.name.as_str(), Ok(Some(entry))));
+        print_table(rows, format.checksum);
+    }
+    Ok(())
+}
+
+async fn stat(args: &Args, names: &[String], format: &Format) -> Result<()> {
+    let sender = connect(args)?;
+    let mut found = Vec::new();
+    for name in names {
+        found.push(sender.stat(name, format.checksum).await);
+    }
+
+    if format.json {
+        let entries: Vec<_> = names
+            .iter()
+            .zip(&found)
+            .map(|(name, entry)| match entry {
+                Ok(Some(entry)) => entry_json(entry),
+                Ok(None) => serde_json::json!({ "name": name, "exists": false }),
+                Err(err) => {
+                    serde_json::json!({ "name": name, "exists": false, "error": err.to_string() })
+                }
+            })
+            .collect();
+        println!("{}", serde_json::to_string_pretty(&entries)?);
+    } else {
+        let rows = names.iter().zip(&found).map(|(name, entry)| {
+            let entry = entry
+                .as_ref()
+                .map(Option::as_ref)
+                .map_err(|err| err.to_string());
+            (name.as_str(), entry)
+        });
+        print_table(rows, format.checksum);
+    }
+
+    let missing = found
+        .iter()
+        .filter(|entry| !matches!(entry, Ok(Some(_))))
+        .count();
+    if missing > 0 {
+        anyhow::bail!("{missing} of {} file(s) not found", names.len());
+    }
+    Ok(())
+}
+
+fn print_table<'a>(
+    rows: impl Iterator<Item = (&'a str, Result<Option<&'a FileEntry>, String>)>,
+    checksum: bool,
+) {
+    let digest_column = |digest: &str| {
+        if checksum {
+            format!("{digest:<64}  ")
+        } else {
+            String::new()
+        }
+    };
+    println!(
+        "{:>14}  {:<20}  {}NAME",
+        "SIZE",
+        "MODIFIED",
+        digest_column("BLAKE3")
+    );
+    for (name, entry) in rows {
+        let entry = match entry {
+            Ok(Some(entry)) => entry,
+            Ok(None) => {
+                let digest = digest_column("-");
+                println!("{:>14}  {:<20}  {digest}{name} (not found)", "-", "-");
+                continue;
+            }
+            Err(err) => {
+                let digest = digest_column("-");
+                println!("{:>14}  {:<20}  {digest}{name} ({err})", "-", "-");
+                continue;
+            }
+        };
+        let modified = entry.modified.map(format_time).unwrap_or("-".into());
+        let digest = entry.checksum.as_ref().map(|digest| hex(digest));
+        println!(
+            "{:>14}  {modified:<20}  {}{name}",
+            entry.size,
+            digest_column(digest.as_deref().unwrap_or("-"))
+        );
+    }
+}
+
+fn entry_json(entry: &FileEntry) -> serde_json::Value {
+    serde_json::json!({
+        "name": entry.name,
+        "exists": true,
+        "size": entry.size,
+        "modified": entry.modified.map(format_time),
+        "checksum": entry.checksum.as_ref().map(|checksum| hex(checksum)),
+    })
+}
+
+/// Render a time as RFC 3339 in UTC, to the second.
+fn format_time(time: SystemTime) -> String {
+    let time = OffsetDateTime::from(time);
+    time.replace_nanosecond(0)
+        .unwrap_or(time)
+        .format(&Rfc3339)
+        .unwrap_or_else(|_| "-".into())
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// Expand `--file` arguments and manifest entries into paths. Entries
+/// containing glob metacharacters are treated as patterns.
+async fn expand_inputs(files: &[PathBuf], manifest: Option<&Path>) -> Result<Vec<PathBuf>> {
+    let mut entries = files.to_vec();
+    if let Some(manifest) = manifest {
+        let contents = fs::read_to_string(manifest).await?;
+        entries.extend(
+            contents
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                .map(PathBuf::from),
+        );
+    }
+
+    let mut paths = Vec::new();
+    for entry in entries {
+        let Some(pattern) = entry.to_str().filter(|s| s.contains(['*', '?', '['])) else {
+            paths.push(entry);
+            continue;
+        };
+
+        let matched = paths.len();
+        for path in glob::glob(pattern)? {
+            paths.push(path?);
+        }
+        if paths.len() == matched {
+            anyhow::bail!("pattern '{pattern}' did not match any files");
+        }
+    }
+    Ok(paths)
+}
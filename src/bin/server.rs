@@ -1,19 +1,34 @@
-use anyhow::Result;
-use clap::Parser;
-use quic3::{FileHeader, ensure_self_signed_certificate, sanitize_file_name, try_decode_header};
+use anyhow::{Context, Result};
+use clap::{CommandFactory, FromArgMatches, Parser, ValueEnum};
+use quic3::ensure_self_signed_certificate;
+use quic3::receiver::{
+    AnyClientName, ClientIdentities, ConflictPolicy, Limits, PreserveMetadata, Receiver,
+    ReceiverOptions, Timeouts,
+};
+use quic3::storage::{LocalStorage, ObjectStoreSink, StorageSink};
 use s2n_quic::Server;
+use s2n_quic::provider::tls::default as tls;
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::ffi::OsString;
 use std::net::SocketAddr;
-use std::path::PathBuf;
-use std::sync::Arc;
-use tokio::fs::{self, File};
-use tokio::io::AsyncWriteExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::task::JoinHandle;
 use tracing_subscriber::{EnvFilter, fmt};
 
 #[derive(Parser, Debug)]
 struct Args {
-    /// Address to listen on (e.g. 0.0.0.0:4433)
+    /// Read settings from this TOML file. Its keys are the long names of the
+    /// other flags, e.g. `max-file-size = "10G"`, and flags given on the
+    /// command line take precedence. Reloaded on SIGHUP.
+    #[arg(long)]
+    config: Option<PathBuf>,
+
+    /// Address to listen on (e.g. 0.0.0.0:4433); may be repeated.
     #[arg(long, default_value = "0.0.0.0:4433")]
-    addr: SocketAddr,
+    addr: Vec<SocketAddr>,
 
     /// Path to the TLS certificate. Generated automatically if missing.
     #[arg(long, default_value = "certs/server-cert.pem")]
@@ -24,128 +39,625 @@ struct Args {
     key: PathBuf,
 
     /// Directory where received files will be written.
-    #[arg(long, default_value = "received")]
+    #[arg(long, default_value = "received", conflicts_with = "store")]
     output: PathBuf,
+
+    /// Write received files to an object store instead of --output, e.g.
+    /// s3://bucket/prefix. Resumable and multi-stream uploads are not offered.
+    #[arg(long)]
+    store: Option<String>,
+
+    /// How long interrupted uploads are kept for resumption, in hours.
+    #[arg(long, default_value_t = 24)]
+    partial_ttl_hours: u64,
+
+    /// Subject alternative names of the self-signed certificate generated when
+    /// --cert and --key do not exist; may be repeated.
+    #[arg(long = "san", default_values = ["localhost", "127.0.0.1"])]
+    subject_alt_names: Vec<String>,
+
+    /// Directory whose files clients may fetch with `client get`.
+    #[arg(long)]
+    export: Option<PathBuf>,
+
+    /// Let `client sync --delete` remove stored files that are missing from
+    /// the client's copy of the directory.
+    #[arg(long)]
+    allow_delete: bool,
+
+    /// What to do when an upload's name is already taken: refuse it, replace
+    /// the stored file, store the upload under a numbered name, or keep the
+    /// stored file as a numbered version. Clients can always ask to overwrite.
+    #[arg(long, value_enum, default_value_t = OnConflict::Overwrite)]
+    on_conflict: OnConflict,
+
+    /// File attributes sent by `client --preserve` to apply to stored files,
+    /// e.g. `--preserve mode,times`. Only modification times, which `sync`
+    /// always sends, are applied by default; `times` adds access times.
+    #[arg(long, value_enum, value_delimiter = ',')]
+    preserve: Vec<Attribute>,
+
+    /// Largest file accepted. Sizes take an optional K, M, G or T suffix
+    /// (powers of 1024).
+    #[arg(long, value_parser = parse_size)]
+    max_file_size: Option<u64>,
+
+    /// Connections open at once, in total.
+    #[arg(long)]
+    max_connections: Option<usize>,
+
+    /// Connections open at once from one IP address.
+    #[arg(long)]
+    max_connections_per_peer: Option<usize>,
+
+    /// Transfers and requests served at once, in total.
+    #[arg(long)]
+    max_streams: Option<usize>,
+
+    /// Transfers and requests served at once for one IP address.
+    #[arg(long)]
+    max_streams_per_peer: Option<usize>,
+
+    /// Total size of the stored files, counting those already there.
+    #[arg(long, value_parser = parse_size)]
+    quota: Option<u64>,
+
+    /// Bytes one client may store while the server runs. Clients are told
+    /// apart by certificate identity, or else by IP address.
+    #[arg(long, value_parser = parse_size)]
+    quota_per_client: Option<u64>,
+
+    /// Refuse uploads that would leave less free space than this in the
+    /// output directory.
+    #[arg(long, value_parser = parse_size)]
+    min_free_space: Option<u64>,
+
+    /// Drop streams whose header has not fully arrived this many seconds
+    /// after they were opened.
+    #[arg(long, default_value_t = 10, value_parser = clap::value_parser!(u64).range(1..))]
+    header_timeout_secs: u64,
+
+    /// Drop transfers that send no data for this many seconds.
+    #[arg(long, default_value_t = 60, value_parser = clap::value_parser!(u64).range(1..))]
+    idle_timeout_secs: u64,
+
+    /// On SIGINT or SIGTERM, give transfers in progress this many seconds to
+    /// finish before aborting them.
+    #[arg(long, default_value_t = 30)]
+    shutdown_grace_secs: u64,
+
+    /// Require clients to present a certificate signed by this CA.
+    #[arg(long)]
+    client_ca: Option<PathBuf>,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum OnConflict {
+    Reject,
+    Overwrite,
+    Rename,
+    Version,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum Attribute {
+    Mode,
+    Times,
+    Owner,
+    Xattrs,
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    let args = Args::parse();
+    // Mistakes on the command line, --help and --version end the process the
+    // way clap reports them; only a reload has to outlive them.
+    Args::try_parse().unwrap_or_else(|err| err.exit());
+    let args = load_args()?;
     fmt()
         .with_env_filter(EnvFilter::from_default_env())
         .with_target(false)
         .compact()
         .init();
 
-    fs::create_dir_all(&args.output).await?;
-    let output_dir = Arc::new(args.output);
-    let (cert_path, key_path) =
-        ensure_self_signed_certificate(&args.cert, &args.key, &["localhost", "127.0.0.1"])?;
-
-    let mut server = Server::builder()
-        .with_tls((cert_path.as_path(), key_path.as_path()))?
-        .with_io(args.addr)?
-        .start()?;
-
-    println!("Server listening on {}", args.addr);
-
-    while let Some(mut connection) = server.accept().await {
-        let output_dir = Arc::clone(&output_dir);
-        tokio::spawn(async move {
-            let remote_addr = match connection.remote_addr() {
-                Ok(addr) => addr,
-                Err(err) => {
-                    eprintln!("Failed to read peer address: {err}");
-                    return;
-                }
-            };
+    match &args.store {
+        Some(url) => run(ObjectStoreSink::from_url(url)?, args).await,
+        None => run(LocalStorage::new(&args.output).await?, args).await,
+    }
+}
+
+async fn run<S: StorageSink>(storage: S, mut args: Args) -> Result<()> {
+    let receiver = Arc::new(Receiver::new(storage, receiver_options(&args)));
+    let partial_ttl = Duration::from_secs(args.partial_ttl_hours * 60 * 60);
+    let removed = receiver.remove_stale_staging_files(partial_ttl).await?;
+    if removed > 0 {
+        println!("Removed {removed} incomplete transfer(s) from a previous run");
+    }
+
+    let tls = TlsConfig(Arc::new(Mutex::new(tls_config(&args)?)));
+    let mut listeners = HashMap::new();
+    for &addr in &args.addr {
+        listeners.insert(addr, serve(&receiver, bind(&tls, addr)?, addr));
+    }
+    if let Some(url) = &args.store {
+        println!("Storing received files in {url}");
+    }
+    if let Some(export) = &args.export {
+        println!("Exporting files from {}", export.display());
+    }
+    if let Some(client_ca) = &args.client_ca {
+        println!(
+            "Requiring client certificates signed by {}",
+            client_ca.display()
+        );
+    }
 
-            println!("Accepted connection from {remote_addr}");
-            while let Ok(Some(stream)) = connection.accept_bidirectional_stream().await {
-                let output_dir = Arc::clone(&output_dir);
-                tokio::spawn(handle_stream(stream, remote_addr, output_dir));
+    let mut signals = Signals::new()?;
+    loop {
+        match signals.next().await? {
+            Signal::Reload => {
+                reload_settings(&receiver, &tls, &mut listeners, &mut args, load_args());
+            }
+            Signal::Shutdown(name) => {
+                println!("Received {name}, shutting down");
+                break;
             }
-        });
+        }
     }
 
+    for listener in listeners.into_values() {
+        listener.abort();
+    }
+    let grace = Duration::from_secs(args.shutdown_grace_secs);
+    let summary = receiver.shut_down(grace).await;
+    println!(
+        "Shut down: {} transfer(s) finished, {} aborted",
+        summary.finished, summary.aborted
+    );
     Ok(())
 }
 
-async fn handle_stream(
-    mut stream: s2n_quic::stream::BidirectionalStream,
-    remote_addr: SocketAddr,
-    output_dir: Arc<PathBuf>,
-) {
-    let mut buffer = Vec::new();
-    let header: FileHeader;
-    let consumed: usize;
+/// Parse the process's command line; see [`parse_args`].
+fn load_args() -> Result<Args> {
+    parse_args(std::env::args_os())
+}
 
-    loop {
-        match stream.receive().await {
-            Ok(Some(data)) => {
-                buffer.extend_from_slice(&data);
-                if let Some((parsed, used)) = try_decode_header(&buffer) {
-                    header = parsed;
-                    consumed = used;
-                    break;
-                }
-            }
-            Ok(None) => {
-                eprintln!("[{remote_addr}] connection closed before header received");
-                return;
-            }
-            Err(err) => {
-                eprintln!("[{remote_addr}] failed to read stream: {err}");
-                return;
-            }
+/// Parse the command line `argv`, taking the defaults of the flags it leaves
+/// out from the `--config` file if one is given.
+///
+/// Errors are returned rather than ending the process, so that a bad edit
+/// noticed on reload leaves the server running.
+fn parse_args<T: Into<OsString>>(argv: impl IntoIterator<Item = T>) -> Result<Args> {
+    let argv: Vec<OsString> = argv.into_iter().map(Into::into).collect();
+    let args = Args::try_parse_from(&argv)?;
+    let Some(path) = &args.config else {
+        return Ok(args);
+    };
+    let command = with_config(Args::command(), path)?;
+    let matches = command
+        .try_get_matches_from(&argv)
+        .with_context(|| format!("invalid setting in {}", path.display()))?;
+    Ok(Args::from_arg_matches(&matches)?)
+}
+
+/// Make the settings in the TOML file at `path` the defaults of the flags
+/// of `command` they are named after.
+fn with_config(mut command: clap::Command, path: &Path) -> Result<clap::Command> {
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("failed to read {}", path.display()))?;
+    let table: toml::Table = text
+        .parse()
+        .with_context(|| format!("failed to parse {}", path.display()))?;
+    for (key, value) in table {
+        let id = command
+            .get_arguments()
+            .find(|arg| arg.get_long() == Some(key.as_str()) && arg.get_id() != "config")
+            .map(|arg| arg.get_id().clone())
+            .with_context(|| format!("unknown setting '{key}' in {}", path.display()))?;
+        let values = match value {
+            toml::Value::Array(items) => items
+                .into_iter()
+                .map(|item| setting(&key, item))
+                .collect::<Result<Vec<_>>>()?,
+            value => vec![setting(&key, value)?],
+        };
+        command = command.mut_arg(id, |arg| arg.default_values(values));
+    }
+    Ok(command)
+}
+
+/// One value of a setting, as it would be written on the command line.
+fn setting(key: &str, value: toml::Value) -> Result<String> {
+    match value {
+        toml::Value::String(value) => Ok(value),
+        toml::Value::Integer(value) => Ok(value.to_string()),
+        toml::Value::Boolean(value) => Ok(value.to_string()),
+        value => anyhow::bail!("setting '{key}' cannot be a {}", value.type_str()),
+    }
+}
+
+fn receiver_options(args: &Args) -> ReceiverOptions {
+    ReceiverOptions {
+        require_client_identity: args.client_ca.is_some(),
+        export_dir: args.export.clone(),
+        allow_delete: args.allow_delete,
+        on_conflict: match args.on_conflict {
+            OnConflict::Reject => ConflictPolicy::Reject,
+            OnConflict::Overwrite => ConflictPolicy::Overwrite,
+            OnConflict::Rename => ConflictPolicy::Rename,
+            OnConflict::Version => ConflictPolicy::Version,
+        },
+        preserve: PreserveMetadata {
+            mode: args.preserve.contains(&Attribute::Mode),
+            times: args.preserve.contains(&Attribute::Times),
+            owner: args.preserve.contains(&Attribute::Owner),
+            xattrs: args.preserve.contains(&Attribute::Xattrs),
+        },
+        limits: Limits {
+            max_file_size: args.max_file_size,
+            max_connections: args.max_connections,
+            max_connections_per_peer: args.max_connections_per_peer,
+            max_streams: args.max_streams,
+            max_streams_per_peer: args.max_streams_per_peer,
+            quota: args.quota,
+            quota_per_client: args.quota_per_client,
+            min_free_space: args.min_free_space,
+        },
+        timeouts: Timeouts {
+            header: Duration::from_secs(args.header_timeout_secs),
+            idle: Duration::from_secs(args.idle_timeout_secs),
+        },
+    }
+}
+
+/// Build the TLS configuration from the certificate, key and client CA in
+/// `args`, generating a self-signed certificate if there is none yet.
+fn tls_config(args: &Args) -> Result<tls::config::Config> {
+    let subject_alt_names: Vec<&str> = args.subject_alt_names.iter().map(String::as_str).collect();
+    let (cert_path, key_path) =
+        ensure_self_signed_certificate(&args.cert, &args.key, &subject_alt_names)?;
+
+    let mut tls =
+        tls::Server::builder().with_certificate(cert_path.as_path(), key_path.as_path())?;
+    if let Some(client_ca) = &args.client_ca {
+        if !client_ca.exists() {
+            anyhow::bail!("client CA certificate not found at {}", client_ca.display());
         }
+        tls = tls
+            .with_empty_trust_store()?
+            .with_trusted_certificate(client_ca.as_path())?
+            .with_client_authentication()?
+            .with_verify_host_name_callback(AnyClientName)?;
+    }
+    Ok(tls.build()?.into())
+}
+
+/// TLS configuration shared by every listener. New connections use the one
+/// most recently loaded, so certificates can be replaced while running.
+#[derive(Clone)]
+struct TlsConfig(Arc<Mutex<tls::config::Config>>);
+
+impl TlsConfig {
+    fn replace(&self, config: tls::config::Config) {
+        *self.0.lock().unwrap_or_else(|err| err.into_inner()) = config;
     }
+}
 
-    let safe_name = sanitize_file_name(&header.file_name);
-    let target_path = output_dir.join(safe_name);
-    if let Err(err) = receive_file(stream, target_path, header, buffer, consumed, remote_addr).await
-    {
-        eprintln!("[{remote_addr}] failed to store file: {err}");
+impl tls::ConfigLoader for TlsConfig {
+    fn load(&mut self, _: tls::ConnectionContext) -> tls::config::Config {
+        self.0.lock().unwrap_or_else(|err| err.into_inner()).clone()
+    }
+}
+
+/// Bind a server to `addr` that takes its TLS material from `tls`.
+fn bind(tls: &TlsConfig, addr: SocketAddr) -> Result<Server> {
+    Ok(Server::builder()
+        .with_tls(tls::Server::from_loader(tls.clone()))?
+        .with_event(ClientIdentities)?
+        .with_io(addr)?
+        .start()?)
+}
+
+/// Accept connections on `server`, bound to `addr`, until the returned task
+/// is aborted. Aborting it leaves the connections already accepted open.
+fn serve<S: StorageSink>(
+    receiver: &Arc<Receiver<S>>,
+    server: Server,
+    addr: SocketAddr,
+) -> JoinHandle<()> {
+    println!("Server listening on {addr}");
+    tokio::spawn(Arc::clone(receiver).serve(server))
+}
+
+/// Apply the settings `loaded` on SIGHUP and make them the current `args`,
+/// or keep running with the current ones if they could not be loaded or
+/// applied.
+fn reload_settings<S: StorageSink>(
+    receiver: &Arc<Receiver<S>>,
+    tls: &TlsConfig,
+    listeners: &mut HashMap<SocketAddr, JoinHandle<()>>,
+    args: &mut Args,
+    loaded: Result<Args>,
+) {
+    let applied = loaded.and_then(|new| {
+        reload(receiver, tls, listeners, args, &new)?;
+        Ok(new)
+    });
+    match applied {
+        Ok(new) => *args = new,
+        Err(err) => eprintln!("Failed to reload, keeping the current settings: {err:#}"),
     }
 }
 
-async fn receive_file(
-    mut stream: s2n_quic::stream::BidirectionalStream,
-    target_path: PathBuf,
-    header: FileHeader,
-    buffer: Vec<u8>,
-    consumed: usize,
-    remote_addr: SocketAddr,
+/// Apply the settings in `new` that can change while the server runs: the
+/// listen addresses, TLS material, limits, timeouts and policies.
+///
+/// The TLS material is read again even if its paths did not change, so that
+/// certificates replaced in place take effect. Nothing is applied if it
+/// cannot be loaded or any new address cannot be bound.
+fn reload<S: StorageSink>(
+    receiver: &Arc<Receiver<S>>,
+    tls: &TlsConfig,
+    listeners: &mut HashMap<SocketAddr, JoinHandle<()>>,
+    current: &Args,
+    new: &Args,
 ) -> Result<()> {
-    let mut file = File::create(&target_path).await?;
-    let mut written: u64 = 0;
+    let config = tls_config(new)?;
+    let mut bound = HashMap::new();
+    for &addr in &new.addr {
+        if listeners.contains_key(&addr) {
+            continue;
+        }
+        if let Entry::Vacant(entry) = bound.entry(addr) {
+            entry.insert(bind(tls, addr)?);
+        }
+    }
 
-    if buffer.len() > consumed {
-        let remaining = &buffer[consumed..];
-        file.write_all(remaining).await?;
-        written += remaining.len() as u64;
+    listeners.retain(|addr, listener| {
+        let keep = new.addr.contains(addr);
+        if !keep {
+            listener.abort();
+            println!("Stopped listening on {addr}");
+        }
+        keep
+    });
+    tls.replace(config);
+    receiver.set_options(receiver_options(new));
+    for (addr, server) in bound {
+        listeners.insert(addr, serve(receiver, server, addr));
     }
 
-    while let Some(chunk) = stream.receive().await? {
-        file.write_all(&chunk).await?;
-        written += chunk.len() as u64;
+    if new.output != current.output || new.store != current.store {
+        eprintln!("Restart the server to store files in a different place");
     }
+    if new.partial_ttl_hours != current.partial_ttl_hours {
+        eprintln!("The new --partial-ttl-hours applies from the next start");
+    }
+    match &new.config {
+        Some(path) => println!("Reloaded settings from {}", path.display()),
+        None => println!("Reloaded the TLS certificate and key"),
+    }
+    Ok(())
+}
 
-    file.flush().await?;
+enum Signal {
+    Reload,
+    Shutdown(&'static str),
+}
 
-    if written == header.file_size {
-        println!(
-            "[{remote_addr}] received '{}' ({} bytes) at {}",
-            header.file_name,
-            header.file_size,
-            target_path.display()
-        );
-    } else {
-        eprintln!(
-            "[{remote_addr}] warning: expected {} bytes for '{}' but wrote {}",
-            header.file_size, header.file_name, written
+/// The signals the server acts on: SIGINT, and on Unix SIGTERM and SIGHUP.
+struct Signals {
+    #[cfg(unix)]
+    terminate: tokio::signal::unix::Signal,
+    #[cfg(unix)]
+    hangup: tokio::signal::unix::Signal,
+}
+
+impl Signals {
+    fn new() -> Result<Self> {
+        #[cfg(unix)]
+        {
+            use tokio::signal::unix::{SignalKind, signal};
+            Ok(Self {
+                terminate: signal(SignalKind::terminate())?,
+                hangup: signal(SignalKind::hangup())?,
+            })
+        }
+        #[cfg(not(unix))]
+        Ok(Self {})
+    }
+
+    async fn next(&mut self) -> Result<Signal> {
+        #[cfg(unix)]
+        tokio::select! {
+            interrupted = tokio::signal::ctrl_c() => {
+                interrupted?;
+                Ok(Signal::Shutdown("SIGINT"))
+            }
+            _ = self.terminate.recv() => Ok(Signal::Shutdown("SIGTERM")),
+            _ = self.hangup.recv() => Ok(Signal::Reload),
+        }
+        #[cfg(not(unix))]
+        {
+            tokio::signal::ctrl_c().await?;
+            Ok(Signal::Shutdown("SIGINT"))
+        }
+    }
+}
+
+/// Parse a byte count such as `512`, `64K` or `10G`.
+fn parse_size(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    let (digits, shift) = match value.char_indices().last() {
+        Some((at, unit)) if unit.is_ascii_alphabetic() => {
+            let shift = match unit.to_ascii_uppercase() {
+                'K' => 10,
+                'M' => 20,
+                'G' => 30,
+                'T' => 40,
+                _ => return Err(format!("unknown size suffix '{unit}'")),
+            };
+            (&value[..at], shift)
+        }
+        _ => (value, 0),
+    };
+    let count: u64 = digits
+        .parse()
+        .map_err(|_| format!("'{value}' is not a size"))?;
+    count
+        .checked_mul(1 << shift)
+        .ok_or_else(|| format!("'{value}' is too large"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use quic3::storage::MemoryStorage;
+
+    /// Command line of a server reading `config`, with its certificate in `dir`.
+    fn argv(dir: &Path, config: &Path, flags: &[&str]) -> Vec<OsString> {
+        let mut argv: Vec<OsString> = vec!["server".into(), "--config".into(), config.into()];
+        argv.extend(["--cert".into(), dir.join("cert.pem").into_os_string()]);
+        argv.extend(["--key".into(), dir.join("key.pem").into_os_string()]);
+        argv.extend(flags.iter().map(OsString::from));
+        argv
+    }
+
+    #[test]
+    fn config_settings_become_defaults() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = dir.path().join("server.toml");
+        std::fs::write(
+            &config,
+            r#"
+                addr = ["127.0.0.1:4433", "[::1]:4433"]
+                on-conflict = "reject"
+                max-file-size = "10G"
+                allow-delete = true
+                idle-timeout-secs = 5
+            "#,
+        )
+        .unwrap();
+
+        let args = parse_args(argv(dir.path(), &config, &[])).unwrap();
+        assert_eq!(
+            args.addr,
+            [
+                "127.0.0.1:4433".parse().unwrap(),
+                "[::1]:4433".parse().unwrap()
+            ]
         );
+        assert!(matches!(args.on_conflict, OnConflict::Reject));
+        assert_eq!(args.max_file_size, Some(10 << 30));
+        assert!(args.allow_delete);
+        assert_eq!(args.idle_timeout_secs, 5);
+        // Settings the file leaves out keep their usual defaults.
+        assert_eq!(args.header_timeout_secs, 10);
+        assert_eq!(args.quota, None);
     }
 
-    Ok(())
+    #[test]
+    fn flags_take_precedence_over_the_config() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = dir.path().join("server.toml");
+        std::fs::write(
+            &config,
+            "on-conflict = \"reject\"\nmax-file-size = \"10G\"\naddr = \"127.0.0.1:4433\"\n",
+        )
+        .unwrap();
+
+        let flags = [
+            "--on-conflict",
+            "rename",
+            "--max-file-size",
+            "1M",
+            "--addr",
+            "127.0.0.1:5533",
+        ];
+        let args = parse_args(argv(dir.path(), &config, &flags)).unwrap();
+        assert!(matches!(args.on_conflict, OnConflict::Rename));
+        assert_eq!(args.max_file_size, Some(1 << 20));
+        assert_eq!(args.addr, ["127.0.0.1:5533".parse().unwrap()]);
+    }
+
+    #[test]
+    fn bad_configs_are_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = dir.path().join("server.toml");
+        for text in [
+            "no-such-setting = 1",
+            "on-conflict = \"sideways\"",
+            "max-file-size = \"10X\"",
+            "max-file-size = 1.5",
+            "header-timeout-secs = 0",
+            "not toml",
+        ] {
+            std::fs::write(&config, text).unwrap();
+            assert!(
+                parse_args(argv(dir.path(), &config, &[])).is_err(),
+                "{text}"
+            );
+        }
+        std::fs::remove_file(&config).unwrap();
+        assert!(parse_args(argv(dir.path(), &config, &[])).is_err());
+        // Mistakes on the command line are returned too, rather than exiting.
+        assert!(parse_args(["server", "--max-streams", "many"]).is_err());
+    }
+
+    #[tokio::test]
+    async fn failed_reloads_keep_the_running_settings() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = dir.path().join("server.toml");
+        std::fs::write(
+            &config,
+            "on-conflict = \"reject\"\naddr = \"127.0.0.1:0\"\n",
+        )
+        .unwrap();
+        let argv = argv(dir.path(), &config, &[]);
+        let mut args = parse_args(&argv).unwrap();
+        let receiver = Arc::new(Receiver::new(MemoryStorage::new(), receiver_options(&args)));
+        let tls = TlsConfig(Arc::new(Mutex::new(tls_config(&args).unwrap())));
+        let mut listeners = HashMap::new();
+        let mut reload = |args: &mut Args| {
+            let loaded = parse_args(&argv);
+            reload_settings(&receiver, &tls, &mut listeners, args, loaded);
+        };
+
+        for text in [
+            "on-conflict = \"rename\"\nmax-file-size = \"lots\"\n",
+            "on-conflict = \"rename\"\nclient-ca = \"missing-ca.pem\"\n",
+        ] {
+            std::fs::write(&config, format!("{text}addr = \"127.0.0.1:0\"\n")).unwrap();
+            reload(&mut args);
+            assert!(matches!(args.on_conflict, OnConflict::Reject), "{text}");
+            assert_eq!(args.client_ca, None);
+            assert_eq!(receiver.options().on_conflict, ConflictPolicy::Reject);
+            assert!(!receiver.options().require_client_identity);
+        }
+
+        std::fs::write(
+            &config,
+            "on-conflict = \"rename\"\naddr = \"127.0.0.1:0\"\n",
+        )
+        .unwrap();
+        reload(&mut args);
+        assert!(matches!(args.on_conflict, OnConflict::Rename));
+        assert_eq!(receiver.options().on_conflict, ConflictPolicy::Rename);
+        for listener in listeners.into_values() {
+            listener.abort();
+        }
+    }
+
+    #[test]
+    fn sizes_take_binary_suffixes() {
+        assert_eq!(parse_size("512"), Ok(512));
+        assert_eq!(parse_size("64K"), Ok(64 << 10));
+        assert_eq!(parse_size(" 10g "), Ok(10 << 30));
+        assert_eq!(parse_size("2T"), Ok(2 << 40));
+        assert!(parse_size("").is_err());
+        assert!(parse_size("K").is_err());
+        assert!(parse_size("10X").is_err());
+        assert!(parse_size("-1").is_err());
+        assert!(parse_size("1.5G").is_err());
+        assert!(parse_size("99999999999T").is_err());
+    }
 }
@@ -0,0 +1,446 @@
+//! A small private certificate authority for quic3 deployments.
+//!
+//! One CA is created per cluster and every server and client certificate is
+//! issued from it, so peers only need the CA certificate to trust each other.
+
+use crate::{Error, Result};
+use rcgen::{
+    BasicConstraints, Certificate, CertificateParams, DistinguishedName, DnType,
+    ExtendedKeyUsagePurpose, IsCa, KeyPair, KeyUsagePurpose, SignatureAlgorithm,
+};
+use rsa::RsaPrivateKey;
+use rsa::pkcs8::{EncodePrivateKey, LineEnding};
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+use std::str::FromStr;
+use time::OffsetDateTime;
+
+/// Modulus size of generated RSA keys.
+const RSA_KEY_BITS: usize = 2048;
+
+/// Key type of a generated certificate.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum KeyAlgorithm {
+    #[default]
+    EcdsaP256,
+    /// Not accepted by the s2n-tls provider the quic3 binaries use; meant for
+    /// peers built on other TLS stacks.
+    Ed25519,
+    /// 2048-bit RSA.
+    Rsa,
+}
+
+impl KeyAlgorithm {
+    fn signature_algorithm(self) -> &'static SignatureAlgorithm {
+        match self {
+            KeyAlgorithm::EcdsaP256 => &rcgen::PKCS_ECDSA_P256_SHA256,
+            KeyAlgorithm::Ed25519 => &rcgen::PKCS_ED25519,
+            KeyAlgorithm::Rsa => &rcgen::PKCS_RSA_SHA256,
+        }
+    }
+
+    fn generate_key_pair(self) -> Result<KeyPair> {
+        let key_pair = match self {
+            // rcgen cannot generate RSA keys itself, so hand it a PKCS#8 key.
+            KeyAlgorithm::Rsa => {
+                let key = RsaPrivateKey::new(&mut rsa::rand_core::OsRng, RSA_KEY_BITS)
+                    .map_err(|err| Error::Tls(format!("failed to generate an RSA key: {err}")))?;
+                let pem = key
+                    .to_pkcs8_pem(LineEnding::LF)
+                    .map_err(|err| Error::Tls(format!("failed to encode the RSA key: {err}")))?;
+                KeyPair::from_pem_and_sign_algo(&pem, self.signature_algorithm())?
+            }
+            _ => KeyPair::generate(self.signature_algorithm())?,
+        };
+        Ok(key_pair)
+    }
+}
+
+impl FromStr for KeyAlgorithm {
+    type Err = Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value.to_ascii_lowercase().as_str() {
+            "ecdsa-p256" | "ecdsa" | "p256" => Ok(KeyAlgorithm::EcdsaP256),
+            "ed25519" => Ok(KeyAlgorithm::Ed25519),
+            "rsa" | "rsa2048" => Ok(KeyAlgorithm::Rsa),
+            _ => Err(Error::Tls(format!(
+                "unknown key algorithm '{value}' (expected ecdsa-p256, ed25519 or rsa)"
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for KeyAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            KeyAlgorithm::EcdsaP256 => "ecdsa-p256",
+            KeyAlgorithm::Ed25519 => "ed25519",
+            KeyAlgorithm::Rsa => "rsa",
+        })
+    }
+}
+
+/// What a leaf certificate may be used for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CertificateRole {
+    Server,
+    Client,
+}
+
+/// Subject, names, key type and lifetime of a certificate to create.
+#[derive(Debug, Clone)]
+pub struct CertificateSpec {
+    pub common_name: String,
+    /// DNS names and IP addresses; entries that parse as an IP address become
+    /// IP address SANs.
+    pub subject_alt_names: Vec<String>,
+    pub algorithm: KeyAlgorithm,
+    pub validity_days: u32,
+}
+
+impl CertificateSpec {
+    fn params(&self) -> Result<CertificateParams> {
+        let mut params = CertificateParams::new(self.subject_alt_names.clone());
+        params.alg = self.algorithm.signature_algorithm();
+        params.key_pair = Some(self.algorithm.generate_key_pair()?);
+        params.distinguished_name = DistinguishedName::new();
+        params
+            .distinguished_name
+            .push(DnType::CommonName, self.common_name.as_str());
+        let now = OffsetDateTime::now_utc();
+        params.not_before = now - time::Duration::minutes(5);
+        params.not_after = now + time::Duration::days(i64::from(self.validity_days));
+        Ok(params)
+    }
+}
+
+/// A certificate and its private key, PEM encoded.
+#[derive(Debug, Clone)]
+pub struct IssuedCertificate {
+    pub cert_pem: String,
+    pub key_pem: String,
+}
+
+impl IssuedCertificate {
+    /// Write the certificate and key, refusing to replace existing files unless `overwrite` is set.
+    ///
+    /// A file in the way is reported as an [`Error::Io`] of kind
+    /// [`io::ErrorKind::AlreadyExists`].
+    pub fn save(&self, cert_path: &Path, key_path: &Path, overwrite: bool) -> Result<()> {
+        if !overwrite {
+            for path in [cert_path, key_path] {
+                if path.exists() {
+                    let message = format!("{} already exists", path.display());
+                    return Err(io::Error::new(io::ErrorKind::AlreadyExists, message).into());
+                }
+            }
+        }
+        write_pem(cert_path, &self.cert_pem, overwrite, false)?;
+        write_pem(key_path, &self.key_pem, overwrite, true)
+    }
+}
+
+/// A certificate authority able to issue server and client certificates.
+pub struct CertificateAuthority {
+    cert: Certificate,
+}
+
+impl CertificateAuthority {
+    /// Create a new self-signed CA. Its subject alternative names are ignored.
+    pub fn generate(spec: &CertificateSpec) -> Result<Self> {
+        let mut params = spec.params()?;
+        params.subject_alt_names.clear();
+        params.is_ca = IsCa::Ca(BasicConstraints::Constrained(0));
+        params.key_usages = vec![
+            KeyUsagePurpose::KeyCertSign,
+            KeyUsagePurpose::CrlSign,
+            KeyUsagePurpose::DigitalSignature,
+        ];
+        Ok(Self {
+            cert: Certificate::from_params(params)?,
+        })
+    }
+
+    /// Load a CA previously written by [`CertificateAuthority::certificate`].
+    pub fn load(cert_path: &Path, key_path: &Path) -> Result<Self> {
+        let cert_pem = fs::read_to_string(cert_path).map_err(|err| {
+            Error::Tls(format!(
+                "failed to read CA certificate {}: {err}",
+                cert_path.display()
+            ))
+        })?;
+        let key_pem = fs::read_to_string(key_path).map_err(|err| {
+            Error::Tls(format!(
+                "failed to read CA key {}: {err}",
+                key_path.display()
+            ))
+        })?;
+        let key_pair = KeyPair::from_pem(&key_pem)?;
+        let params = CertificateParams::from_ca_cert_pem(&cert_pem, key_pair)?;
+        if !matches!(params.is_ca, IsCa::Ca(_)) {
+            return Err(Error::Tls(format!(
+                "{} is not a CA certificate",
+                cert_path.display()
+            )));
+        }
+        Ok(Self {
+            cert: Certificate::from_params(params)?,
+        })
+    }
+
+    /// The CA's own certificate and key, for saving and distributing.
+    pub fn certificate(&self) -> Result<IssuedCertificate> {
+        Ok(IssuedCertificate {
+            cert_pem: self.cert.serialize_pem()?,
+            key_pem: self.cert.serialize_private_key_pem(),
+        })
+    }
+
+    /// Issue a leaf certificate signed by this CA.
+    pub fn issue(
+        &self,
+        spec: &CertificateSpec,
+        role: CertificateRole,
+    ) -> Result<IssuedCertificate> {
+        let mut params = spec.params()?;
+        params.use_authority_key_identifier_extension = true;
+        params.key_usages = vec![KeyUsagePurpose::DigitalSignature];
+        if spec.algorithm == KeyAlgorithm::Rsa {
+            params.key_usages.push(KeyUsagePurpose::KeyEncipherment);
+        }
+        params.extended_key_usages = vec![match role {
+            CertificateRole::Server => ExtendedKeyUsagePurpose::ServerAuth,
+            CertificateRole::Client => ExtendedKeyUsagePurpose::ClientAuth,
+        }];
+        if role == CertificateRole::Server && params.subject_alt_names.is_empty() {
+            return Err(Error::Tls(
+                "server certificates need at least one subject alternative name".into(),
+            ));
+        }
+
+        let leaf = Certificate::from_params(params)?;
+        Ok(IssuedCertificate {
+            cert_pem: leaf.serialize_pem_with_signer(&self.cert)?,
+            key_pem: leaf.serialize_private_key_pem(),
+        })
+    }
+}
+
+fn write_pem(path: &Path, contents: &str, overwrite: bool, private: bool) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).map_err(|err| {
+            Error::Tls(format!(
+                "failed to create directory {}: {err}",
+                parent.display()
+            ))
+        })?;
+    }
+
+    let mut options = fs::OpenOptions::new();
+    options.write(true);
+    if overwrite {
+        options.create(true).truncate(true);
+    } else {
+        options.create_new(true);
+    }
+    #[cfg(unix)]
+    if private {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+
+    let mut file = options.open(path).map_err(|err| {
+        io::Error::new(
+            err.kind(),
+            format!("failed to create {}: {err}", path.display()),
+        )
+    })?;
+    // The mode only applies to new files; one being replaced keeps its own
+    // permissions unless they are narrowed before the key is written.
+    #[cfg(unix)]
+    if private {
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(fs::Permissions::from_mode(0o600))?;
+    }
+    #[cfg(not(unix))]
+    let _ = private;
+    file.write_all(contents.as_bytes())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use x509_parser::extensions::{GeneralName, ParsedExtension};
+    use x509_parser::oid_registry::{
+        OID_X509_EXT_EXTENDED_KEY_USAGE, OID_X509_EXT_SUBJECT_ALT_NAME,
+    };
+    use x509_parser::pem::parse_x509_pem;
+
+    fn spec(common_name: &str, subject_alt_names: &[&str]) -> CertificateSpec {
+        CertificateSpec {
+            common_name: common_name.to_string(),
+            subject_alt_names: subject_alt_names
+                .iter()
+                .map(|name| name.to_string())
+                .collect(),
+            algorithm: KeyAlgorithm::EcdsaP256,
+            validity_days: 30,
+        }
+    }
+
+    fn der(pem: &str) -> Vec<u8> {
+        parse_x509_pem(pem.as_bytes()).unwrap().1.contents
+    }
+
+    #[test]
+    fn issued_leaves_verify_against_the_ca() {
+        let ca = CertificateAuthority::generate(&spec("test CA", &[])).unwrap();
+        let ca_der = der(&ca.certificate().unwrap().cert_pem);
+        let (_, ca_cert) = x509_parser::parse_x509_certificate(&ca_der).unwrap();
+        assert!(ca_cert.is_ca());
+        ca_cert.verify_signature(None).unwrap();
+
+        let leaf = ca
+            .issue(&spec("server", &["localhost"]), CertificateRole::Server)
+            .unwrap();
+        let leaf_der = der(&leaf.cert_pem);
+        let (_, leaf_cert) = x509_parser::parse_x509_certificate(&leaf_der).unwrap();
+        assert!(!leaf_cert.is_ca());
+        assert_eq!(leaf_cert.issuer(), ca_cert.subject());
+        leaf_cert
+            .verify_signature(Some(ca_cert.public_key()))
+            .unwrap();
+
+        let other = CertificateAuthority::generate(&spec("other CA", &[])).unwrap();
+        let other_der = der(&other.certificate().unwrap().cert_pem);
+        let (_, other_cert) = x509_parser::parse_x509_certificate(&other_der).unwrap();
+        assert!(
+            leaf_cert
+                .verify_signature(Some(other_cert.public_key()))
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn roles_set_usage_and_names() {
+        let ca = CertificateAuthority::generate(&spec("test CA", &[])).unwrap();
+        let server = ca
+            .issue(
+                &spec("server", &["files.example", "192.0.2.7"]),
+                CertificateRole::Server,
+            )
+            .unwrap();
+        let client = ca
+            .issue(&spec("alice", &[]), CertificateRole::Client)
+            .unwrap();
+
+        let server_der = der(&server.cert_pem);
+        let (_, cert) = x509_parser::parse_x509_certificate(&server_der).unwrap();
+        let extension = cert
+            .get_extension_unique(&OID_X509_EXT_EXTENDED_KEY_USAGE)
+            .unwrap()
+            .unwrap();
+        let ParsedExtension::ExtendedKeyUsage(usage) = extension.parsed_extension() else {
+            panic!("unexpected extended key usage {extension:?}");
+        };
+        assert!(usage.server_auth && !usage.client_auth);
+        let extension = cert
+            .get_extension_unique(&OID_X509_EXT_SUBJECT_ALT_NAME)
+            .unwrap()
+            .unwrap();
+        let ParsedExtension::SubjectAlternativeName(names) = extension.parsed_extension() else {
+            panic!("unexpected subject alternative names {extension:?}");
+        };
+        assert!(matches!(
+            names.general_names[..],
+            [
+                GeneralName::DNSName("files.example"),
+                GeneralName::IPAddress([192, 0, 2, 7])
+            ]
+        ));
+
+        let client_der = der(&client.cert_pem);
+        let (_, cert) = x509_parser::parse_x509_certificate(&client_der).unwrap();
+        let usage = cert.extended_key_usage().unwrap().unwrap().value;
+        assert!(usage.client_auth && !usage.server_auth);
+        assert!(cert.subject_alternative_name().unwrap().is_none());
+
+        assert!(
+            ca.issue(&spec("server", &[]), CertificateRole::Server)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn saved_ca_loads_again() {
+        let dir = tempfile::tempdir().unwrap();
+        let (cert_path, key_path) = (dir.path().join("ca.pem"), dir.path().join("ca-key.pem"));
+        let ca = CertificateAuthority::generate(&spec("test CA", &[])).unwrap();
+        let saved = ca.certificate().unwrap();
+        saved.save(&cert_path, &key_path, false).unwrap();
+
+        let loaded = CertificateAuthority::load(&cert_path, &key_path).unwrap();
+        let reloaded = loaded.certificate().unwrap();
+        assert_eq!(reloaded.key_pem, saved.key_pem);
+        // Leaves issued by the loaded CA verify against the saved certificate.
+        let leaf = loaded
+            .issue(&spec("alice", &[]), CertificateRole::Client)
+            .unwrap();
+        let saved_der = der(&saved.cert_pem);
+        let (_, ca_cert) = x509_parser::parse_x509_certificate(&saved_der).unwrap();
+        let leaf_der = der(&leaf.cert_pem);
+        let (_, leaf_cert) = x509_parser::parse_x509_certificate(&leaf_der).unwrap();
+        assert_eq!(leaf_cert.issuer(), ca_cert.subject());
+        leaf_cert
+            .verify_signature(Some(ca_cert.public_key()))
+            .unwrap();
+
+        let leaf_path = dir.path().join("leaf.pem");
+        leaf.save(&leaf_path, &dir.path().join("leaf-key.pem"), false)
+            .unwrap();
+        assert!(CertificateAuthority::load(&leaf_path, &dir.path().join("leaf-key.pem")).is_err());
+    }
+
+    #[test]
+    fn save_refuses_to_overwrite_without_force() {
+        let dir = tempfile::tempdir().unwrap();
+        let (cert_path, key_path) = (dir.path().join("cert.pem"), dir.path().join("key.pem"));
+        let ca = CertificateAuthority::generate(&spec("test CA", &[])).unwrap();
+        let first = ca.certificate().unwrap();
+        first.save(&cert_path, &key_path, false).unwrap();
+
+        let second = CertificateAuthority::generate(&spec("test CA", &[]))
+            .unwrap()
+            .certificate()
+            .unwrap();
+        let err = second.save(&cert_path, &key_path, false).unwrap_err();
+        assert!(matches!(&err, Error::Io(err) if err.kind() == io::ErrorKind::AlreadyExists));
+        assert_eq!(fs::read_to_string(&key_path).unwrap(), first.key_pem);
+
+        second.save(&cert_path, &key_path, true).unwrap();
+        assert_eq!(fs::read_to_string(&key_path).unwrap(), second.key_pem);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn keys_are_private_even_when_replaced() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = tempfile::tempdir().unwrap();
+        let (cert_path, key_path) = (dir.path().join("cert.pem"), dir.path().join("key.pem"));
+        fs::write(&key_path, "old").unwrap();
+        fs::set_permissions(&key_path, fs::Permissions::from_mode(0o644)).unwrap();
+
+        let ca = CertificateAuthority::generate(&spec("test CA", &[])).unwrap();
+        ca.certificate()
+            .unwrap()
+            .save(&cert_path, &key_path, true)
+            .unwrap();
+        let mode = |path: &Path| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode(&key_path), 0o600);
+        assert_ne!(mode(&cert_path), 0o600);
+    }
+}
@@ -0,0 +1,320 @@
+//! Compression of upload bodies on the wire.
+//!
+//! When the client and server agree on a codec, everything the client sends
+//! after the negotiation reply (the body and its checksum trailer) is cut
+//! into blocks that are compressed independently, so a resumed upload or one
+//! range of a multi-stream upload can start compressing at any offset. Blocks
+//! that do not shrink are sent as they are. The server decompresses before
+//! the body is checked, so sizes, offsets and checksums all refer to the
+//! uncompressed file.
+
+use crate::{CodecError, FLAG_LZ4, FLAG_ZSTD, Result, read_u32};
+
+/// Uncompressed bytes per block.
+const BLOCK_LEN: usize = 256 * 1024;
+// kind (u8) + uncompressed length (u32) + payload length (u32)
+const BLOCK_PREFIX_LEN: usize = 1 + 4 + 4;
+
+const KIND_STORED: u8 = 0;
+const KIND_ZSTD: u8 = 1;
+const KIND_LZ4: u8 = 2;
+
+/// Extensions of formats that are compressed already and gain nothing from
+/// another pass.
+const COMPRESSED_EXTENSIONS: &[&str] = &[
+    "7z", "avi", "br", "bz2", "gif", "gz", "heic", "jpeg", "jpg", "lz4", "lzma", "mkv", "mov",
+    "mp3", "mp4", "ogg", "png", "rar", "tgz", "webm", "webp", "xz", "zip", "zst",
+];
+
+/// A codec for compressing upload bodies.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    /// Zstandard at the given level (1 to 22; 3 is a good default).
+    Zstd { level: i32 },
+    /// LZ4, which is faster but compresses less.
+    Lz4,
+}
+
+impl Compression {
+    /// The feature flag that asks the server for this codec.
+    pub fn flag(self) -> u32 {
+        match self {
+            Compression::Zstd { .. } => FLAG_ZSTD,
+            Compression::Lz4 => FLAG_LZ4,
+        }
+    }
+}
+
+/// Whether a file named `name` whose contents start with `sample` is worth
+/// compressing: known compressed formats are skipped, as is anything whose
+/// sample shrinks by less than a tenth.
+pub fn worth_compressing(name: &str, sample: &[u8]) -> bool {
+    let extension = name
+        .rsplit_once('.')
+        .map(|(_, extension)| extension.to_ascii_lowercase());
+    if extension.is_some_and(|extension| COMPRESSED_EXTENSIONS.contains(&extension.as_str())) {
+        return false;
+    }
+    sample.is_empty() || lz4_flex::block::compress(sample).len() < sample.len() / 10 * 9
+}
+
+/// Compresses the bytes of an upload into blocks.
+pub(crate) struct Encoder {
+    compression: Compression,
+    pending: Vec<u8>,
+}
+
+impl Encoder {
+    pub(crate) fn new(compression: Compression) -> Self {
+        Self {
+            compression,
+            pending: Vec::with_capacity(BLOCK_LEN),
+        }
+    }
+
+    /// Take `data` and return the encoded blocks it completes.
+    pub(crate) fn encode(&mut self, mut data: &[u8]) -> Result<Vec<u8>> {
+        let mut out = Vec::new();
+        while !data.is_empty() {
+            let take = (BLOCK_LEN - self.pending.len()).min(data.len());
+            self.pending.extend_from_slice(&data[..take]);
+            data = &data[take..];
+            if self.pending.len() == BLOCK_LEN {
+                self.flush_block(&mut out)?;
+            }
+        }
+        Ok(out)
+    }
+
+    /// Encode whatever is left once the upload is complete.
+    pub(crate) fn finish(&mut self) -> Result<Vec<u8>> {
+        let mut out = Vec::new();
+        if !self.pending.is_empty() {
+            self.flush_block(&mut out)?;
+        }
+        Ok(out)
+    }
+
+    fn flush_block(&mut self, out: &mut Vec<u8>) -> Result<()> {
+        let raw = &self.pending;
+        let (kind, compressed) = match self.compression {
+            Compression::Zstd { level } => (KIND_ZSTD, zstd::bulk::compress(raw, level)?),
+            Compression::Lz4 => (KIND_LZ4, lz4_flex::block::compress(raw)),
+        };
+        let (kind, payload) = if compressed.len() < raw.len() {
+            (kind, compressed.as_slice())
+        } else {
+            (KIND_STORED, raw.as_slice())
+        };
+        out.push(kind);
+        out.extend_from_slice(&(raw.len() as u32).to_le_bytes());
+        out.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        out.extend_from_slice(payload);
+        self.pending.clear();
+        Ok(())
+    }
+}
+
+/// Restores the bytes of an upload from the blocks on the wire.
+pub(crate) struct Decoder {
+    buffer: Vec<u8>,
+}
+
+impl Decoder {
+    /// A decoder for the codec selected by `flags`, or `None` if the body is
+    /// not compressed.
+    pub(crate) fn for_flags(flags: u32) -> Option<Self> {
+        (flags & (FLAG_ZSTD | FLAG_LZ4) != 0).then(|| Self { buffer: Vec::new() })
+    }
+
+    /// Take bytes received from the wire and return the contents of the
+    /// blocks they complete.
+    pub(crate) fn decode(&mut self, data: &[u8]) -> Result<Vec<u8>, CodecError> {
+        self.buffer.extend_from_slice(data);
+        let mut out = Vec::new();
+        let mut used = 0;
+        while let Some((block, len)) = decode_block(&self.buffer[used..])? {
+            out.extend_from_slice(&block);
+            used += len;
+        }
+        self.buffer.drain(..used);
+        Ok(out)
+    }
+
+    /// Check that the stream did not end inside a block.
+    pub(crate) fn finish(&self) -> Result<(), CodecError> {
+        if !self.buffer.is_empty() {
+            return Err(CodecError::InvalidBody(
+                "stream ended in the middle of a compressed block".into(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Attempt to decode one block, returning its contents and encoded length.
+fn decode_block(buf: &[u8]) -> Result<Option<(Vec<u8>, usize)>, CodecError> {
+    if buf.len() < BLOCK_PREFIX_LEN {
+        return Ok(None);
+    }
+    let kind = buf[0];
+    let raw_len = read_u32(buf, 1) as usize;
+    let payload_len = read_u32(buf, 5) as usize;
+    if raw_len > BLOCK_LEN || payload_len > BLOCK_LEN {
+        return Err(CodecError::InvalidBody(
+            "compressed block is too large".into(),
+        ));
+    }
+    let len = BLOCK_PREFIX_LEN + payload_len;
+    if buf.len() < len {
+        return Ok(None);
+    }
+
+    let payload = &buf[BLOCK_PREFIX_LEN..len];
+    let invalid = |err: &dyn std::fmt::Display| {
+        CodecError::InvalidBody(format!("block does not decompress: {err}"))
+    };
+    let block = match kind {
+        KIND_STORED => payload.to_vec(),
+        KIND_ZSTD => zstd::bulk::decompress(payload, raw_len).map_err(|err| invalid(&err))?,
+        KIND_LZ4 => lz4_flex::block::decompress(payload, raw_len).map_err(|err| invalid(&err))?,
+        other => {
+            return Err(CodecError::UnknownValue {
+                field: "block kind",
+                value: other,
+            });
+        }
+    };
+    if block.len() != raw_len {
+        return Err(CodecError::InvalidBody(format!(
+            "block decompressed to {} bytes instead of {raw_len}",
+            block.len()
+        )));
+    }
+    Ok(Some((block, len)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CODECS: [Compression; 2] = [Compression::Zstd { level: 3 }, Compression::Lz4];
+
+    /// Compressible bytes that still differ from block to block.
+    fn sample(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i / 7 % 251) as u8).collect()
+    }
+
+    fn encode(compression: Compression, data: &[u8]) -> Vec<u8> {
+        let mut encoder = Encoder::new(compression);
+        let mut wire = encoder.encode(data).unwrap();
+        wire.extend(encoder.finish().unwrap());
+        wire
+    }
+
+    fn decode(flags: u32, wire: &[u8]) -> Result<Vec<u8>, CodecError> {
+        let mut decoder = Decoder::for_flags(flags).unwrap();
+        let mut out = Vec::new();
+        // Feed the blocks in uneven pieces, as they arrive from the network.
+        for chunk in wire.chunks(1000) {
+            out.extend(decoder.decode(chunk)?);
+        }
+        decoder.finish()?;
+        Ok(out)
+    }
+
+    #[test]
+    fn round_trips() {
+        for compression in CODECS {
+            for len in [0, BLOCK_LEN, BLOCK_LEN + 1] {
+                let data = sample(len);
+                let wire = encode(compression, &data);
+                assert_eq!(decode(compression.flag(), &wire).unwrap(), data);
+            }
+        }
+    }
+
+    #[test]
+    fn empty_input_encodes_nothing() {
+        for compression in CODECS {
+            assert!(encode(compression, &[]).is_empty());
+        }
+    }
+
+    #[test]
+    fn full_block_is_flushed_without_finish() {
+        for compression in CODECS {
+            let mut encoder = Encoder::new(compression);
+            let wire = encoder.encode(&sample(BLOCK_LEN)).unwrap();
+            assert_ne!(wire[0], KIND_STORED);
+            assert_eq!(read_u32(&wire, 1) as usize, BLOCK_LEN);
+            assert_eq!(wire.len(), BLOCK_PREFIX_LEN + read_u32(&wire, 5) as usize);
+            assert!(encoder.finish().unwrap().is_empty());
+        }
+    }
+
+    #[test]
+    fn incompressible_block_is_stored() {
+        let mut data = vec![0; 4096];
+        blake3::Hasher::new().finalize_xof().fill(&mut data);
+        for compression in CODECS {
+            let wire = encode(compression, &data);
+            assert_eq!(wire[0], KIND_STORED);
+            assert_eq!(decode(compression.flag(), &wire).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn corrupt_block_is_rejected() {
+        for compression in CODECS {
+            let mut wire = encode(compression, &sample(BLOCK_LEN));
+            assert_ne!(wire[0], KIND_STORED);
+            wire[BLOCK_PREFIX_LEN..].fill(0xff);
+            assert!(matches!(
+                decode(compression.flag(), &wire),
+                Err(CodecError::InvalidBody(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn truncated_stream_is_rejected() {
+        for compression in CODECS {
+            let wire = encode(compression, &sample(BLOCK_LEN + 1));
+            assert!(matches!(
+                decode(compression.flag(), &wire[..wire.len() - 1]),
+                Err(CodecError::InvalidBody(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn oversized_block_is_rejected() {
+        let mut wire = vec![KIND_STORED];
+        wire.extend_from_slice(&(BLOCK_LEN as u32 + 1).to_le_bytes());
+        wire.extend_from_slice(&0u32.to_le_bytes());
+        assert!(matches!(
+            decode(FLAG_ZSTD, &wire),
+            Err(CodecError::InvalidBody(_))
+        ));
+    }
+
+    #[test]
+    fn unknown_block_kind_is_rejected() {
+        let mut wire = vec![9];
+        wire.extend_from_slice(&0u32.to_le_bytes());
+        wire.extend_from_slice(&0u32.to_le_bytes());
+        assert!(matches!(
+            decode(FLAG_LZ4, &wire),
+            Err(CodecError::UnknownValue { value: 9, .. })
+        ));
+    }
+
+    #[test]
+    fn skips_compressed_formats() {
+        let text = sample(4096);
+        assert!(worth_compressing("notes.txt", &text));
+        assert!(!worth_compressing("photo.JPG", &text));
+        assert!(worth_compressing("empty", &[]));
+    }
+}
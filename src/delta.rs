@@ -0,0 +1,650 @@
+//! Block-level delta uploads.
+//!
+//! When a client replaces a file the server already holds, the server sends a
+//! [`Signature`] of its copy: a weak rolling checksum and a strong hash of
+//! every block. The client slides a window over the new version and, wherever
+//! the window matches a block, sends an instruction to copy that block instead
+//! of its bytes. The server rebuilds the file from copies and literal data and
+//! checks the result against the whole-file digest like any other upload.
+
+use crate::storage::StorageSink;
+use crate::{CHECKSUM_LEN, CodecError, Error, MAGIC, Result, read_u32, read_u64};
+use std::collections::HashMap;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Length of the truncated BLAKE3 hash identifying a block.
+pub const STRONG_LEN: usize = 16;
+// magic + block length (u32) + block count (u32)
+pub const SIGNATURE_PREFIX_LEN: usize = 4 + 4 + 4;
+const BLOCK_SIGNATURE_LEN: usize = 4 + STRONG_LEN;
+
+const MIN_BLOCK_LEN: u64 = 2 * 1024;
+const MAX_BLOCK_LEN: u64 = 128 * 1024;
+/// Longest run of literal data carried by one instruction.
+const MAX_LITERAL_LEN: usize = 64 * 1024;
+/// Instructions are batched into frames of about this size.
+const SEND_BATCH_LEN: usize = 64 * 1024;
+
+const OP_COPY: u8 = 0;
+const OP_LITERAL: u8 = 1;
+const OP_END: u8 = 2;
+
+/// Checksums of one block of the server's copy.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockSignature {
+    pub weak: u32,
+    pub strong: [u8; STRONG_LEN],
+}
+
+/// Checksums of the whole blocks of the server's copy of a file, up to as
+/// many as the new version could hold; a shorter last block is left out and
+/// always sent as literal data.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Signature {
+    pub block_len: u32,
+    pub blocks: Vec<BlockSignature>,
+}
+
+/// One step of rebuilding a file from the server's copy.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Instruction {
+    /// Append `len` bytes of the server's copy starting at `offset`.
+    Copy { offset: u64, len: u64 },
+    /// Append these bytes.
+    Literal(Vec<u8>),
+    /// The file is complete; carries the BLAKE3 digest of the whole new version.
+    End([u8; CHECKSUM_LEN]),
+}
+
+/// Block length used for a copy of `size` bytes: about the square root of
+/// the size, so signatures stay small while changes cost little to resend.
+pub fn block_len_for(size: u64) -> u32 {
+    size.isqrt().clamp(MIN_BLOCK_LEN, MAX_BLOCK_LEN) as u32
+}
+
+/// Encode a signature frame.
+pub fn encode_signature(signature: &Signature) -> Result<Vec<u8>> {
+    let count = u32::try_from(signature.blocks.len())
+        .map_err(|_| CodecError::InvalidBody("signature has too many blocks".into()))?;
+    let mut buf =
+        Vec::with_capacity(SIGNATURE_PREFIX_LEN + signature.blocks.len() * BLOCK_SIGNATURE_LEN);
+    buf.extend_from_slice(&MAGIC);
+    buf.extend_from_slice(&signature.block_len.to_le_bytes());
+    buf.extend_from_slice(&count.to_le_bytes());
+    for block in &signature.blocks {
+        buf.extend_from_slice(&block.weak.to_le_bytes());
+        buf.extend_from_slice(&block.strong);
+    }
+    Ok(buf)
+}
+
+/// Most blocks a signature for an upload of `file_size` bytes may list: the
+/// new version cannot copy more whole blocks than that, so the server leaves
+/// out the rest and a client need not reserve memory for them.
+fn max_blocks(file_size: u64, block_len: u32) -> u64 {
+    file_size.div_ceil(u64::from(block_len).max(MIN_BLOCK_LEN))
+}
+
+/// Attempt to decode a signature frame sent for an upload of `file_size`
+/// bytes.
+pub fn try_decode_signature(
+    buf: &[u8],
+    file_size: u64,
+) -> Result<Option<(Signature, usize)>, CodecError> {
+    if buf.len() < SIGNATURE_PREFIX_LEN {
+        return Ok(None);
+    }
+    if buf[..4] != MAGIC {
+        return Err(CodecError::BadMagic);
+    }
+
+    let block_len = read_u32(buf, 4);
+    if u64::from(block_len) < MIN_BLOCK_LEN || u64::from(block_len) > MAX_BLOCK_LEN {
+        return Err(CodecError::InvalidBody(format!(
+            "invalid block length {block_len}"
+        )));
+    }
+    let count = read_u32(buf, 8);
+    if u64::from(count) > max_blocks(file_size, block_len) {
+        return Err(CodecError::InvalidBody(format!(
+            "signature of {count} blocks for a file of {file_size} bytes"
+        )));
+    }
+    let len = SIGNATURE_PREFIX_LEN + count as usize * BLOCK_SIGNATURE_LEN;
+    if buf.len() < len {
+        return Ok(None);
+    }
+
+    let blocks = buf[SIGNATURE_PREFIX_LEN..len]
+        .chunks_exact(BLOCK_SIGNATURE_LEN)
+        .map(|block| {
+            let mut strong = [0u8; STRONG_LEN];
+            strong.copy_from_slice(&block[4..]);
+            BlockSignature {
+                weak: read_u32(block, 0),
+                strong,
+            }
+        })
+        .collect();
+    Ok(Some((Signature { block_len, blocks }, len)))
+}
+
+/// Append the encoding of `instruction` to `buf`.
+pub fn encode_instruction(instruction: &Instruction, buf: &mut Vec<u8>) {
+    match instruction {
+        Instruction::Copy { offset, len } => {
+            buf.push(OP_COPY);
+            buf.extend_from_slice(&offset.to_le_bytes());
+            buf.extend_from_slice(&len.to_le_bytes());
+        }
+        Instruction::Literal(data) => {
+            buf.push(OP_LITERAL);
+            buf.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            buf.extend_from_slice(data);
+        }
+        Instruction::End(checksum) => {
+            buf.push(OP_END);
+            buf.extend_from_slice(checksum);
+        }
+    }
+}
+
+/// Attempt to decode one instruction.
+pub fn try_decode_instruction(buf: &[u8]) -> Result<Option<(Instruction, usize)>, CodecError> {
+    let Some(&op) = buf.first() else {
+        return Ok(None);
+    };
+    match op {
+        OP_COPY if buf.len() >= 17 => {
+            let offset = read_u64(buf, 1);
+            let len = read_u64(buf, 9);
+            Ok(Some((Instruction::Copy { offset, len }, 17)))
+        }
+        OP_LITERAL if buf.len() >= 5 => {
+            let len = read_u32(buf, 1) as usize;
+            if len > MAX_LITERAL_LEN {
+                return Err(CodecError::InvalidBody(format!(
+                    "literal of {len} bytes is too long"
+                )));
+            }
+            if buf.len() < 5 + len {
+                return Ok(None);
+            }
+            Ok(Some((
+                Instruction::Literal(buf[5..5 + len].to_vec()),
+                5 + len,
+            )))
+        }
+        OP_END if buf.len() > CHECKSUM_LEN => {
+            let mut checksum = [0u8; CHECKSUM_LEN];
+            checksum.copy_from_slice(&buf[1..1 + CHECKSUM_LEN]);
+            Ok(Some((Instruction::End(checksum), 1 + CHECKSUM_LEN)))
+        }
+        OP_COPY | OP_LITERAL | OP_END => Ok(None),
+        other => Err(CodecError::UnknownValue {
+            field: "delta instruction",
+            value: other,
+        }),
+    }
+}
+
+/// Compute the signature of the committed file `name` in `storage`, together
+/// with its size, or `None` if there is no copy worth comparing against. Only
+/// the leading blocks a new version of `file_size` bytes could hold are listed.
+pub(crate) async fn signature<S: StorageSink>(
+    storage: &S,
+    name: &str,
+    file_size: u64,
+) -> Result<Option<(Signature, u64)>> {
+    let Some(stored) = storage.stat(name, false).await? else {
+        return Ok(None);
+    };
+    let block_len = block_len_for(stored.size);
+    if stored.size < u64::from(block_len) {
+        return Ok(None);
+    }
+
+    let count = (stored.size / u64::from(block_len)).min(max_blocks(file_size, block_len));
+    let mut blocks = Vec::with_capacity(count as usize);
+    let mut block = vec![0u8; block_len as usize];
+    let mut offset = 0;
+    while (blocks.len() as u64) < count {
+        let mut filled = 0;
+        while filled < block.len() {
+            let bytes_read = storage
+                .read_committed(name, offset + filled as u64, &mut block[filled..])
+                .await?;
+            if bytes_read == 0 {
+                return Err(Error::Storage(anyhow::anyhow!(
+                    "'{name}' shrank while computing its signature"
+                )));
+            }
+            filled += bytes_read;
+        }
+        blocks.push(BlockSignature {
+            weak: Rolling::new(&block).digest(),
+            strong: strong_hash(&block),
+        });
+        offset += u64::from(block_len);
+    }
+    Ok(Some((Signature { block_len, blocks }, stored.size)))
+}
+
+/// Bytes of a delta upload that were sent and that were copied from the
+/// server's copy.
+pub(crate) struct DeltaStats {
+    pub literal: u64,
+    pub copied: u64,
+}
+
+/// Stream the instructions that rebuild the contents of `reader` from the
+/// copy described by `signature`, ending with the digest of the whole file.
+pub(crate) async fn send_delta(
+    stream: &mut (impl AsyncWrite + Unpin),
+    mut reader: impl AsyncRead + Unpin,
+    signature: &Signature,
+) -> Result<DeltaStats> {
+    let block_len = signature.block_len as usize;
+    let mut index: HashMap<u32, Vec<usize>> = HashMap::new();
+    for (block, sig) in signature.blocks.iter().enumerate() {
+        index.entry(sig.weak).or_default().push(block);
+    }
+
+    let mut encoder = DeltaEncoder {
+        stream,
+        out: Vec::new(),
+        pending_copy: None,
+        stats: DeltaStats {
+            literal: 0,
+            copied: 0,
+        },
+    };
+    let mut hasher = blake3::Hasher::new();
+    let mut chunk = vec![0u8; 64 * 1024];
+    // `data[..pos]` is literal data not sent yet; the window starts at `pos`.
+    let mut data = Vec::new();
+    let mut pos = 0;
+    let mut eof = false;
+    let mut rolling: Option<Rolling> = None;
+    loop {
+        while !eof && data.len() <= pos + block_len {
+            let bytes_read = reader.read(&mut chunk).await?;
+            if bytes_read == 0 {
+                eof = true;
+            }
+            hasher.update(&chunk[..bytes_read]);
+            data.extend_from_slice(&chunk[..bytes_read]);
+        }
+        if data.len() - pos < block_len {
+            break;
+        }
+
+        let window = &data[pos..pos + block_len];
+        let weak = rolling.get_or_insert_with(|| Rolling::new(window)).digest();
+        let expected = encoder
+            .pending_copy
+            .map(|(offset, len)| ((offset + len) / u64::from(signature.block_len)) as usize);
+        if let Some(block) = find_block(&index, signature, weak, window, expected) {
+            encoder.literal(&data[..pos]).await?;
+            encoder.copy(
+                block as u64 * u64::from(signature.block_len),
+                block_len as u64,
+            );
+            data.drain(..pos + block_len);
+            pos = 0;
+            rolling = None;
+            continue;
+        }
+
+        if let (Some(rolling), Some(&next)) = (rolling.as_mut(), data.get(pos + block_len)) {
+            rolling.roll(data[pos], next);
+        }
+        pos += 1;
+        if pos >= MAX_LITERAL_LEN {
+            encoder.literal(&data[..pos]).await?;
+            data.drain(..pos);
+            pos = 0;
+        }
+    }
+
+    encoder.literal(&data).await?;
+    encoder.emit(&Instruction::End(*hasher.finalize().as_bytes()));
+    encoder.flush().await?;
+    Ok(encoder.stats)
+}
+
+/// Buffers instructions on their way to the server, merging copies of
+/// consecutive blocks into one.
+struct DeltaEncoder<'a, W> {
+    stream: &'a mut W,
+    out: Vec<u8>,
+    pending_copy: Option<(u64, u64)>,
+    stats: DeltaStats,
+}
+
+impl<W: AsyncWrite + Unpin> DeltaEncoder<'_, W> {
+    fn copy(&mut self, offset: u64, len: u64) {
+        self.stats.copied += len;
+        match &mut self.pending_copy {
+            Some((start, pending)) if *start + *pending == offset => *pending += len,
+            _ => {
+                self.emit_copy();
+                self.pending_copy = Some((offset, len));
+            }
+        }
+    }
+
+    async fn literal(&mut self, data: &[u8]) -> Result<()> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        self.emit_copy();
+        for part in data.chunks(MAX_LITERAL_LEN) {
+            self.stats.literal += part.len() as u64;
+            encode_instruction(&Instruction::Literal(part.to_vec()), &mut self.out);
+            if self.out.len() >= SEND_BATCH_LEN {
+                self.flush().await?;
+            }
+        }
+        Ok(())
+    }
+
+    fn emit_copy(&mut self) {
+        if let Some((offset, len)) = self.pending_copy.take() {
+            encode_instruction(&Instruction::Copy { offset, len }, &mut self.out);
+        }
+    }
+
+    fn emit(&mut self, instruction: &Instruction) {
+        self.emit_copy();
+        encode_instruction(instruction, &mut self.out);
+    }
+
+    async fn flush(&mut self) -> Result<()> {
+        if !self.out.is_empty() {
+            self.stream.write_all(&self.out).await?;
+            self.out.clear();
+        }
+        Ok(())
+    }
+}
+
+/// Find the block whose contents equal `window`, preferring the block after
+/// the last one copied so that copies can be merged.
+fn find_block(
+    index: &HashMap<u32, Vec<usize>>,
+    signature: &Signature,
+    weak: u32,
+    window: &[u8],
+    expected: Option<usize>,
+) -> Option<usize> {
+    let candidates = index.get(&weak)?;
+    let strong = strong_hash(window);
+    let matches = |block: &&usize| signature.blocks[**block].strong == strong;
+    candidates
+        .iter()
+        .filter(matches)
+        .find(|block| Some(**block) == expected)
+        .or_else(|| candidates.iter().find(matches))
+        .copied()
+}
+
+fn strong_hash(block: &[u8]) -> [u8; STRONG_LEN] {
+    let mut strong = [0u8; STRONG_LEN];
+    strong.copy_from_slice(&blake3::hash(block).as_bytes()[..STRONG_LEN]);
+    strong
+}
+
+/// The rsync rolling checksum of a window, which can be moved along by one
+/// byte in constant time.
+struct Rolling {
+    a: u32,
+    b: u32,
+    len: u32,
+}
+
+impl Rolling {
+    fn new(window: &[u8]) -> Self {
+        let len = window.len() as u32;
+        let mut a = 0u32;
+        let mut b = 0u32;
+        for (i, &byte) in window.iter().enumerate() {
+            a = a.wrapping_add(u32::from(byte));
+            b = b.wrapping_add((len - i as u32).wrapping_mul(u32::from(byte)));
+        }
+        Self { a, b, len }
+    }
+
+    /// Drop `out` from the front of the window and append `next`.
+    fn roll(&mut self, out: u8, next: u8) {
+        self.a = self
+            .a
+            .wrapping_sub(u32::from(out))
+            .wrapping_add(u32::from(next));
+        self.b = self
+            .b
+            .wrapping_sub(self.len.wrapping_mul(u32::from(out)))
+            .wrapping_add(self.a);
+    }
+
+    fn digest(&self) -> u32 {
+        (self.b << 16) | (self.a & 0xffff)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+
+    /// Deterministic bytes that share no blocks by accident.
+    fn random(seed: &str, len: usize) -> Vec<u8> {
+        let mut data = vec![0; len];
+        blake3::Hasher::new()
+            .update(seed.as_bytes())
+            .finalize_xof()
+            .fill(&mut data);
+        data
+    }
+
+    /// Compute the delta from `base` to `new` as it is sent on the wire.
+    async fn delta(base: &[u8], new: &[u8]) -> (Vec<u8>, DeltaStats) {
+        let storage = MemoryStorage::new();
+        let (mut staged, _) = storage.open("base", None).await.unwrap();
+        storage.write_at(&mut staged, 0, base).await.unwrap();
+        storage.commit(staged, "base").await.unwrap();
+
+        let (signature, size) = signature(&storage, "base", new.len() as u64)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(size, base.len() as u64);
+        let mut wire = Vec::new();
+        let stats = send_delta(&mut wire, new, &signature).await.unwrap();
+        (wire, stats)
+    }
+
+    /// Rebuild a file from `base` and the instructions in `wire`, checking
+    /// the digest that ends them.
+    fn apply(base: &[u8], mut wire: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        loop {
+            let (instruction, used) = try_decode_instruction(wire).unwrap().unwrap();
+            wire = &wire[used..];
+            match instruction {
+                Instruction::Copy { offset, len } => {
+                    out.extend_from_slice(&base[offset as usize..(offset + len) as usize]);
+                }
+                Instruction::Literal(data) => out.extend(data),
+                Instruction::End(checksum) => {
+                    assert!(wire.is_empty());
+                    assert_eq!(&checksum, blake3::hash(&out).as_bytes());
+                    return out;
+                }
+            }
+        }
+    }
+
+    async fn rebuild(base: &[u8], new: &[u8]) -> DeltaStats {
+        let (wire, stats) = delta(base, new).await;
+        assert_eq!(apply(base, &wire), new);
+        assert_eq!(stats.literal + stats.copied, new.len() as u64);
+        stats
+    }
+
+    #[test]
+    fn rolling_matches_full_recompute() {
+        let data = random("rolling", 4096);
+        for len in [1, 7, 64, 2048] {
+            let mut rolling = Rolling::new(&data[..len]);
+            for start in 1..=data.len() - len {
+                rolling.roll(data[start - 1], data[start + len - 1]);
+                assert_eq!(
+                    rolling.digest(),
+                    Rolling::new(&data[start..start + len]).digest(),
+                    "window of {len} at {start}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn signature_round_trips() {
+        let signature = Signature {
+            block_len: MIN_BLOCK_LEN as u32,
+            blocks: vec![
+                BlockSignature {
+                    weak: 1,
+                    strong: [2; STRONG_LEN],
+                },
+                BlockSignature {
+                    weak: u32::MAX,
+                    strong: [3; STRONG_LEN],
+                },
+            ],
+        };
+        let encoded = encode_signature(&signature).unwrap();
+        assert_eq!(
+            try_decode_signature(&encoded, u64::MAX).unwrap(),
+            Some((signature, encoded.len()))
+        );
+        for len in 0..encoded.len() {
+            assert_eq!(
+                try_decode_signature(&encoded[..len], u64::MAX).unwrap(),
+                None
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn small_copy_has_no_signature() {
+        let storage = MemoryStorage::new();
+        let (mut staged, _) = storage.open("small", None).await.unwrap();
+        storage.write_at(&mut staged, 0, b"tiny").await.unwrap();
+        storage.commit(staged, "small").await.unwrap();
+        assert!(signature(&storage, "small", 4).await.unwrap().is_none());
+        assert!(signature(&storage, "missing", 4).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn signature_lists_only_blocks_the_upload_can_hold() {
+        let storage = MemoryStorage::new();
+        let (mut staged, _) = storage.open("large", None).await.unwrap();
+        storage
+            .write_at(&mut staged, 0, &random("large", 100_000))
+            .await
+            .unwrap();
+        storage.commit(staged, "large").await.unwrap();
+        for (file_size, blocks) in [(0, 0), (10_000, 5), (1 << 20, 100_000 / 2048)] {
+            let (signature, size) = signature(&storage, "large", file_size)
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(size, 100_000);
+            assert_eq!(signature.blocks.len(), blocks);
+        }
+    }
+
+    #[test]
+    fn signature_longer_than_the_upload_is_refused() {
+        let block_len = MIN_BLOCK_LEN as u32;
+        let mut encoded = Vec::from(MAGIC);
+        encoded.extend_from_slice(&block_len.to_le_bytes());
+        encoded.extend_from_slice(&u32::MAX.to_le_bytes());
+        // Refused from the prefix alone, before any block arrives.
+        assert!(matches!(
+            try_decode_signature(&encoded, 1 << 20),
+            Err(CodecError::InvalidBody(_))
+        ));
+
+        let signature = Signature {
+            block_len,
+            blocks: vec![
+                BlockSignature {
+                    weak: 0,
+                    strong: [0; STRONG_LEN],
+                };
+                3
+            ],
+        };
+        let encoded = encode_signature(&signature).unwrap();
+        let file_size = 2 * MIN_BLOCK_LEN;
+        assert!(try_decode_signature(&encoded, file_size).is_err());
+        assert!(
+            try_decode_signature(&encoded, file_size + 1)
+                .unwrap()
+                .is_some()
+        );
+    }
+
+    #[tokio::test]
+    async fn unchanged_file_is_copied() {
+        let base = random("base", 100_000);
+        let stats = rebuild(&base, &base).await;
+        let block_len = block_len_for(base.len() as u64) as u64;
+        // Only the shorter last block is sent.
+        assert_eq!(stats.literal, base.len() as u64 % block_len);
+    }
+
+    #[tokio::test]
+    async fn rebuilds_inserted_block() {
+        let base = random("base", 100_000);
+        let mut new = base.clone();
+        new.splice(50_001..50_001, random("inserted", 3000));
+        let stats = rebuild(&base, &new).await;
+        assert!(stats.copied >= base.len() as u64 - 3 * 2048);
+    }
+
+    #[tokio::test]
+    async fn rebuilds_deleted_block() {
+        let base = random("base", 100_000);
+        let mut new = base.clone();
+        new.drain(10_000..15_000);
+        let stats = rebuild(&base, &new).await;
+        // The shorter version holds one whole block fewer, so the last one
+        // is not in the signature either.
+        assert!(stats.copied >= new.len() as u64 - 4 * 2048);
+    }
+
+    #[tokio::test]
+    async fn rebuilds_changed_block() {
+        let base = random("base", 100_000);
+        let mut new = base.clone();
+        new[70_000..70_100].copy_from_slice(&random("changed", 100));
+        let stats = rebuild(&base, &new).await;
+        assert!(stats.literal <= 3 * 2048);
+    }
+
+    #[tokio::test]
+    async fn rebuilds_reordered_and_unrelated_files() {
+        let base = random("base", 100_000);
+        let mut reordered = base[50_000..].to_vec();
+        reordered.extend_from_slice(&base[..50_000]);
+        rebuild(&base, &reordered).await;
+
+        let unrelated = random("unrelated", 80_000);
+        let stats = rebuild(&base, &unrelated).await;
+        assert_eq!(stats.copied, 0);
+        rebuild(&base, &[]).await;
+    }
+}
@@ -0,0 +1,293 @@
+//! Errors returned by the quic3 library, and the QUIC application error
+//! codes streams and connections are closed with so the peer learns why.
+
+use crate::{MAX_METADATA_LEN, MAX_NAME_LEN, NegotiationStatus, TransferStatus};
+use s2n_quic::{application, connection, stream};
+use std::fmt;
+
+/// Result type of the quic3 library.
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// Everything that can go wrong in a transfer.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// A frame received from the peer is malformed.
+    Codec(CodecError),
+    /// A certificate or key could not be created, read or used.
+    Tls(String),
+    Io(std::io::Error),
+    /// The QUIC connection failed or was closed.
+    Connection(connection::Error),
+    /// A stream failed or was reset.
+    Stream(stream::Error),
+    /// The peer broke the protocol, e.g. by ending a stream early or sending
+    /// more data than it announced.
+    Protocol(String),
+    /// The server refused the request during negotiation.
+    Rejected {
+        status: NegotiationStatus,
+        reason: String,
+    },
+    /// The server received an upload but did not store it, e.g. because its
+    /// checksum did not match.
+    NotStored {
+        status: TransferStatus,
+        detail: String,
+    },
+    /// The peer closed the stream or connection, giving one of our codes as
+    /// the reason.
+    Aborted(ErrorCode),
+    /// A limit was exceeded, such as a timeout or the room left for an
+    /// upload.
+    Limit(String),
+    /// A [`crate::storage::StorageSink`] failed.
+    Storage(anyhow::Error),
+    /// The receiver cut the transfer off to shut down.
+    ShuttingDown,
+}
+
+impl Error {
+    /// The application error code to close a stream or connection with
+    /// because of this error.
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            Error::Codec(_) => ErrorCode::Codec,
+            Error::Protocol(_) => ErrorCode::Protocol,
+            Error::Limit(_) => ErrorCode::Limit,
+            Error::Storage(_) => ErrorCode::Storage,
+            Error::ShuttingDown => ErrorCode::ShuttingDown,
+            Error::Tls(_)
+            | Error::Io(_)
+            | Error::Connection(_)
+            | Error::Stream(_)
+            | Error::Rejected { .. }
+            | Error::NotStored { .. }
+            | Error::Aborted(_) => ErrorCode::Internal,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Codec(err) => err.fmt(f),
+            Error::Tls(reason) => f.write_str(reason),
+            Error::Io(err) => err.fmt(f),
+            Error::Connection(err) => err.fmt(f),
+            Error::Stream(err) => err.fmt(f),
+            Error::Protocol(reason) => write!(f, "protocol violation: {reason}"),
+            Error::Rejected { reason, .. } => f.write_str(reason),
+            Error::NotStored { status, detail } => {
+                write!(f, "server did not store the file ({status:?}): {detail}")
+            }
+            Error::Aborted(code) => write!(f, "peer closed the stream: {}", code.description()),
+            Error::Limit(reason) => f.write_str(reason),
+            Error::Storage(err) => write!(f, "storage failed: {err:#}"),
+            Error::ShuttingDown => f.write_str("the server is shutting down"),
+        }
+    }
+}
+
+/// Wrapped errors are displayed as they are, so their sources are passed
+/// through rather than the errors themselves, which would repeat them.
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Codec(err) => err.source(),
+            Error::Io(err) => err.source(),
+            Error::Connection(err) => std::error::Error::source(err),
+            Error::Stream(err) => std::error::Error::source(err),
+            // The message of a storage error already holds its whole chain.
+            _ => None,
+        }
+    }
+}
+
+impl From<CodecError> for Error {
+    fn from(err: CodecError) -> Self {
+        Error::Codec(err)
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
+impl From<rcgen::Error> for Error {
+    fn from(err: rcgen::Error) -> Self {
+        Error::Tls(err.to_string())
+    }
+}
+
+impl From<tokio::task::JoinError> for Error {
+    fn from(err: tokio::task::JoinError) -> Self {
+        Error::Io(err.into())
+    }
+}
+
+/// Errors of storage backends, which implement [`crate::storage::StorageSink`]
+/// with `anyhow`.
+impl From<anyhow::Error> for Error {
+    fn from(err: anyhow::Error) -> Self {
+        Error::Storage(err)
+    }
+}
+
+impl From<connection::Error> for Error {
+    fn from(err: connection::Error) -> Self {
+        if let connection::Error::Application { error, .. } = err
+            && let Some(code) = ErrorCode::from_code(error.into())
+        {
+            return Error::Aborted(code);
+        }
+        Error::Connection(err)
+    }
+}
+
+impl From<stream::Error> for Error {
+    fn from(err: stream::Error) -> Self {
+        match err {
+            stream::Error::StreamReset { error, .. } => match ErrorCode::from_code(error.into()) {
+                Some(code) => Error::Aborted(code),
+                None => Error::Stream(err),
+            },
+            stream::Error::ConnectionError { error, .. } => error.into(),
+            _ => Error::Stream(err),
+        }
+    }
+}
+
+/// QUIC application error codes quic3 closes streams and connections with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ErrorCode {
+    /// The client did not present a usable certificate.
+    Unauthenticated = 1,
+    /// The server has as many connections open as it allows.
+    TooManyConnections = 2,
+    /// A frame could not be decoded.
+    Codec = 3,
+    Protocol = 4,
+    /// A limit was exceeded, e.g. the peer stalled for too long.
+    Limit = 5,
+    /// The server could not store or read the data.
+    Storage = 6,
+    Internal = 7,
+    /// The server is shutting down; retry once it is back.
+    ShuttingDown = 8,
+}
+
+impl ErrorCode {
+    /// The code a peer sent, if it is one of ours.
+    pub fn from_code(code: u64) -> Option<Self> {
+        Some(match code {
+            1 => ErrorCode::Unauthenticated,
+            2 => ErrorCode::TooManyConnections,
+            3 => ErrorCode::Codec,
+            4 => ErrorCode::Protocol,
+            5 => ErrorCode::Limit,
+            6 => ErrorCode::Storage,
+            7 => ErrorCode::Internal,
+            8 => ErrorCode::ShuttingDown,
+            _ => return None,
+        })
+    }
+
+    pub fn description(self) -> &'static str {
+        match self {
+            ErrorCode::Unauthenticated => "no usable client certificate",
+            ErrorCode::TooManyConnections => "too many connections",
+            ErrorCode::Codec => "malformed frame",
+            ErrorCode::Protocol => "protocol violation",
+            ErrorCode::Limit => "limit exceeded",
+            ErrorCode::Storage => "storage failure",
+            ErrorCode::Internal => "internal error",
+            ErrorCode::ShuttingDown => "server shutting down",
+        }
+    }
+}
+
+impl From<ErrorCode> for application::Error {
+    fn from(code: ErrorCode) -> Self {
+        (code as u32).into()
+    }
+}
+
+/// Why a frame received from the peer could not be decoded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum CodecError {
+    /// The buffer does not start with [`crate::MAGIC`].
+    BadMagic,
+    UnsupportedVersion(u8),
+    /// A field holds a value this version does not know, e.g. a request type.
+    UnknownValue {
+        field: &'static str,
+        value: u8,
+    },
+    /// The name is longer than [`MAX_NAME_LEN`] bytes.
+    NameTooLong(usize),
+    NameNotUtf8,
+    /// The name contains a control character.
+    ForbiddenCharacter(char),
+    /// The name cannot be stored safely, e.g. because it escapes its root.
+    InvalidPath {
+        path: String,
+        reason: &'static str,
+    },
+    /// The metadata section is longer than [`MAX_METADATA_LEN`] bytes.
+    MetadataTooLarge(usize),
+    InvalidMetadata(String),
+    /// A delta or compressed body does not decode.
+    InvalidBody(String),
+}
+
+impl CodecError {
+    /// Status the server refuses a header with, once it has read a valid
+    /// preamble.
+    pub fn status(&self) -> NegotiationStatus {
+        match self {
+            CodecError::UnsupportedVersion(_) => NegotiationStatus::UnsupportedVersion,
+            CodecError::NameTooLong(_)
+            | CodecError::NameNotUtf8
+            | CodecError::ForbiddenCharacter(_)
+            | CodecError::InvalidPath { .. } => NegotiationStatus::InvalidPath,
+            CodecError::BadMagic
+            | CodecError::UnknownValue { .. }
+            | CodecError::MetadataTooLarge(_)
+            | CodecError::InvalidMetadata(_)
+            | CodecError::InvalidBody(_) => NegotiationStatus::UnsupportedRequest,
+        }
+    }
+}
+
+impl fmt::Display for CodecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CodecError::BadMagic => f.write_str("bad magic bytes, peer is not speaking quic3"),
+            CodecError::UnsupportedVersion(version) => {
+                write!(f, "unsupported protocol version {version}")
+            }
+            CodecError::UnknownValue { field, value } => write!(f, "unknown {field} {value}"),
+            CodecError::NameTooLong(len) => {
+                write!(f, "name of {len} bytes exceeds the {MAX_NAME_LEN} allowed")
+            }
+            CodecError::NameNotUtf8 => f.write_str("name is not valid UTF-8"),
+            CodecError::ForbiddenCharacter(c) => {
+                write!(f, "name contains the forbidden character {c:?}")
+            }
+            CodecError::InvalidPath { path, reason } => write!(f, "path '{path}' {reason}"),
+            CodecError::MetadataTooLarge(len) => write!(
+                f,
+                "file metadata of {len} bytes exceeds the {MAX_METADATA_LEN} allowed"
+            ),
+            CodecError::InvalidMetadata(reason) => write!(f, "invalid file metadata: {reason}"),
+            CodecError::InvalidBody(reason) => f.write_str(reason),
+        }
+    }
+}
+
+impl std::error::Error for CodecError {}
@@ -0,0 +1,134 @@
+//! Serving side of downloads: sends files from the server's export directory
+//! to clients that ask for them by name.
+
+use crate::receiver::{Peer, reject};
+use crate::source::transfer_id_for;
+use crate::storage::find_file;
+use crate::{
+    FLAG_CHECKSUM, FLAG_RESUME, FileHeader, Negotiation, NegotiationStatus, PROTOCOL_VERSION,
+    Request, Result, encode_header, encode_negotiation,
+};
+use bytes::Bytes;
+use s2n_quic::stream::BidirectionalStream;
+use std::io::SeekFrom;
+use std::path::Path;
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, BufReader};
+
+/// Answer a download request for `header.file_name` from `export_dir`.
+///
+/// The client may resume by proposing an offset together with the transfer
+/// ID it was given earlier; the offset is only honoured while the file still
+/// has that ID, i.e. has not changed since.
+pub(crate) async fn serve_download(
+    stream: &mut BidirectionalStream,
+    export_dir: Option<&Path>,
+    header: &FileHeader,
+    mut negotiation: Negotiation,
+    peer: &Peer,
+) -> Result<()> {
+    let Some(export_dir) = export_dir else {
+        eprintln!(
+            "[{peer}] refusing download of '{}': nothing is exported",
+            header.file_name
+        );
+        negotiation.status = NegotiationStatus::UnsupportedRequest;
+        reject(stream, &negotiation).await;
+        return Ok(());
+    };
+    let path = match find_file(export_dir, &header.file_name).await {
+        Ok(Some(path)) => path,
+        Ok(None) => {
+            eprintln!("[{peer}] '{}' is not exported", header.file_name);
+            negotiation.status = NegotiationStatus::NotFound;
+            reject(stream, &negotiation).await;
+            return Ok(());
+        }
+        Err(err) => {
+            eprintln!(
+                "[{peer}] refusing download of '{}': {err}",
+                header.file_name
+            );
+            negotiation.status = NegotiationStatus::InvalidPath;
+            reject(stream, &negotiation).await;
+            return Ok(());
+        }
+    };
+
+    let file = File::open(&path).await?;
+    let metadata = file.metadata().await?;
+    let file_size = metadata.len();
+    let transfer_id = transfer_id_for(&path, &metadata).await?;
+    negotiation.flags &= FLAG_CHECKSUM | FLAG_RESUME;
+    negotiation.offset =
+        if negotiation.flags & FLAG_RESUME != 0 && header.transfer_id == transfer_id {
+            header.offset.min(file_size)
+        } else {
+            0
+        };
+
+    let reply = FileHeader {
+        version: PROTOCOL_VERSION,
+        flags: negotiation.flags,
+        request: Request::Download,
+        file_name: header.file_name.clone(),
+        file_size,
+        offset: negotiation.offset,
+        range_start: 0,
+        range_end: file_size,
+        transfer_id,
+        metadata: None,
+    };
+    stream
+        .send(Bytes::from(encode_negotiation(&negotiation)))
+        .await?;
+    stream.send(Bytes::from(encode_header(&reply)?)).await?;
+
+    let checksum = negotiation.flags & FLAG_CHECKSUM != 0;
+    let mut reader = BufReader::new(file);
+    let mut buffer = vec![0u8; 64 * 1024];
+    let mut hasher = blake3::Hasher::new();
+    if checksum {
+        // The digest covers the whole file, so the part the client holds is hashed too.
+        let mut prefix = (&mut reader).take(negotiation.offset);
+        loop {
+            let bytes_read = prefix.read(&mut buffer).await?;
+            if bytes_read == 0 {
+                break;
+            }
+            hasher.update(&buffer[..bytes_read]);
+        }
+    } else {
+        reader.seek(SeekFrom::Start(negotiation.offset)).await?;
+    }
+
+    let mut body = reader.take(file_size - negotiation.offset);
+    let mut sent: u64 = 0;
+    loop {
+        let bytes_read = body.read(&mut buffer).await?;
+        if bytes_read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..bytes_read]);
+        stream
+            .send(Bytes::copy_from_slice(&buffer[..bytes_read]))
+            .await?;
+        sent += bytes_read as u64;
+    }
+    if checksum {
+        stream
+            .send(Bytes::copy_from_slice(hasher.finalize().as_bytes()))
+            .await?;
+    }
+    stream.finish()?;
+
+    if negotiation.offset > 0 {
+        println!(
+            "[{peer}] sent '{}' ({sent} bytes, resumed at byte {})",
+            header.file_name, negotiation.offset
+        );
+    } else {
+        println!("[{peer}] sent '{}' ({sent} bytes)", header.file_name);
+    }
+    Ok(())
+}
@@ -1,54 +1,830 @@
-use anyhow::{Result, anyhow};
+pub mod certs;
+pub mod compression;
+pub mod delta;
+pub mod error;
+pub mod receiver;
+pub mod sender;
+pub mod source;
+pub mod storage;
+
+mod export;
+mod limits;
+
+pub use error::{CodecError, Error, ErrorCode, Result};
+
 use rcgen::{Certificate, CertificateParams, DistinguishedName};
 use std::fs;
 use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Magic bytes that open every quic3 frame.
+pub const MAGIC: [u8; 4] = *b"QIC3";
+
+/// Wire protocol version spoken by this build.
+pub const PROTOCOL_VERSION: u8 = 3;
+
+/// The client appends a BLAKE3 digest of the file contents after the body.
+pub const FLAG_CHECKSUM: u32 = 1 << 0;
+
+/// The server keeps interrupted uploads so the same transfer ID can resume them.
+pub const FLAG_RESUME: u32 = 1 << 1;
+
+/// The file is split into ranges sent concurrently on several streams that
+/// share one transfer ID; the server reassembles them before committing.
+pub const FLAG_RANGES: u32 = 1 << 2;
+
+/// The size of the file is not known up front: the header's size and range
+/// are zero and the body runs until the client finishes the stream, its last
+/// [`CHECKSUM_LEN`] bytes being the digest when checksums are in use. Such
+/// uploads cannot be resumed or split into ranges.
+pub const FLAG_STREAMING: u32 = 1 << 3;
+
+/// A sync request also removes stored files below its directory that are
+/// missing from the client's manifest. Servers only accept it when allowed
+/// to delete.
+pub const FLAG_DELETE: u32 = 1 << 4;
+
+/// The client would rather send the changes to the server's existing copy of
+/// the file than the whole body. If the server holds a copy, it answers with
+/// a [`delta::Signature`] of it and the body is a stream of
+/// [`delta::Instruction`]s. Requires [`FLAG_CHECKSUM`]; such uploads cannot be
+/// resumed or split into ranges.
+pub const FLAG_DELTA: u32 = 1 << 5;
+
+/// Everything the client sends after the negotiation reply is compressed
+/// with Zstandard, in the block format of [`compression`].
+pub const FLAG_ZSTD: u32 = 1 << 6;
+
+/// Like [`FLAG_ZSTD`], with LZ4. A client offers at most one codec.
+pub const FLAG_LZ4: u32 = 1 << 7;
+
+/// The client means to replace any file already stored under the name, so
+/// the server overwrites it even if its conflict policy would otherwise
+/// refuse the upload or store it under another name.
+pub const FLAG_OVERWRITE: u32 = 1 << 8;
+
+/// The upload header is followed by a [`FileMetadata`] section, which the
+/// server applies to the stored file if it is configured to. The server only
+/// keeps the flag in its reply if it will.
+pub const FLAG_METADATA: u32 = 1 << 9;
+
+/// Feature flags this build knows how to honour. Peers negotiate the
+/// intersection of what the client offers and what the server supports.
+pub const SUPPORTED_FLAGS: u32 = FLAG_CHECKSUM
+    | FLAG_RESUME
+    | FLAG_RANGES
+    | FLAG_STREAMING
+    | FLAG_DELETE
+    | FLAG_DELTA
+    | FLAG_ZSTD
+    | FLAG_LZ4
+    | FLAG_OVERWRITE
+    | FLAG_METADATA;
+
+pub const PREAMBLE_LEN: usize = 4 + 1 + 4; // magic + version (u8) + feature flags (u32)
+pub const TRANSFER_ID_LEN: usize = 16;
+// preamble + request (u8) + name length (u16) + file size (u64) + offset (u64) + range (2 * u64) + transfer ID
+pub const HEADER_PREFIX_LEN: usize = PREAMBLE_LEN + 1 + 2 + 8 + 8 + 8 + 8 + TRANSFER_ID_LEN;
+// magic + status (u8) + version (u8) + flags (u32) + offset (u64)
+pub const NEGOTIATION_LEN: usize = 4 + 1 + 1 + 4 + 8;
+pub const CHECKSUM_LEN: usize = blake3::OUT_LEN;
+// magic + status (u8) + placement (u8) + bytes stored (u64) + detail length (u16)
+pub const RESPONSE_PREFIX_LEN: usize = 4 + 1 + 1 + 8 + 2;
+// magic + present fields (u8) + size (u64) + modification time (i64) + name length (u16)
+pub const ENTRY_PREFIX_LEN: usize = 4 + 1 + 8 + 8 + 2;
+
+const ENTRY_HAS_MODIFIED: u8 = 1 << 0;
+const ENTRY_HAS_CHECKSUM: u8 = 1 << 1;
+
+/// Longest file name, in bytes, a header or entry may carry.
+pub const MAX_NAME_LEN: usize = 4096;
+/// Largest metadata section a header may carry.
+pub const MAX_METADATA_LEN: usize = 1024 * 1024;
+/// Largest header a peer may send: the fixed prefix, the longest name and
+/// the largest metadata section with its length.
+pub const MAX_HEADER_LEN: usize = HEADER_PREFIX_LEN + MAX_NAME_LEN + 4 + MAX_METADATA_LEN;
+// tag (u8) + value length (u32)
+const RECORD_PREFIX_LEN: usize = 1 + 4;
+
+const RECORD_MODE: u8 = 1;
+const RECORD_MODIFIED: u8 = 2;
+const RECORD_ACCESSED: u8 = 3;
+const RECORD_OWNER: u8 = 4;
+const RECORD_XATTR: u8 = 5;
+
+/// Version-independent prefix of a file header.
+#[derive(Debug, Clone)]
+pub struct Preamble {
+    pub version: u8,
+    pub flags: u32,
+}
+
+/// Stable identifier a client attaches to a file so interrupted uploads can be resumed.
+pub type TransferId = [u8; TRANSFER_ID_LEN];
+
+/// What the client opening a stream asks the server to do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Request {
+    /// Store the file that follows the header.
+    Upload,
+    /// Send back the named file from the server's export directory. The
+    /// server answers with its own header carrying the size and transfer ID,
+    /// followed by the body from the negotiated offset.
+    Download,
+    /// List the stored files below the directory named in the header (every
+    /// file for an empty name). The server answers with one [`FileEntry`]
+    /// frame per file and then finishes the stream.
+    List,
+    /// Describe the stored file named in the header with a single [`FileEntry`].
+    Stat,
+    /// Compare a manifest with the stored files below the directory (or the
+    /// single file) named in the header. The client sends one [`FileEntry`]
+    /// per local file and finishes its half of the stream; the server answers
+    /// with the manifest entries it is missing or holds an older or different
+    /// copy of, followed by the stored files it removed if [`FLAG_DELETE`]
+    /// is in effect. A wanted entry with a checksum names a copy of the same
+    /// size the server could not date; the client sends the file only if its
+    /// own digest differs.
+    Sync,
+}
 
 /// Metadata describing a single file transfer.
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct FileHeader {
+    pub version: u8,
+    pub flags: u32,
+    pub request: Request,
     pub file_name: String,
     pub file_size: u64,
+    /// Offset the client proposes to start sending from. The server clamps it
+    /// to the bytes of the range it already holds for `transfer_id`, so a
+    /// client resuming passes `range_end` and one forcing a fresh upload
+    /// passes `range_start`.
+    pub offset: u64,
+    /// Start of the part of the file carried by this stream.
+    pub range_start: u64,
+    /// End (exclusive) of the part of the file carried by this stream. A
+    /// stream carrying the whole file uses `0..file_size`.
+    pub range_end: u64,
+    pub transfer_id: TransferId,
+    /// Attributes of the file being uploaded; sent if [`FLAG_METADATA`] is set.
+    pub metadata: Option<FileMetadata>,
+}
+
+/// Attributes of an uploaded file beyond its contents.
+///
+/// On the wire this is a section of tagged records, so fields can be added
+/// without breaking older peers, which skip records they do not know.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FileMetadata {
+    /// Unix permission bits.
+    pub mode: Option<u32>,
+    pub modified: Option<SystemTime>,
+    pub accessed: Option<SystemTime>,
+    /// Numeric user and group ID of the owner.
+    pub owner: Option<(u32, u32)>,
+    /// Extended attributes as name and value pairs.
+    pub xattrs: Vec<(String, Vec<u8>)>,
 }
 
-pub const HEADER_PREFIX_LEN: usize = 2 + 8; // name length (u16) + file size (u64)
+/// Outcome of the version negotiation performed by the server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NegotiationStatus {
+    Accepted,
+    UnsupportedVersion,
+    /// Another stream is currently uploading the same transfer ID.
+    TransferInProgress,
+    /// The file name is not a safe relative path under the server's output directory.
+    InvalidPath,
+    /// The range does not fit inside the file.
+    InvalidRange,
+    /// The server does not serve this kind of request, e.g. downloads when
+    /// it has no export directory.
+    UnsupportedRequest,
+    /// The requested file does not exist.
+    NotFound,
+    /// A file is already stored under the name and the server's conflict
+    /// policy refuses to replace it.
+    AlreadyExists,
+    /// The file is larger than the server accepts.
+    TooLarge,
+    /// Storing the file would exceed the server's quota for all clients or
+    /// for this one.
+    QuotaExceeded,
+    /// Storing the file would leave the server with too little free space.
+    InsufficientStorage,
+    /// The server is serving as many streams as it allows, in total or for
+    /// this client; the request may succeed later.
+    Busy,
+}
+
+/// Reply sent by the server on the return half of the stream once it has
+/// inspected the client's header.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Negotiation {
+    pub status: NegotiationStatus,
+    /// Protocol version the server speaks.
+    pub version: u8,
+    /// Feature flags in effect for the rest of the stream.
+    pub flags: u32,
+    /// Offset the body must start at; everything before it is already stored.
+    pub offset: u64,
+}
+
+/// Final verdict the server sends once the whole body has been received.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransferStatus {
+    Ok,
+    SizeMismatch,
+    ChecksumMismatch,
+    /// The server could not store the file, e.g. because of an I/O error.
+    Failed,
+    /// The stream's range was verified and staged; another range still has
+    /// to arrive before the file is committed.
+    RangeStored,
+}
+
+/// How a committed upload relates to the file previously stored under its name.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Placement {
+    /// Nothing was stored under the name.
+    Created,
+    /// The previous file was replaced.
+    Overwritten,
+    /// The previous file was kept and the upload stored under a new name.
+    Renamed,
+    /// The previous file was kept as an older version and the upload took
+    /// its place.
+    Versioned,
+}
+
+/// Structured result the server writes back once a transfer has finished.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TransferResponse {
+    pub status: TransferStatus,
+    /// What happened to an existing file of the same name; only set once
+    /// the file has been committed.
+    pub placement: Option<Placement>,
+    /// Bytes committed to storage by this stream: the whole file for
+    /// [`TransferStatus::Ok`], the range for [`TransferStatus::RangeStored`]
+    /// and 0 otherwise.
+    pub bytes_stored: u64,
+    /// Final path of the stored file on success, otherwise the reason for the failure.
+    pub detail: String,
+}
+
+/// A stored file, as reported by list and stat requests, or a local file in
+/// the manifest of a sync request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileEntry {
+    /// Relative `/`-separated name the file is stored under.
+    pub name: String,
+    pub size: u64,
+    pub modified: Option<SystemTime>,
+    /// BLAKE3 digest of the contents; only computed when the request sets
+    /// [`FLAG_CHECKSUM`].
+    pub checksum: Option<[u8; CHECKSUM_LEN]>,
+}
+
+/// Encode a file header into a versioned, length-prefixed buffer.
+pub fn encode_header(header: &FileHeader) -> Result<Vec<u8>> {
+    let name_bytes = header.file_name.as_bytes();
+    if name_bytes.len() > MAX_NAME_LEN {
+        return Err(CodecError::NameTooLong(name_bytes.len()).into());
+    }
+
+    let mut buf = Vec::with_capacity(HEADER_PREFIX_LEN + name_bytes.len());
+    buf.extend_from_slice(&MAGIC);
+    buf.push(header.version);
+    buf.extend_from_slice(&header.flags.to_le_bytes());
+    buf.push(match header.request {
+        Request::Upload => 0,
+        Request::Download => 1,
+        Request::List => 2,
+        Request::Stat => 3,
+        Request::Sync => 4,
+    });
+    buf.extend_from_slice(&(name_bytes.len() as u16).to_le_bytes());
+    buf.extend_from_slice(&header.file_size.to_le_bytes());
+    buf.extend_from_slice(&header.offset.to_le_bytes());
+    buf.extend_from_slice(&header.range_start.to_le_bytes());
+    buf.extend_from_slice(&header.range_end.to_le_bytes());
+    buf.extend_from_slice(&header.transfer_id);
+    buf.extend_from_slice(name_bytes);
+    if header.flags & FLAG_METADATA != 0 {
+        let metadata = header.metadata.clone().unwrap_or_default();
+        encode_metadata(&metadata, &mut buf)?;
+    }
+    Ok(buf)
+}
+
+/// Append a metadata section: its length (`u32`), then one record per
+/// attribute, each a tag (`u8`), a value length (`u32`) and the value.
+fn encode_metadata(metadata: &FileMetadata, buf: &mut Vec<u8>) -> Result<()> {
+    let mut records = Vec::new();
+    let mut record = |tag: u8, value: &[u8]| {
+        records.push(tag);
+        records.extend_from_slice(&(value.len() as u32).to_le_bytes());
+        records.extend_from_slice(value);
+    };
+    if let Some(mode) = metadata.mode {
+        record(RECORD_MODE, &mode.to_le_bytes());
+    }
+    if let Some(modified) = metadata.modified {
+        record(RECORD_MODIFIED, &time_to_nanos(modified).to_le_bytes());
+    }
+    if let Some(accessed) = metadata.accessed {
+        record(RECORD_ACCESSED, &time_to_nanos(accessed).to_le_bytes());
+    }
+    if let Some((uid, gid)) = metadata.owner {
+        let mut value = uid.to_le_bytes().to_vec();
+        value.extend_from_slice(&gid.to_le_bytes());
+        record(RECORD_OWNER, &value);
+    }
+    for (name, value) in &metadata.xattrs {
+        let name = name.as_bytes();
+        if name.len() > u16::MAX as usize {
+            return Err(CodecError::InvalidMetadata(format!(
+                "extended attribute name of {} bytes is too long",
+                name.len()
+            ))
+            .into());
+        }
+        let mut xattr = (name.len() as u16).to_le_bytes().to_vec();
+        xattr.extend_from_slice(name);
+        xattr.extend_from_slice(value);
+        record(RECORD_XATTR, &xattr);
+    }
+
+    if records.len() > MAX_METADATA_LEN {
+        return Err(CodecError::MetadataTooLarge(records.len()).into());
+    }
+    buf.extend_from_slice(&(records.len() as u32).to_le_bytes());
+    buf.extend_from_slice(&records);
+    Ok(())
+}
+
+/// Decode the records of a metadata section, skipping unknown tags.
+fn decode_metadata(mut records: &[u8]) -> Result<FileMetadata, CodecError> {
+    let mut metadata = FileMetadata::default();
+    while !records.is_empty() {
+        if records.len() < RECORD_PREFIX_LEN {
+            return Err(CodecError::InvalidMetadata("truncated record".into()));
+        }
+        let tag = records[0];
+        let len = read_u32(records, 1) as usize;
+        let Some(value) = records.get(RECORD_PREFIX_LEN..RECORD_PREFIX_LEN + len) else {
+            return Err(CodecError::InvalidMetadata("truncated record".into()));
+        };
+        records = &records[RECORD_PREFIX_LEN + len..];
+
+        let expect = |expected: usize| {
+            if len == expected {
+                Ok(())
+            } else {
+                Err(CodecError::InvalidMetadata(format!(
+                    "record {tag} has {len} bytes, not {expected}"
+                )))
+            }
+        };
+        match tag {
+            RECORD_MODE => {
+                expect(4)?;
+                metadata.mode = Some(read_u32(value, 0));
+            }
+            RECORD_MODIFIED => {
+                expect(8)?;
+                metadata.modified = Some(nanos_to_time(read_u64(value, 0) as i64));
+            }
+            RECORD_ACCESSED => {
+                expect(8)?;
+                metadata.accessed = Some(nanos_to_time(read_u64(value, 0) as i64));
+            }
+            RECORD_OWNER => {
+                expect(8)?;
+                metadata.owner = Some((read_u32(value, 0), read_u32(value, 4)));
+            }
+            RECORD_XATTR => {
+                if len < 2 {
+                    return Err(CodecError::InvalidMetadata(
+                        "truncated extended attribute".into(),
+                    ));
+                }
+                let name_len = u16::from_le_bytes([value[0], value[1]]) as usize;
+                let Some(name) = value.get(2..2 + name_len) else {
+                    return Err(CodecError::InvalidMetadata(
+                        "truncated extended attribute".into(),
+                    ));
+                };
+                let name = String::from_utf8(name.to_vec()).map_err(|_| {
+                    CodecError::InvalidMetadata("extended attribute name is not UTF-8".into())
+                })?;
+                metadata.xattrs.push((name, value[2 + name_len..].to_vec()));
+            }
+            _ => {}
+        }
+    }
+    Ok(metadata)
+}
 
-/// Encode a file header into a length-prefixed buffer.
-pub fn encode_header(file_name: &str, file_size: u64) -> Result<Vec<u8>> {
-    let name_bytes = file_name.as_bytes();
-    if name_bytes.len() > u16::MAX as usize {
-        return Err(anyhow!("file name too long"));
+/// Attempt to decode the version-independent preamble of a file header.
+///
+/// Returns `Ok(None)` if more bytes are needed and an error if the buffer
+/// does not start with [`MAGIC`].
+pub fn try_decode_preamble(buf: &[u8]) -> Result<Option<(Preamble, usize)>, CodecError> {
+    if buf.len() < PREAMBLE_LEN {
+        return Ok(None);
+    }
+    if buf[..4] != MAGIC {
+        return Err(CodecError::BadMagic);
     }
 
-    let mut header = Vec::with_capacity(HEADER_PREFIX_LEN + name_bytes.len());
-    header.extend_from_slice(&(name_bytes.len() as u16).to_le_bytes());
-    header.extend_from_slice(&file_size.to_le_bytes());
-    header.extend_from_slice(name_bytes);
-    Ok(header)
+    let version = buf[4];
+    let flags = read_u32(buf, 5);
+    Ok(Some((Preamble { version, flags }, PREAMBLE_LEN)))
 }
 
 /// Attempt to decode a file header from the provided buffer.
-pub fn try_decode_header(buf: &[u8]) -> Option<(FileHeader, usize)> {
+///
+/// Only headers written with [`PROTOCOL_VERSION`] can be decoded; callers
+/// should check the preamble first so they can reject other versions cleanly.
+/// Names must be valid UTF-8 without control characters; malformed headers
+/// are reported as soon as the bytes that break them have arrived.
+pub fn try_decode_header(buf: &[u8]) -> Result<Option<(FileHeader, usize)>, CodecError> {
+    let Some((preamble, _)) = try_decode_preamble(buf)? else {
+        return Ok(None);
+    };
+    if preamble.version != PROTOCOL_VERSION {
+        return Err(CodecError::UnsupportedVersion(preamble.version));
+    }
     if buf.len() < HEADER_PREFIX_LEN {
-        return None;
+        return Ok(None);
     }
 
-    let name_len = u16::from_le_bytes([buf[0], buf[1]]) as usize;
-    let file_size = u64::from_le_bytes([
-        buf[2], buf[3], buf[4], buf[5], buf[6], buf[7], buf[8], buf[9],
-    ]);
+    let request = match buf[9] {
+        0 => Request::Upload,
+        1 => Request::Download,
+        2 => Request::List,
+        3 => Request::Stat,
+        4 => Request::Sync,
+        other => {
+            return Err(CodecError::UnknownValue {
+                field: "request type",
+                value: other,
+            });
+        }
+    };
+    let name_len = u16::from_le_bytes([buf[10], buf[11]]) as usize;
+    if name_len > MAX_NAME_LEN {
+        return Err(CodecError::NameTooLong(name_len));
+    }
+    let file_size = read_u64(buf, 12);
+    let offset = read_u64(buf, 20);
+    let range_start = read_u64(buf, 28);
+    let range_end = read_u64(buf, 36);
+    let mut transfer_id = [0u8; TRANSFER_ID_LEN];
+    transfer_id.copy_from_slice(&buf[44..44 + TRANSFER_ID_LEN]);
 
     if buf.len() < HEADER_PREFIX_LEN + name_len {
-        return None;
+        return Ok(None);
     }
 
-    let name_bytes = &buf[HEADER_PREFIX_LEN..HEADER_PREFIX_LEN + name_len];
-    let file_name = String::from_utf8_lossy(name_bytes).to_string();
-    Some((
+    let file_name = decode_name(&buf[HEADER_PREFIX_LEN..HEADER_PREFIX_LEN + name_len])?;
+
+    let mut len = HEADER_PREFIX_LEN + name_len;
+    let metadata = if preamble.flags & FLAG_METADATA != 0 {
+        if buf.len() < len + 4 {
+            return Ok(None);
+        }
+        let metadata_len = read_u32(buf, len) as usize;
+        if metadata_len > MAX_METADATA_LEN {
+            return Err(CodecError::MetadataTooLarge(metadata_len));
+        }
+        len += 4;
+        if buf.len() < len + metadata_len {
+            return Ok(None);
+        }
+        let metadata = decode_metadata(&buf[len..len + metadata_len])?;
+        len += metadata_len;
+        Some(metadata)
+    } else {
+        None
+    };
+    Ok(Some((
         FileHeader {
+            version: preamble.version,
+            flags: preamble.flags,
+            request,
             file_name,
             file_size,
+            offset,
+            range_start,
+            range_end,
+            transfer_id,
+            metadata,
+        },
+        len,
+    )))
+}
+
+/// Check that a name received from a peer is UTF-8 without control characters.
+fn decode_name(bytes: &[u8]) -> Result<String, CodecError> {
+    let name = std::str::from_utf8(bytes).map_err(|_| CodecError::NameNotUtf8)?;
+    if let Some(c) = name.chars().find(|c| c.is_control()) {
+        return Err(CodecError::ForbiddenCharacter(c));
+    }
+    Ok(name.to_string())
+}
+
+/// Decide whether the server can serve a client that sent `preamble`.
+///
+/// The returned offset is always 0; the server fills it in once it knows how
+/// much of the transfer it already holds.
+pub fn negotiate(preamble: &Preamble) -> Negotiation {
+    let status = if preamble.version == PROTOCOL_VERSION {
+        NegotiationStatus::Accepted
+    } else {
+        NegotiationStatus::UnsupportedVersion
+    };
+
+    let mut flags = preamble.flags & SUPPORTED_FLAGS;
+    if flags & FLAG_STREAMING != 0 {
+        flags &= !(FLAG_RESUME | FLAG_RANGES);
+    }
+    if flags & (FLAG_STREAMING | FLAG_RANGES) != 0 || flags & FLAG_CHECKSUM == 0 {
+        flags &= !FLAG_DELTA;
+    }
+    if flags & FLAG_ZSTD != 0 {
+        flags &= !FLAG_LZ4;
+    }
+    Negotiation {
+        status,
+        version: PROTOCOL_VERSION,
+        flags,
+        offset: 0,
+    }
+}
+
+/// Encode the server's negotiation reply.
+pub fn encode_negotiation(negotiation: &Negotiation) -> Vec<u8> {
+    let status = match negotiation.status {
+        NegotiationStatus::Accepted => 0u8,
+        NegotiationStatus::UnsupportedVersion => 1u8,
+        NegotiationStatus::TransferInProgress => 2u8,
+        NegotiationStatus::InvalidPath => 3u8,
+        NegotiationStatus::InvalidRange => 4u8,
+        NegotiationStatus::UnsupportedRequest => 5u8,
+        NegotiationStatus::NotFound => 6u8,
+        NegotiationStatus::AlreadyExists => 7u8,
+        NegotiationStatus::TooLarge => 8u8,
+        NegotiationStatus::QuotaExceeded => 9u8,
+        NegotiationStatus::InsufficientStorage => 10u8,
+        NegotiationStatus::Busy => 11u8,
+    };
+
+    let mut reply = Vec::with_capacity(NEGOTIATION_LEN);
+    reply.extend_from_slice(&MAGIC);
+    reply.push(status);
+    reply.push(negotiation.version);
+    reply.extend_from_slice(&negotiation.flags.to_le_bytes());
+    reply.extend_from_slice(&negotiation.offset.to_le_bytes());
+    reply
+}
+
+/// Attempt to decode the server's negotiation reply.
+pub fn try_decode_negotiation(buf: &[u8]) -> Result<Option<(Negotiation, usize)>, CodecError> {
+    if buf.len() < NEGOTIATION_LEN {
+        return Ok(None);
+    }
+    if buf[..4] != MAGIC {
+        return Err(CodecError::BadMagic);
+    }
+
+    let status = match buf[4] {
+        0 => NegotiationStatus::Accepted,
+        1 => NegotiationStatus::UnsupportedVersion,
+        2 => NegotiationStatus::TransferInProgress,
+        3 => NegotiationStatus::InvalidPath,
+        4 => NegotiationStatus::InvalidRange,
+        5 => NegotiationStatus::UnsupportedRequest,
+        6 => NegotiationStatus::NotFound,
+        7 => NegotiationStatus::AlreadyExists,
+        8 => NegotiationStatus::TooLarge,
+        9 => NegotiationStatus::QuotaExceeded,
+        10 => NegotiationStatus::InsufficientStorage,
+        11 => NegotiationStatus::Busy,
+        other => {
+            return Err(CodecError::UnknownValue {
+                field: "negotiation status",
+                value: other,
+            });
+        }
+    };
+    let version = buf[5];
+    let flags = read_u32(buf, 6);
+    let offset = read_u64(buf, 10);
+    Ok(Some((
+        Negotiation {
+            status,
+            version,
+            flags,
+            offset,
+        },
+        NEGOTIATION_LEN,
+    )))
+}
+
+/// Encode the server's final transfer response.
+pub fn encode_response(response: &TransferResponse) -> Vec<u8> {
+    let code = match response.status {
+        TransferStatus::Ok => 0u8,
+        TransferStatus::SizeMismatch => 1u8,
+        TransferStatus::ChecksumMismatch => 2u8,
+        TransferStatus::Failed => 3u8,
+        TransferStatus::RangeStored => 4u8,
+    };
+    let placement = match response.placement {
+        None => 0u8,
+        Some(Placement::Created) => 1,
+        Some(Placement::Overwritten) => 2,
+        Some(Placement::Renamed) => 3,
+        Some(Placement::Versioned) => 4,
+    };
+    // Details are informational, so an overly long one is cut rather than rejected.
+    let mut detail_len = response.detail.len().min(u16::MAX as usize);
+    while !response.detail.is_char_boundary(detail_len) {
+        detail_len -= 1;
+    }
+
+    let mut buf = Vec::with_capacity(RESPONSE_PREFIX_LEN + detail_len);
+    buf.extend_from_slice(&MAGIC);
+    buf.push(code);
+    buf.push(placement);
+    buf.extend_from_slice(&response.bytes_stored.to_le_bytes());
+    buf.extend_from_slice(&(detail_len as u16).to_le_bytes());
+    buf.extend_from_slice(&response.detail.as_bytes()[..detail_len]);
+    buf
+}
+
+/// Attempt to decode the server's final transfer response.
+pub fn try_decode_response(buf: &[u8]) -> Result<Option<(TransferResponse, usize)>, CodecError> {
+    if buf.len() < RESPONSE_PREFIX_LEN {
+        return Ok(None);
+    }
+    if buf[..4] != MAGIC {
+        return Err(CodecError::BadMagic);
+    }
+
+    let status = match buf[4] {
+        0 => TransferStatus::Ok,
+        1 => TransferStatus::SizeMismatch,
+        2 => TransferStatus::ChecksumMismatch,
+        3 => TransferStatus::Failed,
+        4 => TransferStatus::RangeStored,
+        other => {
+            return Err(CodecError::UnknownValue {
+                field: "transfer status",
+                value: other,
+            });
+        }
+    };
+    let placement = match buf[5] {
+        0 => None,
+        1 => Some(Placement::Created),
+        2 => Some(Placement::Overwritten),
+        3 => Some(Placement::Renamed),
+        4 => Some(Placement::Versioned),
+        other => {
+            return Err(CodecError::UnknownValue {
+                field: "placement",
+                value: other,
+            });
+        }
+    };
+    let bytes_stored = read_u64(buf, 6);
+    let detail_len = u16::from_le_bytes([buf[14], buf[15]]) as usize;
+    if buf.len() < RESPONSE_PREFIX_LEN + detail_len {
+        return Ok(None);
+    }
+
+    let detail = &buf[RESPONSE_PREFIX_LEN..RESPONSE_PREFIX_LEN + detail_len];
+    Ok(Some((
+        TransferResponse {
+            status,
+            placement,
+            bytes_stored,
+            detail: String::from_utf8_lossy(detail).to_string(),
+        },
+        RESPONSE_PREFIX_LEN + detail_len,
+    )))
+}
+
+/// Encode one entry of a list or stat reply.
+pub fn encode_entry(entry: &FileEntry) -> Result<Vec<u8>> {
+    let name_bytes = entry.name.as_bytes();
+    if name_bytes.len() > MAX_NAME_LEN {
+        return Err(CodecError::NameTooLong(name_bytes.len()).into());
+    }
+
+    let mut fields = 0;
+    let mut modified = 0i64;
+    if let Some(time) = entry.modified {
+        fields |= ENTRY_HAS_MODIFIED;
+        modified = time_to_nanos(time);
+    }
+    if entry.checksum.is_some() {
+        fields |= ENTRY_HAS_CHECKSUM;
+    }
+
+    let mut buf = Vec::with_capacity(ENTRY_PREFIX_LEN + CHECKSUM_LEN + name_bytes.len());
+    buf.extend_from_slice(&MAGIC);
+    buf.push(fields);
+    buf.extend_from_slice(&entry.size.to_le_bytes());
+    buf.extend_from_slice(&modified.to_le_bytes());
+    buf.extend_from_slice(&(name_bytes.len() as u16).to_le_bytes());
+    if let Some(checksum) = &entry.checksum {
+        buf.extend_from_slice(checksum);
+    }
+    buf.extend_from_slice(name_bytes);
+    Ok(buf)
+}
+
+/// Attempt to decode one entry of a list or stat reply.
+pub fn try_decode_entry(buf: &[u8]) -> Result<Option<(FileEntry, usize)>, CodecError> {
+    if buf.len() < ENTRY_PREFIX_LEN {
+        return Ok(None);
+    }
+    if buf[..4] != MAGIC {
+        return Err(CodecError::BadMagic);
+    }
+
+    let fields = buf[4];
+    let size = read_u64(buf, 5);
+    let modified = read_u64(buf, 13) as i64;
+    let name_len = u16::from_le_bytes([buf[21], buf[22]]) as usize;
+    if name_len > MAX_NAME_LEN {
+        return Err(CodecError::NameTooLong(name_len));
+    }
+    let checksum_len = if fields & ENTRY_HAS_CHECKSUM != 0 {
+        CHECKSUM_LEN
+    } else {
+        0
+    };
+    let len = ENTRY_PREFIX_LEN + checksum_len + name_len;
+    if buf.len() < len {
+        return Ok(None);
+    }
+
+    let checksum = (checksum_len > 0).then(|| {
+        let mut checksum = [0u8; CHECKSUM_LEN];
+        checksum.copy_from_slice(&buf[ENTRY_PREFIX_LEN..ENTRY_PREFIX_LEN + CHECKSUM_LEN]);
+        checksum
+    });
+    let modified = (fields & ENTRY_HAS_MODIFIED != 0).then(|| nanos_to_time(modified));
+    let name = decode_name(&buf[ENTRY_PREFIX_LEN + checksum_len..len])?;
+    Ok(Some((
+        FileEntry {
+            name,
+            size,
+            modified,
+            checksum,
         },
-        HEADER_PREFIX_LEN + name_len,
-    ))
+        len,
+    )))
+}
+
+/// Render a transfer ID as lowercase hex, e.g. for naming partial uploads.
+pub fn format_transfer_id(id: &TransferId) -> String {
+    id.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// Nanoseconds since the Unix epoch, negative for earlier times.
+fn time_to_nanos(time: SystemTime) -> i64 {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(since) => i64::try_from(since.as_nanos()).unwrap_or(i64::MAX),
+        Err(before) => i64::try_from(before.duration().as_nanos()).map_or(i64::MIN, |n| -n),
+    }
+}
+
+fn nanos_to_time(nanos: i64) -> SystemTime {
+    let offset = Duration::from_nanos(nanos.unsigned_abs());
+    if nanos >= 0 {
+        UNIX_EPOCH + offset
+    } else {
+        UNIX_EPOCH - offset
+    }
+}
+
+fn read_u32(buf: &[u8], at: usize) -> u32 {
+    let mut bytes = [0u8; 4];
+    bytes.copy_from_slice(&buf[at..at + 4]);
+    u32::from_le_bytes(bytes)
+}
+
+fn read_u64(buf: &[u8], at: usize) -> u64 {
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&buf[at..at + 8]);
+    u64::from_le_bytes(bytes)
 }
 
 /// Ensure a self-signed certificate exists at the given locations, creating it if needed.
@@ -86,10 +862,622 @@ pub fn ensure_self_signed_certificate(
     Ok((cert_path.to_path_buf(), key_path.to_path_buf()))
 }
 
+/// Who a mutually authenticated peer is, as stated by its leaf certificate.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientIdentity {
+    /// Distinguished name of the certificate subject, e.g. `CN=backup-01`.
+    pub subject: String,
+    /// DNS names, IP addresses, e-mail addresses and URIs from the subject
+    /// alternative name extension.
+    pub alt_names: Vec<String>,
+}
+
+impl ClientIdentity {
+    /// Read the identity from a DER-encoded certificate.
+    pub fn from_der(der: &[u8]) -> Result<Self> {
+        use x509_parser::extensions::GeneralName;
+
+        let (_, cert) = x509_parser::parse_x509_certificate(der)
+            .map_err(|err| Error::Tls(format!("invalid client certificate: {err}")))?;
+        let mut alt_names = Vec::new();
+        if let Some(extension) = cert
+            .subject_alternative_name()
+            .map_err(|err| Error::Tls(format!("invalid subject alternative names: {err}")))?
+        {
+            for name in &extension.value.general_names {
+                match name {
+                    GeneralName::DNSName(name)
+                    | GeneralName::RFC822Name(name)
+                    | GeneralName::URI(name) => alt_names.push(name.to_string()),
+                    GeneralName::IPAddress(bytes) => {
+                        if let Ok(octets) = <[u8; 4]>::try_from(*bytes) {
+                            alt_names.push(std::net::Ipv4Addr::from(octets).to_string());
+                        } else if let Ok(octets) = <[u8; 16]>::try_from(*bytes) {
+                            alt_names.push(std::net::Ipv6Addr::from(octets).to_string());
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        Ok(Self {
+            subject: cert.subject().to_string(),
+            alt_names,
+        })
+    }
+}
+
+impl std::fmt::Display for ClientIdentity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (self.subject.is_empty(), self.alt_names.first()) {
+            (true, Some(name)) => f.write_str(name),
+            (true, None) => f.write_str("<anonymous>"),
+            (false, _) => f.write_str(&self.subject),
+        }
+    }
+}
+
 /// Sanitize a received file name to avoid directory traversal.
+///
+/// Names that are not a single plain file name, including any with a
+/// separator, are replaced by `received_file` rather than stripped to their
+/// last component.
+#[deprecated(note = "names may contain directories; use `sanitize_relative_path`")]
 pub fn sanitize_file_name(input: &str) -> String {
-    Path::new(input)
-        .file_name()
-        .map(|s| s.to_string_lossy().to_string())
-        .unwrap_or_else(|| "received_file".to_string())
+    match sanitize_relative_path(input) {
+        Ok(path) if !input.contains('/') => path.to_string_lossy().to_string(),
+        _ => "received_file".to_string(),
+    }
+}
+
+/// Normalize a `/`-separated relative path received from a peer so it can be
+/// joined onto a local directory without escaping it.
+///
+/// Absolute paths, `..` components, backslashes and NUL bytes are rejected
+/// rather than stripped; empty and `.` components are dropped.
+pub fn sanitize_relative_path(input: &str) -> Result<PathBuf, CodecError> {
+    let invalid = |reason| CodecError::InvalidPath {
+        path: input.to_string(),
+        reason,
+    };
+    if input.starts_with('/') {
+        return Err(invalid("is absolute"));
+    }
+    if input.contains(['\\', '\0']) {
+        return Err(invalid("contains a forbidden character"));
+    }
+
+    let mut path = PathBuf::new();
+    for component in input.split('/') {
+        match component {
+            "" | "." => {}
+            ".." => return Err(invalid("escapes its root")),
+            name => path.push(name),
+        }
+    }
+
+    if path.as_os_str().is_empty() {
+        return Err(invalid("does not name a file"));
+    }
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(request: Request, flags: u32) -> FileHeader {
+        FileHeader {
+            version: PROTOCOL_VERSION,
+            flags,
+            request,
+            file_name: "photos/2024/beach.jpg".to_string(),
+            file_size: 5_000_000,
+            offset: 1_048_576,
+            range_start: 1_048_576,
+            range_end: 2_097_152,
+            transfer_id: *b"0123456789abcdef",
+            metadata: None,
+        }
+    }
+
+    fn metadata() -> FileMetadata {
+        FileMetadata {
+            mode: Some(0o755),
+            modified: Some(UNIX_EPOCH + Duration::from_nanos(1_700_000_000_123_456_789)),
+            accessed: Some(UNIX_EPOCH + Duration::from_secs(1_600_000_000)),
+            owner: Some((1000, 100)),
+            xattrs: vec![("user.origin".to_string(), b"camera".to_vec())],
+        }
+    }
+
+    /// Check that `decode` reads `encoded` back as `expected`, consuming all
+    /// of it, and asks for more bytes at every shorter length.
+    fn round_trip<T, F>(encoded: &[u8], expected: &T, decode: F)
+    where
+        T: PartialEq + std::fmt::Debug,
+        F: Fn(&[u8]) -> Result<Option<(T, usize)>, CodecError>,
+    {
+        let (decoded, used) = decode(encoded).unwrap().unwrap();
+        assert_eq!(&decoded, expected);
+        assert_eq!(used, encoded.len());
+
+        let mut trailing = encoded.to_vec();
+        trailing.extend_from_slice(b"body");
+        let (decoded, used) = decode(&trailing).unwrap().unwrap();
+        assert_eq!(&decoded, expected);
+        assert_eq!(used, encoded.len());
+
+        for len in 0..encoded.len() {
+            assert!(
+                decode(&encoded[..len]).unwrap().is_none(),
+                "decoded a frame from {len} of {} bytes",
+                encoded.len()
+            );
+        }
+    }
+
+    #[test]
+    fn header_round_trips_for_every_request() {
+        for request in [
+            Request::Upload,
+            Request::Download,
+            Request::List,
+            Request::Stat,
+            Request::Sync,
+        ] {
+            let header = header(request, FLAG_CHECKSUM | FLAG_RESUME);
+            round_trip(&encode_header(&header).unwrap(), &header, try_decode_header);
+        }
+    }
+
+    #[test]
+    fn header_round_trips_with_metadata() {
+        let mut header = header(Request::Upload, FLAG_CHECKSUM | FLAG_METADATA);
+        header.metadata = Some(metadata());
+        round_trip(&encode_header(&header).unwrap(), &header, try_decode_header);
+
+        header.metadata = Some(FileMetadata::default());
+        round_trip(&encode_header(&header).unwrap(), &header, try_decode_header);
+    }
+
+    #[test]
+    fn header_without_metadata_flag_drops_metadata() {
+        let mut header = header(Request::Upload, 0);
+        header.metadata = Some(metadata());
+        let encoded = encode_header(&header).unwrap();
+        let (decoded, _) = try_decode_header(&encoded).unwrap().unwrap();
+        assert_eq!(decoded.metadata, None);
+    }
+
+    #[test]
+    fn unknown_metadata_records_are_skipped() {
+        let mut header = header(Request::Upload, FLAG_METADATA);
+        header.metadata = Some(FileMetadata {
+            mode: Some(0o644),
+            ..FileMetadata::default()
+        });
+        let mut encoded = encode_header(&header).unwrap();
+        let section = encoded.len() - (RECORD_PREFIX_LEN + 4) - 4;
+        let records_len = read_u32(&encoded, section) as usize + RECORD_PREFIX_LEN + 3;
+        encoded[section..section + 4].copy_from_slice(&(records_len as u32).to_le_bytes());
+        encoded.extend_from_slice(&[200, 3, 0, 0, 0, 1, 2, 3]);
+
+        let (decoded, used) = try_decode_header(&encoded).unwrap().unwrap();
+        assert_eq!(decoded, header);
+        assert_eq!(used, encoded.len());
+    }
+
+    #[test]
+    fn preamble_is_read_from_any_version() {
+        let mut encoded = encode_header(&header(Request::Upload, FLAG_ZSTD)).unwrap();
+        encoded[4] = PROTOCOL_VERSION + 1;
+        let (preamble, used) = try_decode_preamble(&encoded).unwrap().unwrap();
+        assert_eq!(preamble.version, PROTOCOL_VERSION + 1);
+        assert_eq!(preamble.flags, FLAG_ZSTD);
+        assert_eq!(used, PREAMBLE_LEN);
+        assert!(
+            try_decode_preamble(&encoded[..PREAMBLE_LEN - 1])
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn negotiation_round_trips_for_every_status() {
+        for status in [
+            NegotiationStatus::Accepted,
+            NegotiationStatus::UnsupportedVersion,
+            NegotiationStatus::TransferInProgress,
+            NegotiationStatus::InvalidPath,
+            NegotiationStatus::InvalidRange,
+            NegotiationStatus::UnsupportedRequest,
+            NegotiationStatus::NotFound,
+            NegotiationStatus::AlreadyExists,
+            NegotiationStatus::TooLarge,
+            NegotiationStatus::QuotaExceeded,
+            NegotiationStatus::InsufficientStorage,
+            NegotiationStatus::Busy,
+        ] {
+            let negotiation = Negotiation {
+                status,
+                version: PROTOCOL_VERSION,
+                flags: FLAG_CHECKSUM | FLAG_DELTA,
+                offset: 123_456,
+            };
+            let encoded = encode_negotiation(&negotiation);
+            assert_eq!(encoded.len(), NEGOTIATION_LEN);
+            round_trip(&encoded, &negotiation, try_decode_negotiation);
+        }
+    }
+
+    #[test]
+    fn response_round_trips_for_every_status_and_placement() {
+        let statuses = [
+            TransferStatus::Ok,
+            TransferStatus::SizeMismatch,
+            TransferStatus::ChecksumMismatch,
+            TransferStatus::Failed,
+            TransferStatus::RangeStored,
+        ];
+        let placements = [
+            None,
+            Some(Placement::Created),
+            Some(Placement::Overwritten),
+            Some(Placement::Renamed),
+            Some(Placement::Versioned),
+        ];
+        for status in statuses {
+            for placement in placements {
+                let response = TransferResponse {
+                    status,
+                    placement,
+                    bytes_stored: 42,
+                    detail: "received/data.bin".to_string(),
+                };
+                round_trip(&encode_response(&response), &response, try_decode_response);
+            }
+        }
+    }
+
+    #[test]
+    fn long_response_detail_is_cut_at_a_char_boundary() {
+        let response = TransferResponse {
+            status: TransferStatus::Failed,
+            placement: None,
+            bytes_stored: 0,
+            detail: "é".repeat(u16::MAX as usize),
+        };
+        let (decoded, _) = try_decode_response(&encode_response(&response))
+            .unwrap()
+            .unwrap();
+        assert_eq!(decoded.detail.len(), u16::MAX as usize - 1);
+        assert!(response.detail.starts_with(&decoded.detail));
+    }
+
+    #[test]
+    fn entry_round_trips_with_and_without_optional_fields() {
+        let mut entry = FileEntry {
+            name: "logs/app.log".to_string(),
+            size: 9_000,
+            modified: None,
+            checksum: None,
+        };
+        round_trip(&encode_entry(&entry).unwrap(), &entry, try_decode_entry);
+
+        entry.modified = Some(UNIX_EPOCH + Duration::from_nanos(1_234_567_890));
+        round_trip(&encode_entry(&entry).unwrap(), &entry, try_decode_entry);
+
+        entry.checksum = Some(*blake3::hash(b"contents").as_bytes());
+        round_trip(&encode_entry(&entry).unwrap(), &entry, try_decode_entry);
+    }
+
+    #[test]
+    fn frames_decode_one_after_another() {
+        let first = FileEntry {
+            name: "a".to_string(),
+            size: 1,
+            modified: None,
+            checksum: None,
+        };
+        let second = FileEntry {
+            name: "b/c".to_string(),
+            size: 2,
+            modified: None,
+            checksum: Some([7; CHECKSUM_LEN]),
+        };
+        let mut buf = encode_entry(&first).unwrap();
+        buf.extend(encode_entry(&second).unwrap());
+
+        let (decoded, used) = try_decode_entry(&buf).unwrap().unwrap();
+        assert_eq!(decoded, first);
+        let (decoded, rest) = try_decode_entry(&buf[used..]).unwrap().unwrap();
+        assert_eq!(decoded, second);
+        assert_eq!(used + rest, buf.len());
+    }
+
+    #[test]
+    fn unknown_enum_values_are_rejected() {
+        let mut encoded = encode_header(&header(Request::Upload, 0)).unwrap();
+        encoded[PREAMBLE_LEN] = 9;
+        assert_eq!(
+            try_decode_header(&encoded).unwrap_err(),
+            CodecError::UnknownValue {
+                field: "request type",
+                value: 9
+            }
+        );
+
+        let mut encoded = encode_negotiation(&negotiate(&Preamble {
+            version: PROTOCOL_VERSION,
+            flags: 0,
+        }));
+        encoded[4] = 200;
+        assert!(matches!(
+            try_decode_negotiation(&encoded),
+            Err(CodecError::UnknownValue { value: 200, .. })
+        ));
+
+        let mut encoded = encode_response(&TransferResponse {
+            status: TransferStatus::Ok,
+            placement: None,
+            bytes_stored: 0,
+            detail: String::new(),
+        });
+        encoded[5] = 9;
+        assert!(matches!(
+            try_decode_response(&encoded),
+            Err(CodecError::UnknownValue {
+                field: "placement",
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn truncated_metadata_records_are_rejected() {
+        let mut header = header(Request::Upload, FLAG_METADATA);
+        header.metadata = Some(FileMetadata::default());
+        let mut encoded = encode_header(&header).unwrap();
+        let section = encoded.len() - 4;
+        encoded[section..].copy_from_slice(&3u32.to_le_bytes());
+        encoded.extend_from_slice(&[RECORD_MODE, 4, 0]);
+        assert!(matches!(
+            try_decode_header(&encoded),
+            Err(CodecError::InvalidMetadata(_))
+        ));
+    }
+
+    #[test]
+    fn negotiate_drops_conflicting_flags() {
+        let negotiation = negotiate(&Preamble {
+            version: PROTOCOL_VERSION,
+            flags: FLAG_STREAMING
+                | FLAG_RESUME
+                | FLAG_RANGES
+                | FLAG_DELTA
+                | FLAG_CHECKSUM
+                | FLAG_ZSTD
+                | FLAG_LZ4
+                | 1 << 31,
+        });
+        assert_eq!(negotiation.status, NegotiationStatus::Accepted);
+        assert_eq!(
+            negotiation.flags,
+            FLAG_STREAMING | FLAG_CHECKSUM | FLAG_ZSTD
+        );
+
+        let negotiation = negotiate(&Preamble {
+            version: PROTOCOL_VERSION - 1,
+            flags: 0,
+        });
+        assert_eq!(negotiation.status, NegotiationStatus::UnsupportedVersion);
+        assert_eq!(negotiation.version, PROTOCOL_VERSION);
+    }
+
+    /// A header for `name` encoded by hand, bypassing the checks of
+    /// [`encode_header`].
+    fn raw_header(name: &[u8]) -> Vec<u8> {
+        let mut encoded = encode_header(&FileHeader {
+            file_name: String::new(),
+            ..header(Request::Upload, 0)
+        })
+        .unwrap();
+        encoded[10..12].copy_from_slice(&(name.len() as u16).to_le_bytes());
+        encoded.extend_from_slice(name);
+        encoded
+    }
+
+    #[test]
+    fn bad_magic_is_rejected() {
+        let mut encoded = encode_header(&header(Request::Upload, 0)).unwrap();
+        encoded[0] = b'X';
+        assert_eq!(
+            try_decode_preamble(&encoded).unwrap_err(),
+            CodecError::BadMagic
+        );
+        assert_eq!(
+            try_decode_header(&encoded).unwrap_err(),
+            CodecError::BadMagic
+        );
+        // Even before the whole preamble has arrived.
+        assert_eq!(
+            try_decode_header(&encoded[..PREAMBLE_LEN]).unwrap_err(),
+            CodecError::BadMagic
+        );
+
+        let mut encoded = encode_negotiation(&negotiate(&Preamble {
+            version: PROTOCOL_VERSION,
+            flags: 0,
+        }));
+        encoded[3] = 0;
+        assert_eq!(
+            try_decode_negotiation(&encoded).unwrap_err(),
+            CodecError::BadMagic
+        );
+    }
+
+    #[test]
+    fn other_versions_are_rejected() {
+        let mut encoded = encode_header(&header(Request::Upload, 0)).unwrap();
+        encoded[4] = PROTOCOL_VERSION + 1;
+        let err = try_decode_header(&encoded).unwrap_err();
+        assert_eq!(err, CodecError::UnsupportedVersion(PROTOCOL_VERSION + 1));
+        assert_eq!(err.status(), NegotiationStatus::UnsupportedVersion);
+    }
+
+    #[test]
+    fn names_up_to_the_limit_are_accepted() {
+        let name = "a".repeat(MAX_NAME_LEN);
+        let (decoded, _) = try_decode_header(&raw_header(name.as_bytes()))
+            .unwrap()
+            .unwrap();
+        assert_eq!(decoded.file_name, name);
+    }
+
+    #[test]
+    fn names_over_the_limit_are_rejected() {
+        let name = "a".repeat(MAX_NAME_LEN + 1);
+        let encoded = raw_header(name.as_bytes());
+        let err = try_decode_header(&encoded).unwrap_err();
+        assert_eq!(err, CodecError::NameTooLong(MAX_NAME_LEN + 1));
+        assert_eq!(err.status(), NegotiationStatus::InvalidPath);
+        // The length alone is enough to refuse the header.
+        assert_eq!(
+            try_decode_header(&encoded[..HEADER_PREFIX_LEN]).unwrap_err(),
+            CodecError::NameTooLong(MAX_NAME_LEN + 1)
+        );
+
+        let too_long = FileHeader {
+            file_name: name.clone(),
+            ..header(Request::Upload, 0)
+        };
+        assert!(matches!(
+            encode_header(&too_long),
+            Err(Error::Codec(CodecError::NameTooLong(_)))
+        ));
+        let entry = FileEntry {
+            name,
+            size: 0,
+            modified: None,
+            checksum: None,
+        };
+        assert!(matches!(
+            encode_entry(&entry),
+            Err(Error::Codec(CodecError::NameTooLong(_)))
+        ));
+    }
+
+    #[test]
+    fn names_must_be_utf8() {
+        let err = try_decode_header(&raw_header(b"caf\xe9.txt")).unwrap_err();
+        assert_eq!(err, CodecError::NameNotUtf8);
+        assert_eq!(err.status(), NegotiationStatus::InvalidPath);
+
+        let mut encoded = encode_entry(&FileEntry {
+            name: "cafe".to_string(),
+            size: 0,
+            modified: None,
+            checksum: None,
+        })
+        .unwrap();
+        let last = encoded.len() - 1;
+        encoded[last] = 0xff;
+        assert_eq!(
+            try_decode_entry(&encoded).unwrap_err(),
+            CodecError::NameNotUtf8
+        );
+    }
+
+    #[test]
+    fn control_characters_are_rejected() {
+        for (name, c) in [
+            (&b"a\tb"[..], '\t'),
+            (b"a\nb", '\n'),
+            (b"a\0b", '\0'),
+            (b"a\x7fb", '\x7f'),
+            ("a\u{85}b".as_bytes(), '\u{85}'),
+        ] {
+            let err = try_decode_header(&raw_header(name)).unwrap_err();
+            assert_eq!(err, CodecError::ForbiddenCharacter(c));
+            assert_eq!(err.status(), NegotiationStatus::InvalidPath);
+        }
+        let (decoded, _) = try_decode_header(&raw_header("naïve résumé.txt".as_bytes()))
+            .unwrap()
+            .unwrap();
+        assert_eq!(decoded.file_name, "naïve résumé.txt");
+    }
+
+    fn invalid_reason(path: &str) -> &'static str {
+        match sanitize_relative_path(path) {
+            Err(CodecError::InvalidPath { path: bad, reason }) => {
+                assert_eq!(bad, path);
+                reason
+            }
+            other => panic!("'{path}' gave {other:?}"),
+        }
+    }
+
+    #[test]
+    fn paths_escaping_their_root_are_rejected() {
+        for path in ["..", "../etc/passwd", "a/../../b", "a/b/..", "./.."] {
+            assert_eq!(invalid_reason(path), "escapes its root", "{path}");
+        }
+    }
+
+    #[test]
+    fn absolute_paths_are_rejected() {
+        for path in ["/etc/passwd", "/", "//server/share"] {
+            assert_eq!(invalid_reason(path), "is absolute", "{path}");
+        }
+    }
+
+    #[test]
+    fn paths_with_separators_of_other_systems_are_rejected() {
+        for path in ["a\\b", "..\\..\\windows", "C:\\x", "a\0b"] {
+            assert_eq!(
+                invalid_reason(path),
+                "contains a forbidden character",
+                "{path}"
+            );
+        }
+    }
+
+    #[test]
+    fn paths_of_only_empty_components_are_rejected() {
+        for path in ["", ".", "./", "./.", ".//."] {
+            assert_eq!(invalid_reason(path), "does not name a file", "{path:?}");
+        }
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn file_names_with_separators_are_refused() {
+        assert_eq!(sanitize_file_name("beach.jpg"), "beach.jpg");
+        assert_eq!(sanitize_file_name("..jpg"), "..jpg");
+        for name in [
+            "photos/beach.jpg",
+            "../beach.jpg",
+            "/etc/passwd",
+            "a\\b",
+            "..",
+            ".",
+            "",
+        ] {
+            assert_eq!(sanitize_file_name(name), "received_file", "{name:?}");
+        }
+    }
+
+    #[test]
+    fn empty_and_dot_components_are_dropped() {
+        assert_eq!(
+            sanitize_relative_path("a//b/./c/").unwrap(),
+            Path::new("a").join("b").join("c")
+        );
+        assert_eq!(
+            sanitize_relative_path("./photos/..jpg").unwrap(),
+            Path::new("photos").join("..jpg")
+        );
+    }
 }
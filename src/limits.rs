@@ -0,0 +1,456 @@
+//! Bookkeeping behind [`Limits`]: how many connections and streams are open,
+//! and how many bytes are stored or on their way.
+
+use crate::receiver::Limits;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::sync::{Mutex, MutexGuard};
+
+/// Shared by every connection of a [`crate::receiver::Receiver`].
+pub(crate) struct Tracker {
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    /// Kept with the counts so that replacing them is seen by every check at once.
+    limits: Limits,
+    connections: Counts<IpAddr>,
+    streams: Counts<IpAddr>,
+    /// Total size of the stored files, once it has been counted.
+    stored: Option<u64>,
+    /// Bytes each client has stored since the tracker was created.
+    stored_by_client: HashMap<String, u64>,
+    reserved: Counts<String, u64>,
+}
+
+/// A total and its share per key; keys are dropped once their share is zero.
+struct Counts<K, V = usize> {
+    total: V,
+    by_key: HashMap<K, V>,
+}
+
+impl<K, V: Default> Default for Counts<K, V> {
+    fn default() -> Self {
+        Self {
+            total: V::default(),
+            by_key: HashMap::new(),
+        }
+    }
+}
+
+impl<K: Hash + Eq + Clone> Counts<K, u64> {
+    fn get(&self, key: &K) -> u64 {
+        self.by_key.get(key).copied().unwrap_or(0)
+    }
+
+    fn add(&mut self, key: &K, amount: u64) {
+        self.total += amount;
+        *self.by_key.entry(key.clone()).or_default() += amount;
+    }
+
+    fn remove(&mut self, key: &K, amount: u64) {
+        self.total -= amount;
+        if let Some(count) = self.by_key.get_mut(key) {
+            *count -= amount;
+            if *count == 0 {
+                self.by_key.remove(key);
+            }
+        }
+    }
+}
+
+impl<K: Hash + Eq + Clone> Counts<K> {
+    /// Count one more for `key` unless that would exceed either maximum.
+    fn try_add(&mut self, key: &K, max: Option<usize>, max_per_key: Option<usize>) -> bool {
+        let held = self.by_key.get(key).copied().unwrap_or(0);
+        if max.is_some_and(|max| self.total >= max) || max_per_key.is_some_and(|max| held >= max) {
+            return false;
+        }
+        self.total += 1;
+        *self.by_key.entry(key.clone()).or_default() += 1;
+        true
+    }
+
+    fn remove(&mut self, key: &K) {
+        self.total -= 1;
+        if let Some(count) = self.by_key.get_mut(key) {
+            *count -= 1;
+            if *count == 0 {
+                self.by_key.remove(key);
+            }
+        }
+    }
+}
+
+/// Why an upload cannot be admitted.
+#[derive(Debug)]
+pub(crate) enum Refusal {
+    TooLarge,
+    QuotaExceeded,
+}
+
+impl Tracker {
+    pub(crate) fn new(limits: Limits) -> Self {
+        Self {
+            state: Mutex::new(State {
+                limits,
+                ..State::default()
+            }),
+        }
+    }
+
+    pub(crate) fn limits(&self) -> Limits {
+        self.lock().limits.clone()
+    }
+
+    /// Enforce `limits` from now on. Connections, streams and uploads
+    /// already admitted are kept even if they exceed them.
+    pub(crate) fn set_limits(&self, limits: Limits) {
+        self.lock().limits = limits;
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    /// Count a connection from `ip`, or return `None` if there are too many.
+    pub(crate) fn connect(&self, ip: IpAddr) -> Option<Slot<'_>> {
+        let added = {
+            let mut state = self.lock();
+            let limits = &state.limits;
+            let (max, per_peer) = (limits.max_connections, limits.max_connections_per_peer);
+            state.connections.try_add(&ip, max, per_peer)
+        };
+        added.then(|| Slot {
+            tracker: self,
+            ip,
+            stream: false,
+        })
+    }
+
+    /// Count a stream from `ip`, or return `None` if there are too many.
+    pub(crate) fn open_stream(&self, ip: IpAddr) -> Option<Slot<'_>> {
+        let added = {
+            let mut state = self.lock();
+            let limits = &state.limits;
+            let (max, per_peer) = (limits.max_streams, limits.max_streams_per_peer);
+            state.streams.try_add(&ip, max, per_peer)
+        };
+        added.then(|| Slot {
+            tracker: self,
+            ip,
+            stream: true,
+        })
+    }
+
+    /// Whether the total size of the stored files has to be known and has
+    /// not been counted yet.
+    pub(crate) fn needs_usage(&self) -> bool {
+        let state = self.lock();
+        state.limits.quota.is_some() && state.stored.is_none()
+    }
+
+    /// Record the total size of the stored files, unless it is known already.
+    pub(crate) fn set_usage(&self, stored: u64) {
+        self.lock().stored.get_or_insert(stored);
+    }
+
+    /// Bytes reserved by uploads in progress.
+    pub(crate) fn reserved(&self) -> u64 {
+        self.lock().reserved.total
+    }
+
+    /// Reserve room for `client` to upload `len` bytes of a file of
+    /// `file_size` bytes. The file must not exceed the maximum file size and
+    /// `len` must fit both quotas. Uploads of unknown size reserve nothing
+    /// here and [`Reservation::grow`] as their body arrives.
+    pub(crate) fn reserve(
+        &self,
+        client: &str,
+        file_size: u64,
+        len: u64,
+    ) -> Result<Reservation<'_>, Refusal> {
+        let client = client.to_string();
+        let mut state = self.lock();
+        if state
+            .limits
+            .max_file_size
+            .is_some_and(|max| file_size > max)
+        {
+            return Err(Refusal::TooLarge);
+        }
+        if state.remaining(&client).is_some_and(|left| len > left) {
+            return Err(Refusal::QuotaExceeded);
+        }
+        state.reserved.add(&client, len);
+        Ok(Reservation {
+            tracker: self,
+            client,
+            len,
+        })
+    }
+
+    /// How many more bytes `client` may upload beyond what it has reserved,
+    /// or `None` if that is not limited.
+    pub(crate) fn room_for(&self, client: &str) -> Option<u64> {
+        let state = self.lock();
+        let room = state.remaining(&client.to_string());
+        match (room, state.limits.max_file_size) {
+            (Some(room), Some(max)) => Some(room.min(max)),
+            (room, max) => room.or(max),
+        }
+    }
+
+    /// Count a file of `len` bytes committed by `client` that replaced one
+    /// of `replaced` bytes.
+    pub(crate) fn stored(&self, client: &str, len: u64, replaced: u64) {
+        let mut state = self.lock();
+        if let Some(stored) = state.stored.as_mut() {
+            *stored = (*stored + len).saturating_sub(replaced);
+        }
+        *state
+            .stored_by_client
+            .entry(client.to_string())
+            .or_default() += len;
+    }
+
+    /// Count a removed file of `len` bytes.
+    pub(crate) fn removed(&self, len: u64) {
+        if let Some(stored) = self.lock().stored.as_mut() {
+            *stored = stored.saturating_sub(len);
+        }
+    }
+}
+
+impl State {
+    /// Bytes `client` may still upload under the quotas, or `None` if
+    /// neither applies.
+    fn remaining(&self, client: &String) -> Option<u64> {
+        let total = self.limits.quota.map(|quota| {
+            let used = self.stored.unwrap_or(0) + self.reserved.total;
+            quota.saturating_sub(used)
+        });
+        let own = self.limits.quota_per_client.map(|quota| {
+            let stored = self.stored_by_client.get(client).copied().unwrap_or(0);
+            quota.saturating_sub(stored + self.reserved.get(client))
+        });
+        match (total, own) {
+            (Some(total), Some(own)) => Some(total.min(own)),
+            (total, own) => total.or(own),
+        }
+    }
+}
+
+/// A counted connection or stream, released when dropped.
+pub(crate) struct Slot<'a> {
+    tracker: &'a Tracker,
+    ip: IpAddr,
+    stream: bool,
+}
+
+impl Drop for Slot<'_> {
+    fn drop(&mut self) {
+        let mut state = self.tracker.lock();
+        if self.stream {
+            state.streams.remove(&self.ip);
+        } else {
+            state.connections.remove(&self.ip);
+        }
+    }
+}
+
+/// Room held for an upload in progress, released when dropped.
+pub(crate) struct Reservation<'a> {
+    tracker: &'a Tracker,
+    client: String,
+    len: u64,
+}
+
+impl Reservation<'_> {
+    /// Hold `len` more bytes, as an upload of unknown size does while its
+    /// body arrives, unless that would exceed either quota.
+    pub(crate) fn grow(&mut self, len: u64) -> Result<(), Refusal> {
+        let mut state = self.tracker.lock();
+        if state.remaining(&self.client).is_some_and(|left| len > left) {
+            return Err(Refusal::QuotaExceeded);
+        }
+        state.reserved.add(&self.client, len);
+        self.len += len;
+        Ok(())
+    }
+}
+
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        self.tracker.lock().reserved.remove(&self.client, self.len);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    const A: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+    const B: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2));
+
+    fn quotas(quota: Option<u64>, quota_per_client: Option<u64>) -> Tracker {
+        Tracker::new(Limits {
+            quota,
+            quota_per_client,
+            ..Limits::default()
+        })
+    }
+
+    #[test]
+    fn counts_enforce_both_maximums() {
+        let mut counts = Counts::default();
+        assert!(counts.try_add(&A, Some(3), Some(2)));
+        assert!(counts.try_add(&A, Some(3), Some(2)));
+        assert!(!counts.try_add(&A, Some(3), Some(2)));
+        assert!(counts.try_add(&B, Some(3), Some(2)));
+        assert!(!counts.try_add(&B, Some(3), Some(2)));
+        assert_eq!(counts.total, 3);
+
+        counts.remove(&A);
+        counts.remove(&A);
+        assert!(!counts.by_key.contains_key(&A));
+        assert!(counts.try_add(&B, Some(3), Some(2)));
+        assert!(counts.try_add(&A, None, None));
+        assert_eq!(counts.total, 3);
+    }
+
+    #[test]
+    fn amounts_drop_keys_at_zero() {
+        let mut counts: Counts<String, u64> = Counts::default();
+        let key = "client".to_string();
+        counts.add(&key, 10);
+        counts.add(&key, 5);
+        assert_eq!((counts.total, counts.get(&key)), (15, 15));
+        counts.remove(&key, 15);
+        assert_eq!(counts.total, 0);
+        assert!(counts.by_key.is_empty());
+    }
+
+    #[test]
+    fn slots_are_released_when_dropped() {
+        let tracker = Tracker::new(Limits {
+            max_connections: Some(2),
+            max_connections_per_peer: Some(1),
+            max_streams_per_peer: Some(1),
+            ..Limits::default()
+        });
+        let first = tracker.connect(A).unwrap();
+        assert!(tracker.connect(A).is_none());
+        let second = tracker.connect(B).unwrap();
+        assert!(tracker.connect(B).is_none());
+        drop(first);
+        let third = tracker.connect(A).unwrap();
+
+        // Streams are counted apart from connections.
+        let stream = tracker.open_stream(A).unwrap();
+        assert!(tracker.open_stream(A).is_none());
+        drop(stream);
+        assert!(tracker.open_stream(A).is_some());
+
+        drop((second, third));
+        let state = tracker.lock();
+        assert_eq!((state.connections.total, state.streams.total), (0, 0));
+    }
+
+    #[test]
+    fn reserve_checks_file_size_and_quotas() {
+        let tracker = Tracker::new(Limits {
+            max_file_size: Some(100),
+            quota: Some(150),
+            ..Limits::default()
+        });
+        assert!(matches!(
+            tracker.reserve("a", 101, 10),
+            Err(Refusal::TooLarge)
+        ));
+        let first = tracker.reserve("a", 100, 100).unwrap();
+        assert_eq!(tracker.reserved(), 100);
+        assert!(matches!(
+            tracker.reserve("b", 100, 51),
+            Err(Refusal::QuotaExceeded)
+        ));
+        let second = tracker.reserve("b", 100, 50).unwrap();
+        assert_eq!(tracker.reserved(), 150);
+        drop(first);
+        assert_eq!(tracker.reserved(), 50);
+        drop(second);
+        assert_eq!(tracker.reserved(), 0);
+    }
+
+    #[test]
+    fn remaining_takes_the_smaller_quota() {
+        let tracker = quotas(Some(1000), Some(300));
+        tracker.set_usage(500);
+        tracker.stored("a", 100, 0);
+        let _held = tracker.reserve("a", 50, 50).unwrap();
+        {
+            let state = tracker.lock();
+            // 300 per client less 100 stored and 50 reserved.
+            assert_eq!(state.remaining(&"a".to_string()), Some(150));
+            // 1000 in total less 600 stored and 50 reserved.
+            assert_eq!(state.remaining(&"b".to_string()), Some(300));
+        }
+
+        let tracker = quotas(Some(1000), None);
+        tracker.set_usage(900);
+        assert_eq!(tracker.lock().remaining(&"a".to_string()), Some(100));
+        let tracker = quotas(None, None);
+        assert_eq!(tracker.lock().remaining(&"a".to_string()), None);
+    }
+
+    #[test]
+    fn room_is_capped_by_file_size() {
+        let tracker = Tracker::new(Limits {
+            max_file_size: Some(100),
+            ..Limits::default()
+        });
+        assert_eq!(tracker.room_for("a"), Some(100));
+
+        let tracker = Tracker::new(Limits {
+            max_file_size: Some(100),
+            quota: Some(1000),
+            ..Limits::default()
+        });
+        assert_eq!(tracker.room_for("a"), Some(100));
+        tracker.set_usage(960);
+        assert_eq!(tracker.room_for("a"), Some(40));
+        assert_eq!(quotas(None, None).room_for("a"), None);
+    }
+
+    #[test]
+    fn growing_reservations_share_the_quota() {
+        let tracker = quotas(Some(100), None);
+        let mut first = tracker.reserve("a", 0, 0).unwrap();
+        let mut second = tracker.reserve("b", 0, 0).unwrap();
+        first.grow(60).unwrap();
+        assert!(matches!(second.grow(41), Err(Refusal::QuotaExceeded)));
+        second.grow(40).unwrap();
+        assert_eq!(tracker.reserved(), 100);
+        drop(first);
+        assert_eq!(tracker.reserved(), 40);
+        second.grow(60).unwrap();
+        drop(second);
+        assert_eq!(tracker.reserved(), 0);
+    }
+
+    #[test]
+    fn usage_follows_stored_and_removed_files() {
+        let tracker = quotas(Some(1000), None);
+        assert!(tracker.needs_usage());
+        tracker.set_usage(100);
+        tracker.set_usage(999);
+        assert!(!tracker.needs_usage());
+        tracker.stored("a", 50, 20);
+        tracker.removed(200);
+        assert_eq!(tracker.lock().stored, Some(0));
+        assert!(!quotas(None, Some(10)).needs_usage());
+    }
+}
@@ -1,5 +1,165 @@
-fn main() {
+use anyhow::{Context, Result};
+use clap::{Args, Parser, Subcommand};
+use quic3::certs::{
+    CertificateAuthority, CertificateRole, CertificateSpec, IssuedCertificate, KeyAlgorithm,
+};
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Use `cargo run --bin server` or `cargo run --bin client` to start the QUIC
+/// transfer demo; this binary holds the supporting tools.
+#[derive(Parser, Debug)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Create a private CA and issue server and client certificates from it.
+    Certs {
+        #[command(subcommand)]
+        command: CertsCommand,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum CertsCommand {
+    /// Create a new certificate authority.
+    Ca {
+        /// Where to write the CA certificate.
+        #[arg(long, default_value = "certs/ca.pem")]
+        cert: PathBuf,
+
+        /// Where to write the CA private key.
+        #[arg(long, default_value = "certs/ca-key.pem")]
+        key: PathBuf,
+
+        /// Common name of the CA.
+        #[arg(long, default_value = "quic3 CA")]
+        common_name: String,
+
+        /// Validity period in days.
+        #[arg(long, default_value_t = 3650)]
+        days: u32,
+
+        #[command(flatten)]
+        options: KeyOptions,
+    },
+    /// Issue a server certificate signed by the CA.
+    Server(LeafArgs),
+    /// Issue a client certificate signed by the CA.
+    Client(LeafArgs),
+}
+
+#[derive(Args, Debug)]
+struct LeafArgs {
+    /// CA certificate to sign with.
+    #[arg(long, default_value = "certs/ca.pem")]
+    ca_cert: PathBuf,
+
+    /// CA private key to sign with.
+    #[arg(long, default_value = "certs/ca-key.pem")]
+    ca_key: PathBuf,
+
+    /// Where to write the issued certificate.
+    #[arg(long)]
+    cert: PathBuf,
+
+    /// Where to write the issued private key.
+    #[arg(long)]
+    key: PathBuf,
+
+    /// Common name of the subject; defaults to the first --san.
+    #[arg(long)]
+    common_name: Option<String>,
+
+    /// DNS name or IP address the certificate is valid for; may be repeated.
+    #[arg(long = "san")]
+    subject_alt_names: Vec<String>,
+
+    /// Validity period in days.
+    #[arg(long, default_value_t = 825)]
+    days: u32,
+
+    #[command(flatten)]
+    options: KeyOptions,
+}
+
+#[derive(Args, Debug)]
+struct KeyOptions {
+    /// Key algorithm: ecdsa-p256, ed25519 or rsa.
+    #[arg(long, default_value_t = KeyAlgorithm::EcdsaP256)]
+    algorithm: KeyAlgorithm,
+
+    /// Replace existing certificate and key files.
+    #[arg(long)]
+    force: bool,
+}
+
+fn main() -> Result<()> {
+    let Command::Certs { command } = Cli::parse().command;
+    match command {
+        CertsCommand::Ca {
+            cert,
+            key,
+            common_name,
+            days,
+            options,
+        } => {
+            let ca = CertificateAuthority::generate(&CertificateSpec {
+                common_name,
+                subject_alt_names: Vec::new(),
+                algorithm: options.algorithm,
+                validity_days: days,
+            })?;
+            save(&ca.certificate()?, &cert, &key, options.force)?;
+            println!(
+                "Wrote CA certificate to {} and key to {}",
+                cert.display(),
+                key.display()
+            );
+        }
+        CertsCommand::Server(args) => issue(args, CertificateRole::Server)?,
+        CertsCommand::Client(args) => issue(args, CertificateRole::Client)?,
+    }
+    Ok(())
+}
+
+fn issue(args: LeafArgs, role: CertificateRole) -> Result<()> {
+    let Some(common_name) = args
+        .common_name
+        .or_else(|| args.subject_alt_names.first().cloned())
+    else {
+        anyhow::bail!("pass --common-name or at least one --san");
+    };
+
+    let ca = CertificateAuthority::load(&args.ca_cert, &args.ca_key)?;
+    let issued = ca.issue(
+        &CertificateSpec {
+            common_name: common_name.clone(),
+            subject_alt_names: args.subject_alt_names,
+            algorithm: args.options.algorithm,
+            validity_days: args.days,
+        },
+        role,
+    )?;
+    save(&issued, &args.cert, &args.key, args.options.force)?;
     println!(
-        "Use `cargo run --bin server` or `cargo run --bin client` to start the QUIC transfer demo."
+        "Issued certificate for '{common_name}' to {} and key to {}",
+        args.cert.display(),
+        args.key.display()
     );
+    Ok(())
+}
+
+/// Write `certificate`, suggesting `--force` if an existing file is in the way.
+fn save(certificate: &IssuedCertificate, cert: &Path, key: &Path, force: bool) -> Result<()> {
+    let result = certificate.save(cert, key, force);
+    if let Err(quic3::Error::Io(err)) = &result
+        && err.kind() == io::ErrorKind::AlreadyExists
+    {
+        return result.context("certificate not written; pass --force to replace existing files");
+    }
+    Ok(result?)
 }
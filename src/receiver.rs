@@ -0,0 +1,2345 @@
+//! Receiving side of the protocol: accepts streams, stages their bodies in a
+//! [`StorageSink`] and commits the files that verify.
+
+use crate::compression::Decoder;
+use crate::delta::{self, Instruction};
+use crate::limits::{Refusal, Reservation, Tracker};
+use crate::storage::{LocalStorage, StorageSink};
+use crate::{
+    CHECKSUM_LEN, ClientIdentity, Error, ErrorCode, FLAG_CHECKSUM, FLAG_DELETE, FLAG_DELTA,
+    FLAG_LZ4, FLAG_METADATA, FLAG_OVERWRITE, FLAG_RANGES, FLAG_RESUME, FLAG_STREAMING, FLAG_ZSTD,
+    FileEntry, FileHeader, FileMetadata, MAX_HEADER_LEN, Negotiation, NegotiationStatus, Placement,
+    Request, Result, TRANSFER_ID_LEN, TransferId, TransferResponse, TransferStatus, encode_entry,
+    encode_negotiation, encode_response, export, format_transfer_id, negotiate,
+    sanitize_relative_path, try_decode_entry, try_decode_header, try_decode_preamble,
+};
+use bytes::Bytes;
+use s2n_quic::provider::event::{ConnectionInfo, ConnectionMeta, Subscriber, events};
+use s2n_quic::provider::tls::default::callbacks::VerifyHostNameCallback;
+use s2n_quic::stream::BidirectionalStream;
+use s2n_quic::{Connection, Server};
+use std::collections::{BTreeMap, HashSet};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+use tokio::sync::watch;
+use tokio::time::Instant;
+
+/// The remote end of a connection.
+pub struct Peer {
+    pub addr: SocketAddr,
+    /// Present when the client authenticated with a certificate.
+    pub identity: Option<ClientIdentity>,
+}
+
+impl std::fmt::Display for Peer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.identity {
+            Some(identity) => write!(f, "{} ({identity})", self.addr),
+            None => write!(f, "{}", self.addr),
+        }
+    }
+}
+
+/// Records the certificate identity of each client once its handshake completes.
+///
+/// Install it with `Server::builder().with_event(ClientIdentities)` so
+/// [`Receiver::handle_connection`] can tell who is connecting.
+pub struct ClientIdentities;
+
+impl Subscriber for ClientIdentities {
+    type ConnectionContext = Option<ClientIdentity>;
+
+    fn create_connection_context(
+        &mut self,
+        _meta: &ConnectionMeta,
+        _info: &ConnectionInfo,
+    ) -> Self::ConnectionContext {
+        None
+    }
+
+    fn on_tls_exporter_ready(
+        &mut self,
+        context: &mut Self::ConnectionContext,
+        _meta: &ConnectionMeta,
+        event: &events::TlsExporterReady,
+    ) {
+        if let Ok(chain) = event.session.peer_cert_chain_der()
+            && let Some(leaf) = chain.first()
+        {
+            match ClientIdentity::from_der(leaf) {
+                Ok(identity) => *context = Some(identity),
+                Err(err) => eprintln!("Failed to read client certificate: {err}"),
+            }
+        }
+    }
+}
+
+/// Host name callback for servers requiring client certificates.
+///
+/// Client certificates are authenticated by their chain to the trusted CA;
+/// which names a client may use is decided per identity, not by TLS.
+pub struct AnyClientName;
+
+impl VerifyHostNameCallback for AnyClientName {
+    fn verify_host_name(&self, _host_name: &str) -> bool {
+        true
+    }
+}
+
+/// Settings of a [`Receiver`].
+#[derive(Debug, Clone, Default)]
+pub struct ReceiverOptions {
+    /// Refuse connections whose client did not present a certificate. The
+    /// TLS provider must be configured to request one.
+    pub require_client_identity: bool,
+    /// Directory whose files clients may download. Downloads are refused
+    /// when unset.
+    pub export_dir: Option<PathBuf>,
+    /// Let sync requests remove stored files that are missing from the
+    /// client's manifest. Otherwise the delete flag is not negotiated.
+    pub allow_delete: bool,
+    /// What to do with an upload whose name is already taken.
+    pub on_conflict: ConflictPolicy,
+    /// Which attributes sent with an upload are applied to the stored file.
+    pub preserve: PreserveMetadata,
+    pub limits: Limits,
+    pub timeouts: Timeouts,
+}
+
+/// Resource limits a [`Receiver`] enforces before accepting any data.
+/// Nothing is limited by default.
+///
+/// Requests beyond a limit are refused with a [`NegotiationStatus`] saying
+/// which one, and connections beyond one are closed.
+#[derive(Debug, Clone, Default)]
+pub struct Limits {
+    /// Largest file accepted, in bytes.
+    pub max_file_size: Option<u64>,
+    /// Connections open at once, in total and from one IP address.
+    pub max_connections: Option<usize>,
+    pub max_connections_per_peer: Option<usize>,
+    /// Streams served at once, in total and from one IP address.
+    pub max_streams: Option<usize>,
+    pub max_streams_per_peer: Option<usize>,
+    /// Total size of the stored files and uploads in progress, in bytes.
+    pub quota: Option<u64>,
+    /// Bytes one client may store, counted from when the receiver was
+    /// created. Clients are told apart by certificate identity, or else by
+    /// IP address.
+    pub quota_per_client: Option<u64>,
+    /// Free space the storage must have left once uploads in progress are
+    /// stored, in bytes.
+    pub min_free_space: Option<u64>,
+}
+
+/// How long a [`Receiver`] waits on a client before dropping its stream, so
+/// that peers sending nothing, or a byte at a time, cannot hold on to it.
+#[derive(Debug, Clone, Copy)]
+pub struct Timeouts {
+    /// Time from a stream being opened until its whole header has arrived.
+    pub header: Duration,
+    /// Time without any data while receiving a body or sync manifest.
+    pub idle: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            header: Duration::from_secs(10),
+            idle: Duration::from_secs(60),
+        }
+    }
+}
+
+/// Which [`FileMetadata`] a [`Receiver`] applies to the files it stores.
+/// Only modification times are applied by default.
+///
+/// Set-user-ID and set-group-ID bits are only kept along with the owner, so
+/// a server running as root never grants its own privileges to a client's
+/// executables. Only extended attributes in the `user.` namespace are set.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PreserveMetadata {
+    /// Unix permission bits.
+    pub mode: bool,
+    /// Access times. Modification times sent by clients are always applied,
+    /// so that syncs can tell which stored copies are current.
+    pub times: bool,
+    /// User and group IDs, which usually takes a server running as root.
+    pub owner: bool,
+    pub xattrs: bool,
+}
+
+impl PreserveMetadata {
+    /// The part of `metadata` to apply.
+    fn select(&self, metadata: &FileMetadata) -> FileMetadata {
+        let setid = if self.owner { 0o7777 } else { 0o1777 };
+        FileMetadata {
+            mode: metadata.mode.filter(|_| self.mode).map(|mode| mode & setid),
+            modified: metadata.modified,
+            accessed: metadata.accessed.filter(|_| self.times),
+            owner: metadata.owner.filter(|_| self.owner),
+            xattrs: metadata
+                .xattrs
+                .iter()
+                .filter(|(name, _)| self.xattrs && name.starts_with("user."))
+                .cloned()
+                .collect(),
+        }
+    }
+}
+
+/// What a [`Receiver`] does with an upload whose name is already taken.
+///
+/// The policy is applied when the upload starts. Uploads that set
+/// [`FLAG_OVERWRITE`] replace the stored file under every policy, though
+/// [`ConflictPolicy::Version`] still keeps the old one.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ConflictPolicy {
+    /// Refuse the upload.
+    Reject,
+    /// Replace the stored file.
+    #[default]
+    Overwrite,
+    /// Keep the stored file and store the upload as `name-1.ext`,
+    /// `name-2.ext` and so on.
+    Rename,
+    /// Keep the stored file as `name.ext.~1~`, `name.ext.~2~` and so on, and
+    /// store the upload under its name. Syncs never delete these versions.
+    Version,
+}
+
+/// How far a [`Receiver`] has got in shutting down.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Phase {
+    Running,
+    /// New transfers are refused while those in progress finish.
+    Draining,
+    /// Transfers still in progress are being aborted.
+    Aborting,
+    /// Every transfer has ended; connections are closed.
+    Stopped,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Activity {
+    phase: Phase,
+    /// Transfers past negotiation that have not ended yet.
+    transfers: usize,
+}
+
+/// What happened to the transfers in progress when [`Receiver::shut_down`]
+/// was called.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ShutdownSummary {
+    /// Transfers that ended within the grace period.
+    pub finished: usize,
+    /// Transfers that were cut off, with their partial files removed.
+    pub aborted: usize,
+}
+
+/// Receives files sent by [`crate::sender::Sender`] into a [`StorageSink`].
+///
+/// One receiver is shared by every connection the server accepts.
+pub struct Receiver<S = LocalStorage> {
+    storage: S,
+    options: RwLock<Arc<ReceiverOptions>>,
+    /// Transfer IDs and range starts of resumable uploads currently being received.
+    active: Mutex<HashSet<(TransferId, u64)>>,
+    /// Serializes updates to the range logs of ranged uploads.
+    range_log: tokio::sync::Mutex<()>,
+    limits: Tracker,
+    activity: watch::Sender<Activity>,
+}
+
+impl<S: StorageSink> Receiver<S> {
+    pub fn new(storage: S, options: ReceiverOptions) -> Self {
+        let limits = Tracker::new(options.limits.clone());
+        Self {
+            storage,
+            options: RwLock::new(Arc::new(options)),
+            active: Mutex::new(HashSet::new()),
+            range_log: tokio::sync::Mutex::new(()),
+            limits,
+            activity: watch::Sender::new(Activity {
+                phase: Phase::Running,
+                transfers: 0,
+            }),
+        }
+    }
+
+    pub fn storage(&self) -> &S {
+        &self.storage
+    }
+
+    pub fn options(&self) -> Arc<ReceiverOptions> {
+        let options = self.options.read().unwrap_or_else(|err| err.into_inner());
+        Arc::clone(&options)
+    }
+
+    /// Apply `options` to streams and connections from now on. Transfers
+    /// already in progress keep the settings they were admitted under where
+    /// they have acted on them, and are not cut off by lower limits.
+    pub fn set_options(&self, options: ReceiverOptions) {
+        self.limits.set_limits(options.limits.clone());
+        *self.options.write().unwrap_or_else(|err| err.into_inner()) = Arc::new(options);
+    }
+
+    /// Remove staging left behind by transfers that never completed.
+    ///
+    /// Partial uploads are kept for `partial_ttl` so their clients can still resume them.
+    pub async fn remove_stale_staging_files(&self, partial_ttl: Duration) -> Result<usize> {
+        Ok(self.storage.remove_stale(partial_ttl).await?)
+    }
+
+    /// Accept connections from `server` until it shuts down, handling each on its own task.
+    ///
+    /// To stop gracefully, drop this future to stop accepting connections
+    /// and then call [`Receiver::shut_down`].
+    pub async fn serve(self: Arc<Self>, mut server: Server) {
+        while let Some(connection) = server.accept().await {
+            tokio::spawn(Arc::clone(&self).handle_connection(connection));
+        }
+    }
+
+    /// Refuse new transfers and give those in progress `grace` to finish,
+    /// then abort the rest and close every connection.
+    ///
+    /// Aborted uploads are removed from the storage, even resumable ones, and
+    /// their clients are told the server is shutting down.
+    pub async fn shut_down(&self, grace: Duration) -> ShutdownSummary {
+        let mut activity = self.activity.subscribe();
+        let mut in_progress = 0;
+        self.activity.send_modify(|activity| {
+            activity.phase = Phase::Draining;
+            in_progress = activity.transfers;
+        });
+        if in_progress > 0 {
+            println!(
+                "Waiting up to {} seconds for {in_progress} transfer(s) to finish",
+                grace.as_secs()
+            );
+        }
+        let idle = |activity: &Activity| activity.transfers == 0;
+        let drained = tokio::time::timeout(grace, activity.wait_for(idle))
+            .await
+            .is_ok();
+
+        let mut aborted = 0;
+        if !drained {
+            self.activity.send_modify(|activity| {
+                activity.phase = Phase::Aborting;
+                aborted = activity.transfers;
+            });
+            println!("Aborting {aborted} transfer(s)");
+            let _ = activity.wait_for(idle).await;
+        }
+        self.activity
+            .send_modify(|activity| activity.phase = Phase::Stopped);
+        ShutdownSummary {
+            finished: in_progress - aborted,
+            aborted,
+        }
+    }
+
+    /// Wait until the receiver has got as far as `phase` in shutting down.
+    async fn reached(&self, phase: Phase) {
+        let mut activity = self.activity.subscribe();
+        let _ = activity.wait_for(|activity| activity.phase >= phase).await;
+    }
+
+    /// Run `work` unless transfers start being aborted first.
+    async fn until_aborted<T>(&self, work: impl Future<Output = Result<T>>) -> Result<T> {
+        tokio::select! {
+            result = work => result,
+            () = self.reached(Phase::Aborting) => Err(Error::ShuttingDown),
+        }
+    }
+
+    /// Receive files on every stream the client opens on `connection`.
+    pub async fn handle_connection(self: Arc<Self>, mut connection: Connection) {
+        let addr = match connection.remote_addr() {
+            Ok(addr) => addr,
+            Err(err) => {
+                eprintln!("Failed to read peer address: {err}");
+                return;
+            }
+        };
+        let identity = connection
+            .query_event_context(|identity: &Option<ClientIdentity>| identity.clone())
+            .ok()
+            .flatten();
+        if self.options().require_client_identity && identity.is_none() {
+            eprintln!("Refusing connection from {addr}: no usable client certificate");
+            connection.close(ErrorCode::Unauthenticated.into());
+            return;
+        }
+        let Some(_slot) = self.limits.connect(addr.ip()) else {
+            eprintln!("Refusing connection from {addr}: too many connections");
+            connection.close(ErrorCode::TooManyConnections.into());
+            return;
+        };
+
+        let peer = Arc::new(Peer { addr, identity });
+        println!("Accepted connection from {peer}");
+        loop {
+            let stream = tokio::select! {
+                accepted = connection.accept_bidirectional_stream() => match accepted {
+                    Ok(Some(stream)) => stream,
+                    _ => break,
+                },
+                () = self.reached(Phase::Stopped) => {
+                    connection.close(ErrorCode::ShuttingDown.into());
+                    break;
+                }
+            };
+            let receiver = Arc::clone(&self);
+            let peer = Arc::clone(&peer);
+            tokio::spawn(async move { receiver.handle_stream(stream, &peer).await });
+        }
+    }
+
+    /// Receive one file on `stream`, returning the response sent to the
+    /// client, or `None` if the stream was refused before the body.
+    ///
+    /// Download requests are answered from the export directory, and list
+    /// and stat requests from the storage; these also return `None`, as no
+    /// transfer response follows them.
+    pub async fn handle_stream(
+        &self,
+        mut stream: BidirectionalStream,
+        peer: &Peer,
+    ) -> Option<TransferResponse> {
+        let slot = self.limits.open_stream(peer.addr.ip());
+        let header_timeout = self.options().timeouts.header;
+        let deadline = Instant::now() + header_timeout;
+        let mut buffer = Vec::new();
+        let mut negotiation = None;
+        let mut header: FileHeader;
+        let consumed: usize;
+
+        loop {
+            let Ok(received) = tokio::time::timeout_at(deadline, stream.receive()).await else {
+                eprintln!(
+                    "[{peer}] dropping stream: no complete header within {} seconds",
+                    header_timeout.as_secs()
+                );
+                abort(&mut stream, ErrorCode::Limit);
+                return None;
+            };
+            match received {
+                Ok(Some(data)) => {
+                    buffer.extend_from_slice(&data);
+
+                    if negotiation.is_none() {
+                        let preamble = match try_decode_preamble(&buffer) {
+                            Ok(Some((preamble, _))) => preamble,
+                            Ok(None) => continue,
+                            Err(err) => {
+                                eprintln!("[{peer}] rejecting stream: {err}");
+                                abort(&mut stream, ErrorCode::Codec);
+                                return None;
+                            }
+                        };
+
+                        let mut reply = negotiate(&preamble);
+                        if !self.storage.supports_resume() {
+                            reply.flags &= !(FLAG_RESUME | FLAG_RANGES);
+                        }
+                        if !self.storage.supports_metadata() {
+                            reply.flags &= !FLAG_METADATA;
+                        }
+                        if reply.status != NegotiationStatus::Accepted {
+                            eprintln!(
+                                "[{peer}] rejecting protocol version {} (server speaks {})",
+                                preamble.version, reply.version
+                            );
+                            reject(&mut stream, &reply).await;
+                            return None;
+                        }
+                        if slot.is_none() {
+                            eprintln!("[{peer}] refusing stream: too many streams");
+                            reply.status = NegotiationStatus::Busy;
+                            reject(&mut stream, &reply).await;
+                            return None;
+                        }
+                        negotiation = Some(reply);
+                    }
+
+                    match try_decode_header(&buffer) {
+                        Ok(Some((parsed, used))) => {
+                            header = parsed;
+                            consumed = used;
+                            break;
+                        }
+                        Ok(None) if buffer.len() > MAX_HEADER_LEN => {
+                            eprintln!(
+                                "[{peer}] dropping stream: header exceeds {MAX_HEADER_LEN} bytes"
+                            );
+                            abort(&mut stream, ErrorCode::Limit);
+                            return None;
+                        }
+                        Ok(None) => {}
+                        Err(err) => {
+                            eprintln!("[{peer}] invalid header: {err}");
+                            match negotiation {
+                                Some(mut reply) => {
+                                    reply.status = err.status();
+                                    reject(&mut stream, &reply).await;
+                                }
+                                None => abort(&mut stream, ErrorCode::Codec),
+                            }
+                            return None;
+                        }
+                    }
+                }
+                Ok(None) => {
+                    eprintln!("[{peer}] connection closed before header received");
+                    return None;
+                }
+                Err(err) => {
+                    eprintln!("[{peer}] failed to read stream: {err}");
+                    return None;
+                }
+            }
+        }
+
+        let mut negotiation = negotiation?;
+        let Some(_transfer) = InFlight::begin(&self.activity) else {
+            eprintln!("[{peer}] refusing '{}': shutting down", header.file_name);
+            abort(&mut stream, ErrorCode::ShuttingDown);
+            return None;
+        };
+
+        if header.request == Request::Download {
+            let options = self.options();
+            let export_dir = options.export_dir.as_deref();
+            let download =
+                export::serve_download(&mut stream, export_dir, &header, negotiation, peer);
+            if let Err(err) = self.until_aborted(download).await {
+                eprintln!("[{peer}] failed to send '{}': {err}", header.file_name);
+                abort(&mut stream, err.code());
+            }
+            return None;
+        }
+        if matches!(header.request, Request::List | Request::Stat) {
+            let query = self.answer_query(&mut stream, &header, negotiation, peer);
+            if let Err(err) = self.until_aborted(query).await {
+                eprintln!("[{peer}] failed to answer query: {err}");
+                abort(&mut stream, err.code());
+            }
+            return None;
+        }
+        if header.request == Request::Sync {
+            let sync =
+                self.answer_sync(&mut stream, &header, negotiation, &buffer[consumed..], peer);
+            if let Err(err) = self.until_aborted(sync).await {
+                eprintln!("[{peer}] failed to sync '{}': {err}", header.file_name);
+                abort(&mut stream, err.code());
+            }
+            return None;
+        }
+
+        if let Err(err) = self.storage.validate(&header.file_name).await {
+            eprintln!("[{peer}] rejecting '{}': {err}", header.file_name);
+            negotiation.status = NegotiationStatus::InvalidPath;
+            reject(&mut stream, &negotiation).await;
+            return None;
+        }
+
+        let ranged = negotiation.flags & FLAG_RANGES != 0;
+        let whole_file = header.range_start == 0 && header.range_end == header.file_size;
+        if header.range_start > header.range_end
+            || header.range_end > header.file_size
+            || (!ranged && !whole_file)
+        {
+            eprintln!(
+                "[{peer}] rejecting range {}..{} of '{}' ({} bytes)",
+                header.range_start, header.range_end, header.file_name, header.file_size
+            );
+            negotiation.status = NegotiationStatus::InvalidRange;
+            reject(&mut stream, &negotiation).await;
+            return None;
+        }
+
+        // Partial uploads are kept under an ID derived from the client's, so
+        // guessing or reusing one cannot continue another name or client's upload.
+        header.transfer_id = staging_id(&header, peer.identity.as_ref());
+
+        let client = client_key(peer);
+        let (reservation, room) = match self.admit(&header, negotiation.flags, &client).await {
+            Ok(Ok(admitted)) => admitted,
+            Ok(Err(status)) => {
+                eprintln!(
+                    "[{peer}] refusing '{}' ({} bytes): {status:?}",
+                    header.file_name, header.file_size
+                );
+                negotiation.status = status;
+                reject(&mut stream, &negotiation).await;
+                return None;
+            }
+            Err(err) => {
+                eprintln!("[{peer}] failed to check the limits: {err}");
+                abort(&mut stream, ErrorCode::Storage);
+                return None;
+            }
+        };
+
+        if negotiation.flags & FLAG_METADATA == 0 {
+            header.metadata = None;
+        }
+        let placement = match self.place(&mut header, negotiation.flags).await {
+            Ok(Some(placement)) => placement,
+            Ok(None) => {
+                eprintln!(
+                    "[{peer}] refusing '{}': a file of that name is already stored",
+                    header.file_name
+                );
+                negotiation.status = NegotiationStatus::AlreadyExists;
+                reject(&mut stream, &negotiation).await;
+                return None;
+            }
+            Err(err) => {
+                eprintln!("[{peer}] failed to look up '{}': {err}", header.file_name);
+                abort(&mut stream, ErrorCode::Storage);
+                return None;
+            }
+        };
+
+        // A delta needs a stored copy to apply to, and replaces resuming and
+        // compression.
+        let base = if negotiation.flags & FLAG_DELTA != 0 {
+            match delta::signature(&self.storage, &header.file_name, header.file_size).await {
+                Ok(base) => base,
+                Err(err) => {
+                    eprintln!(
+                        "[{peer}] not offering a delta upload of '{}': {err}",
+                        header.file_name
+                    );
+                    None
+                }
+            }
+        } else {
+            None
+        };
+        if base.is_some() {
+            negotiation.flags &= !(FLAG_RESUME | FLAG_RANGES | FLAG_ZSTD | FLAG_LZ4);
+        } else {
+            negotiation.flags &= !FLAG_DELTA;
+        }
+
+        let _active = if negotiation.flags & (FLAG_RESUME | FLAG_RANGES) != 0 {
+            match ActiveTransfer::claim(&self.active, header.transfer_id, header.range_start) {
+                Some(guard) => Some(guard),
+                None => {
+                    eprintln!(
+                        "[{peer}] transfer {} of '{}' is already in progress",
+                        format_transfer_id(&header.transfer_id),
+                        header.file_name
+                    );
+                    negotiation.status = NegotiationStatus::TransferInProgress;
+                    reject(&mut stream, &negotiation).await;
+                    return None;
+                }
+            }
+        } else {
+            None
+        };
+
+        let mut body = match IncomingBody::open(self, &header, negotiation.flags).await {
+            Ok(body) => body,
+            Err(err) => {
+                eprintln!("[{peer}] failed to stage '{}': {err}", header.file_name);
+                abort(&mut stream, ErrorCode::Storage);
+                return None;
+            }
+        };
+        body.room = room;
+        body.reservation = Some(reservation);
+        if body.written > header.range_start {
+            println!(
+                "[{peer}] resuming '{}' at byte {} of {}",
+                header.file_name, body.written, header.range_end
+            );
+        }
+
+        negotiation.offset = body.written;
+        if let Err(err) = stream
+            .send(Bytes::from(encode_negotiation(&negotiation)))
+            .await
+        {
+            eprintln!("[{peer}] failed to send negotiation reply: {err}");
+            return None;
+        }
+        if let Some((signature, base_len)) = base {
+            let sent = match delta::encode_signature(&signature) {
+                Ok(encoded) => stream.send(Bytes::from(encoded)).await.map_err(Into::into),
+                Err(err) => Err(err),
+            };
+            if let Err(err) = sent {
+                eprintln!("[{peer}] failed to send block signatures: {err}");
+                let _ = self.storage.abort(body.staged, false).await;
+                return None;
+            }
+            body.base_len = Some(base_len);
+        }
+
+        let response = match self
+            .receive_file(
+                &mut stream,
+                body,
+                &header,
+                placement,
+                &buffer[consumed..],
+                peer,
+            )
+            .await
+        {
+            Ok(response) => response,
+            Err(err) => {
+                eprintln!("[{peer}] failed to store file: {err}");
+                // The client may still be sending the body; stop it with the
+                // reason, but still tell it what went wrong if it is listening.
+                let _ = stream.stop_sending(err.code().into());
+                TransferResponse {
+                    status: TransferStatus::Failed,
+                    placement: None,
+                    bytes_stored: 0,
+                    detail: err.to_string(),
+                }
+            }
+        };
+
+        let encoded = Bytes::from(encode_response(&response));
+        if let Err(err) = stream.send(encoded).await {
+            eprintln!("[{peer}] failed to send transfer response: {err}");
+            return None;
+        }
+        let _ = stream.finish();
+        Some(response)
+    }
+}
+
+/// Send a negotiation reply refusing the transfer and close our half of the stream.
+pub(crate) async fn reject(stream: &mut BidirectionalStream, negotiation: &Negotiation) {
+    let reply = Bytes::from(encode_negotiation(negotiation));
+    if stream.send(reply).await.is_ok() {
+        let _ = stream.close().await;
+    }
+}
+
+/// Reset both directions of `stream` with `code`, so the client learns why
+/// it was dropped.
+pub(crate) fn abort(stream: &mut BidirectionalStream, code: ErrorCode) {
+    let _ = stream.stop_sending(code.into());
+    let _ = stream.reset(code.into());
+}
+
+impl<S: StorageSink> Receiver<S> {
+    async fn receive_file(
+        &self,
+        stream: &mut BidirectionalStream,
+        mut body: IncomingBody<'_, S>,
+        header: &FileHeader,
+        placement: Placement,
+        initial: &[u8],
+        peer: &Peer,
+    ) -> Result<TransferResponse> {
+        let idle = self.options().timeouts.idle;
+        let receiving = async {
+            match body.base_len {
+                Some(base_len) => {
+                    let name = &header.file_name;
+                    receive_delta(stream, &mut body, name, base_len, initial, idle).await
+                }
+                None => receive_body(stream, &mut body, initial, idle).await,
+            }
+        };
+        if let Err(err) = self.until_aborted(receiving).await {
+            if matches!(err, Error::ShuttingDown) {
+                body.resumable = false;
+            }
+            if body.resumable {
+                eprintln!(
+                    "[{peer}] keeping {} bytes of '{}' to resume later",
+                    body.written - header.range_start,
+                    header.file_name
+                );
+            }
+            let resumable = body.resumable;
+            let _ = self.storage.abort(body.staged, resumable).await;
+            return Err(err);
+        }
+
+        let status = body.verify();
+        let ranged = body.ranged;
+        let stored = body.written;
+        let staged = body.staged;
+        let detail = match status {
+            TransferStatus::Ok if ranged => {
+                // Recording the range and committing happen under one lock so that
+                // exactly one of the streams sees the file become complete.
+                let _guard = self.range_log.lock().await;
+                if !self.record_range(header).await? {
+                    drop(staged);
+                    return Ok(TransferResponse {
+                        status: TransferStatus::RangeStored,
+                        placement: None,
+                        bytes_stored: header.range_end - header.range_start,
+                        detail: format!(
+                            "range {}..{} of '{}' staged",
+                            header.range_start, header.range_end, header.file_name
+                        ),
+                    });
+                }
+                let size = header.file_size;
+                let (location, placement) =
+                    self.commit(staged, header, placement, size, peer).await?;
+                return Ok(committed(
+                    header,
+                    header.file_size,
+                    &location,
+                    placement,
+                    peer,
+                ));
+            }
+            TransferStatus::Ok => {
+                let (location, placement) =
+                    self.commit(staged, header, placement, stored, peer).await?;
+                return Ok(committed(header, stored, &location, placement, peer));
+            }
+            TransferStatus::SizeMismatch if header.flags & FLAG_STREAMING != 0 => {
+                "stream ended before the checksum".to_string()
+            }
+            TransferStatus::SizeMismatch => format!(
+                "body does not match the declared size of {} bytes",
+                header.range_end - header.range_start
+            ),
+            TransferStatus::ChecksumMismatch => "checksum mismatch".to_string(),
+            TransferStatus::Failed | TransferStatus::RangeStored => {
+                "failed to store file".to_string()
+            }
+        };
+
+        eprintln!("[{peer}] discarding '{}': {detail}", header.file_name);
+        // Other ranges may still be valid, so a ranged upload keeps its staging.
+        self.storage.abort(staged, ranged).await?;
+        Ok(TransferResponse {
+            status,
+            placement: None,
+            bytes_stored: 0,
+            detail,
+        })
+    }
+
+    /// Decide how an upload relates to a file already stored under its name,
+    /// moving `header` to a free name under [`ConflictPolicy::Rename`].
+    /// Returns `None` if the upload must be refused.
+    async fn place(&self, header: &mut FileHeader, flags: u32) -> Result<Option<Placement>> {
+        if self.storage.stat(&header.file_name, false).await?.is_none() {
+            return Ok(Some(Placement::Created));
+        }
+        let placement = match self.options().on_conflict {
+            ConflictPolicy::Version => Placement::Versioned,
+            _ if flags & FLAG_OVERWRITE != 0 => Placement::Overwritten,
+            ConflictPolicy::Overwrite => Placement::Overwritten,
+            ConflictPolicy::Reject => return Ok(None),
+            ConflictPolicy::Rename => {
+                let (stem, extension) = split_extension(&header.file_name);
+                header.file_name = self.free_name(|n| format!("{stem}-{n}{extension}")).await?;
+                Placement::Renamed
+            }
+        };
+        Ok(Some(placement))
+    }
+
+    /// Publish `staged` under the header's name and apply the metadata the
+    /// server preserves. Under [`ConflictPolicy::Version`] a file stored
+    /// there in the meantime is kept as an older version first.
+    async fn commit(
+        &self,
+        staged: S::Staged,
+        header: &FileHeader,
+        placement: Placement,
+        size: u64,
+        peer: &Peer,
+    ) -> Result<(String, Placement)> {
+        let name = &header.file_name;
+        let options = self.options();
+        let replaced =
+            if options.limits.quota.is_none() || options.on_conflict == ConflictPolicy::Version {
+                0
+            } else {
+                let stored = self.storage.stat(name, false).await?;
+                stored.map_or(0, |entry| entry.size)
+            };
+        let placement = if options.on_conflict != ConflictPolicy::Version {
+            placement
+        } else if self.storage.stat(name, false).await?.is_some() {
+            let version = self.free_name(|n| format!("{name}.~{n}~")).await?;
+            self.storage.rename(name, &version).await?;
+            Placement::Versioned
+        } else {
+            Placement::Created
+        };
+        let location = self.storage.commit(staged, name).await?;
+        self.limits.stored(&client_key(peer), size, replaced);
+
+        // The file is stored either way, so metadata failures are only logged.
+        if let Some(metadata) = &header.metadata {
+            let selected = self.options().preserve.select(metadata);
+            if let Err(err) = self.storage.set_metadata(name, &selected).await {
+                eprintln!("[{peer}] stored '{name}' without all of its metadata: {err}");
+            }
+        }
+        Ok((location, placement))
+    }
+
+    /// Check an upload against the limits before it is accepted, reserving
+    /// room for its body. For uploads of unknown size, whose reservation
+    /// grows with the body, also return how many bytes they may grow to.
+    async fn admit(
+        &self,
+        header: &FileHeader,
+        flags: u32,
+        client: &str,
+    ) -> Result<std::result::Result<(Reservation<'_>, Option<u64>), NegotiationStatus>> {
+        let limits = self.limits.limits();
+        if self.limits.needs_usage() {
+            let stored = self.storage.list("", false).await?;
+            self.limits
+                .set_usage(stored.iter().map(|entry| entry.size).sum());
+        }
+        // The body of an upload of unknown size runs until the end of the
+        // stream, whatever range it declares; its reservation grows instead.
+        let streaming = flags & FLAG_STREAMING != 0;
+        let len = if streaming {
+            0
+        } else {
+            header.range_end - header.range_start
+        };
+        let reservation = match self.limits.reserve(client, header.file_size, len) {
+            Ok(reservation) => reservation,
+            Err(Refusal::TooLarge) => return Ok(Err(NegotiationStatus::TooLarge)),
+            Err(Refusal::QuotaExceeded) => return Ok(Err(NegotiationStatus::QuotaExceeded)),
+        };
+
+        let mut room = self.limits.room_for(client);
+        if let Some(min_free) = limits.min_free_space
+            && let Some(free) = self.storage.free_space().await?
+        {
+            let spare = free.saturating_sub(min_free);
+            let reserved = self.limits.reserved();
+            if spare < reserved {
+                return Ok(Err(NegotiationStatus::InsufficientStorage));
+            }
+            let left = spare - reserved;
+            room = Some(room.map_or(left, |room| room.min(left)));
+        }
+        Ok(Ok((reservation, room.filter(|_| streaming))))
+    }
+
+    /// The first name produced by `candidate` for 1, 2, ... that is not stored.
+    async fn free_name(&self, candidate: impl Fn(u64) -> String) -> Result<String> {
+        for n in 1.. {
+            let name = candidate(n);
+            if self.storage.stat(&name, false).await?.is_none() {
+                return Ok(name);
+            }
+        }
+        unreachable!("every numbered name is taken")
+    }
+
+    /// Answer a list or stat request with one entry frame per stored file.
+    async fn answer_query(
+        &self,
+        stream: &mut BidirectionalStream,
+        header: &FileHeader,
+        mut negotiation: Negotiation,
+        peer: &Peer,
+    ) -> Result<()> {
+        negotiation.flags &= FLAG_CHECKSUM;
+        let checksum = negotiation.flags & FLAG_CHECKSUM != 0;
+        let name = &header.file_name;
+        let entries = if header.request == Request::Stat {
+            self.storage
+                .stat(name, checksum)
+                .await
+                .map(|entry| entry.into_iter().collect())
+        } else {
+            self.storage.list(name, checksum).await
+        };
+        let entries: Vec<_> = match entries {
+            Ok(entries) => entries,
+            Err(err) => {
+                eprintln!("[{peer}] refusing to describe '{name}': {err}");
+                negotiation.status = NegotiationStatus::InvalidPath;
+                reject(stream, &negotiation).await;
+                return Ok(());
+            }
+        };
+        if header.request == Request::Stat && entries.is_empty() {
+            negotiation.status = NegotiationStatus::NotFound;
+            reject(stream, &negotiation).await;
+            return Ok(());
+        }
+
+        stream
+            .send(Bytes::from(encode_negotiation(&negotiation)))
+            .await?;
+        for entry in &entries {
+            stream.send(Bytes::from(encode_entry(entry)?)).await?;
+        }
+        stream.finish()?;
+        if header.request == Request::List {
+            println!("[{peer}] listed {} file(s) under '{name}'", entries.len());
+        }
+        Ok(())
+    }
+
+    /// Answer a sync request: read the client's manifest, reply with the
+    /// entries that have to be sent and remove stored files the client no
+    /// longer has if asked to.
+    async fn answer_sync(
+        &self,
+        stream: &mut BidirectionalStream,
+        header: &FileHeader,
+        mut negotiation: Negotiation,
+        received: &[u8],
+        peer: &Peer,
+    ) -> Result<()> {
+        negotiation.flags &= FLAG_CHECKSUM | FLAG_DELETE;
+        if !self.options().allow_delete {
+            negotiation.flags &= !FLAG_DELETE;
+        }
+        let prefix = &header.file_name;
+
+        let mut buffer = received.to_vec();
+        let mut manifest = Vec::new();
+        loop {
+            if let Some((entry, used)) = try_decode_entry(&buffer)? {
+                buffer.drain(..used);
+                manifest.push(entry);
+                continue;
+            }
+            match receive_within(stream, self.options().timeouts.idle).await? {
+                Some(data) => buffer.extend_from_slice(&data),
+                None if buffer.is_empty() => break,
+                None => {
+                    return Err(Error::Protocol(
+                        "client closed the stream in the middle of an entry".into(),
+                    ));
+                }
+            }
+        }
+
+        let (manifest, stored) = match self.sync_state(prefix, manifest).await {
+            Ok(state) => state,
+            Err(err) => {
+                eprintln!("[{peer}] refusing to sync '{prefix}': {err}");
+                negotiation.status = NegotiationStatus::InvalidPath;
+                reject(stream, &negotiation).await;
+                return Ok(());
+            }
+        };
+
+        let compare_checksums = negotiation.flags & FLAG_CHECKSUM != 0;
+        let keeps_times = self.storage.supports_metadata();
+        let mut wanted = Vec::new();
+        for entry in &manifest {
+            let Some(held) = stored.get(&entry.name) else {
+                wanted.push(FileEntry {
+                    checksum: None,
+                    ..entry.clone()
+                });
+                continue;
+            };
+            let held_time = held.modified.filter(|_| keeps_times);
+            let checksum = if held.size != entry.size {
+                None
+            } else if compare_checksums && entry.checksum.is_some() {
+                let current = self.storage.stat(&entry.name, true).await?;
+                if current.and_then(|current| current.checksum) == entry.checksum {
+                    continue;
+                }
+                None
+            } else if let (Some(local), Some(held)) = (entry.modified, held_time) {
+                if same_time(local, held) {
+                    continue;
+                }
+                None
+            } else {
+                // Without a modification time kept from the client, let it
+                // compare the stored copy's digest with its own.
+                let current = self.storage.stat(&entry.name, true).await?;
+                current.and_then(|current| current.checksum)
+            };
+            wanted.push(FileEntry {
+                checksum,
+                ..entry.clone()
+            });
+        }
+
+        let mut removed = Vec::new();
+        if negotiation.flags & FLAG_DELETE != 0 {
+            let listed: HashSet<&str> = manifest.iter().map(|entry| entry.name.as_str()).collect();
+            for held in stored.values() {
+                if !listed.contains(held.name.as_str()) && !is_version(&held.name) {
+                    self.storage.remove(&held.name).await?;
+                    self.limits.removed(held.size);
+                    println!("[{peer}] removed '{}'", held.name);
+                    removed.push(held);
+                }
+            }
+        }
+
+        stream
+            .send(Bytes::from(encode_negotiation(&negotiation)))
+            .await?;
+        for entry in wanted.iter().chain(removed.iter().copied()) {
+            stream.send(Bytes::from(encode_entry(entry)?)).await?;
+        }
+        stream.finish()?;
+        println!(
+            "[{peer}] synced '{prefix}': {} of {} file(s) to send, {} removed",
+            wanted.len(),
+            manifest.len(),
+            removed.len()
+        );
+        Ok(())
+    }
+
+    /// Normalize the names of a sync manifest, which must all lie within
+    /// `prefix`, and collect the stored files it is compared against.
+    async fn sync_state(
+        &self,
+        prefix: &str,
+        manifest: Vec<FileEntry>,
+    ) -> Result<(Vec<FileEntry>, BTreeMap<String, FileEntry>)> {
+        let prefix = if prefix.is_empty() {
+            String::new()
+        } else {
+            normalize_name(prefix)?
+        };
+        let mut seen = HashSet::new();
+        let mut normalized = Vec::with_capacity(manifest.len());
+        for mut entry in manifest {
+            entry.name = normalize_name(&entry.name)?;
+            let inside = prefix.is_empty()
+                || entry.name == prefix
+                || entry
+                    .name
+                    .strip_prefix(&prefix)
+                    .is_some_and(|rest| rest.starts_with('/'));
+            if !inside {
+                return Err(Error::Protocol(format!(
+                    "'{}' is outside of '{prefix}'",
+                    entry.name
+                )));
+            }
+            if !seen.insert(entry.name.clone()) {
+                return Err(Error::Protocol(format!("'{}' is listed twice", entry.name)));
+            }
+            normalized.push(entry);
+        }
+
+        // A manifest naming the prefix itself syncs a single file.
+        let mut stored = self.storage.list(&prefix, false).await?;
+        if normalized.iter().any(|entry| entry.name == prefix)
+            && let Some(file) = self.storage.stat(&prefix, false).await?
+        {
+            stored.push(file);
+        }
+        let stored = stored
+            .into_iter()
+            .map(|entry| (entry.name.clone(), entry))
+            .collect();
+        Ok((normalized, stored))
+    }
+
+    /// Persist that the header's range is verified and report whether the ranges
+    /// recorded so far cover the whole file.
+    async fn record_range(&self, header: &FileHeader) -> Result<bool> {
+        let range = (header.range_start, header.range_end);
+        let mut ranges = self.storage.completed_ranges(&header.transfer_id).await?;
+        if !ranges.contains(&range) {
+            self.storage
+                .record_range(&header.transfer_id, range)
+                .await?;
+            ranges.push(range);
+        }
+
+        ranges.sort_unstable();
+        let mut covered = 0;
+        for (start, end) in ranges {
+            if start > covered {
+                break;
+            }
+            covered = covered.max(end);
+        }
+        Ok(covered >= header.file_size)
+    }
+}
+
+/// The canonical `/`-separated form of a peer-supplied name.
+fn normalize_name(name: &str) -> Result<String> {
+    Ok(sanitize_relative_path(name)?
+        .to_string_lossy()
+        .replace('\\', "/"))
+}
+
+/// Split the last `.ext` off a name, unless the file name is all extension
+/// like `.bashrc`.
+fn split_extension(name: &str) -> (&str, &str) {
+    let file_start = name.rfind('/').map_or(0, |slash| slash + 1);
+    match name[file_start..].rfind('.') {
+        Some(dot) if dot > 0 => name.split_at(file_start + dot),
+        _ => (name, ""),
+    }
+}
+
+/// Whether a client's modification time `local` matches `held`, the time a
+/// stored copy kept from it. Filesystems that store coarser times round
+/// them, so `held` only has to match as precisely as it was stored: to the
+/// last non-zero decimal digit of its fraction, or within two seconds, the
+/// granularity of FAT, if it has none.
+fn same_time(local: SystemTime, held: SystemTime) -> bool {
+    let difference = local
+        .duration_since(held)
+        .or_else(|_| held.duration_since(local))
+        .unwrap_or_default();
+    let nanos = held
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .subsec_nanos();
+    let granularity = if nanos == 0 {
+        Duration::from_secs(2)
+    } else {
+        let mut step = 1;
+        while nanos.is_multiple_of(step * 10) {
+            step *= 10;
+        }
+        Duration::from_nanos(step.into())
+    };
+    difference < granularity
+}
+
+/// Whether `name` is an older version kept under [`ConflictPolicy::Version`].
+fn is_version(name: &str) -> bool {
+    name.strip_suffix('~')
+        .and_then(|rest| rest.rsplit_once(".~"))
+        .is_some_and(|(_, n)| !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit()))
+}
+
+/// Who quotas are counted against: the certificate identity of the client,
+/// or else its IP address.
+fn client_key(peer: &Peer) -> String {
+    match &peer.identity {
+        Some(identity) => identity.to_string(),
+        None => peer.addr.ip().to_string(),
+    }
+}
+
+/// ID a resumable upload is staged under: the client's transfer ID bound to
+/// the requested name and, with mutual TLS, to the client's certificate.
+fn staging_id(header: &FileHeader, identity: Option<&ClientIdentity>) -> TransferId {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(&header.transfer_id);
+    hasher.update(&(header.file_name.len() as u64).to_le_bytes());
+    hasher.update(header.file_name.as_bytes());
+    if let Some(identity) = identity {
+        for part in std::iter::once(&identity.subject).chain(&identity.alt_names) {
+            hasher.update(&(part.len() as u64).to_le_bytes());
+            hasher.update(part.as_bytes());
+        }
+    }
+    let mut id = [0u8; TRANSFER_ID_LEN];
+    id.copy_from_slice(&hasher.finalize().as_bytes()[..TRANSFER_ID_LEN]);
+    id
+}
+
+fn committed(
+    header: &FileHeader,
+    size: u64,
+    location: &str,
+    placement: Placement,
+    peer: &Peer,
+) -> TransferResponse {
+    let note = match placement {
+        Placement::Created => "",
+        Placement::Overwritten => ", replacing the stored file",
+        Placement::Renamed => ", renamed to keep the stored file",
+        Placement::Versioned => ", keeping the stored file as a version",
+    };
+    println!(
+        "[{peer}] received '{}' ({size} bytes) at {location}{note}",
+        header.file_name
+    );
+    TransferResponse {
+        status: TransferStatus::Ok,
+        placement: Some(placement),
+        bytes_stored: size,
+        detail: location.to_string(),
+    }
+}
+
+/// Wait for the next chunk of `stream`, giving up once it has been silent
+/// for `idle`.
+async fn receive_within(stream: &mut BidirectionalStream, idle: Duration) -> Result<Option<Bytes>> {
+    match tokio::time::timeout(idle, stream.receive()).await {
+        Ok(received) => Ok(received?),
+        Err(_) => Err(Error::Limit(format!(
+            "client sent nothing for {} seconds",
+            idle.as_secs()
+        ))),
+    }
+}
+
+/// Stream the rest of the body into `body`, decompressing it if a codec was
+/// negotiated, and make it durable.
+async fn receive_body<S: StorageSink>(
+    stream: &mut BidirectionalStream,
+    body: &mut IncomingBody<'_, S>,
+    initial: &[u8],
+    idle: Duration,
+) -> Result<()> {
+    let mut decoder = body.decoder.take();
+    let mut chunk = Bytes::copy_from_slice(initial);
+    loop {
+        match decoder.as_mut() {
+            Some(decoder) => body.push(&decoder.decode(&chunk)?).await?,
+            None => body.push(&chunk).await?,
+        }
+        match receive_within(stream, idle).await? {
+            Some(next) => chunk = next,
+            None => break,
+        }
+    }
+    if let Some(decoder) = &decoder {
+        decoder.finish()?;
+    }
+    Ok(body.storage.sync(&mut body.staged).await?)
+}
+
+/// Rebuild the file from the delta instructions on the stream, copying the
+/// parts that are unchanged from the `base_len` byte copy stored as `name`.
+async fn receive_delta<S: StorageSink>(
+    stream: &mut BidirectionalStream,
+    body: &mut IncomingBody<'_, S>,
+    name: &str,
+    base_len: u64,
+    initial: &[u8],
+    idle: Duration,
+) -> Result<()> {
+    let expected = body.expected.unwrap_or(0);
+    let mut buffer = initial.to_vec();
+    let mut copied = vec![0u8; 64 * 1024];
+    let mut ended = false;
+    loop {
+        while let Some((instruction, used)) = delta::try_decode_instruction(&buffer)? {
+            buffer.drain(..used);
+            if ended {
+                return Err(Error::Protocol("delta continues after its end".into()));
+            }
+            match instruction {
+                Instruction::Copy { offset, len } => {
+                    if offset.checked_add(len).is_none_or(|end| end > base_len)
+                        || body.written + len > expected
+                    {
+                        return Err(Error::Protocol(format!(
+                            "copy of {len} bytes at {offset} is out of bounds"
+                        )));
+                    }
+                    let mut done = 0;
+                    while done < len {
+                        let want = (len - done).min(copied.len() as u64) as usize;
+                        let bytes_read = body
+                            .storage
+                            .read_committed(name, offset + done, &mut copied[..want])
+                            .await?;
+                        if bytes_read == 0 {
+                            return Err(Error::Storage(anyhow::anyhow!(
+                                "stored copy of '{name}' changed during the upload"
+                            )));
+                        }
+                        body.write(&copied[..bytes_read]).await?;
+                        done += bytes_read as u64;
+                    }
+                }
+                Instruction::Literal(data) => {
+                    if body.written + data.len() as u64 > expected {
+                        return Err(Error::Protocol(
+                            "delta is longer than the declared size".into(),
+                        ));
+                    }
+                    body.write(&data).await?;
+                }
+                Instruction::End(checksum) => {
+                    body.trailer = checksum.to_vec();
+                    ended = true;
+                }
+            }
+        }
+        match receive_within(stream, idle).await? {
+            Some(chunk) => buffer.extend_from_slice(&chunk),
+            None if buffer.is_empty() => break,
+            None => {
+                return Err(Error::Protocol(
+                    "stream ended in the middle of a delta instruction".into(),
+                ));
+            }
+        }
+    }
+    Ok(body.storage.sync(&mut body.staged).await?)
+}
+
+/// Marks a range of a resumable transfer as in progress for as long as it is alive.
+struct ActiveTransfer<'a> {
+    active: &'a Mutex<HashSet<(TransferId, u64)>>,
+    key: (TransferId, u64),
+}
+
+impl<'a> ActiveTransfer<'a> {
+    fn claim(
+        active: &'a Mutex<HashSet<(TransferId, u64)>>,
+        transfer_id: TransferId,
+        range_start: u64,
+    ) -> Option<Self> {
+        let key = (transfer_id, range_start);
+        if !active
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .insert(key)
+        {
+            return None;
+        }
+        Some(Self { active, key })
+    }
+}
+
+impl Drop for ActiveTransfer<'_> {
+    fn drop(&mut self) {
+        let mut active = self.active.lock().unwrap_or_else(|err| err.into_inner());
+        active.remove(&self.key);
+    }
+}
+
+/// Counts a transfer as in progress for as long as it is alive.
+struct InFlight<'a>(&'a watch::Sender<Activity>);
+
+impl<'a> InFlight<'a> {
+    /// `None` once the receiver has started shutting down.
+    fn begin(activity: &'a watch::Sender<Activity>) -> Option<Self> {
+        let began = activity.send_if_modified(|activity| {
+            let running = activity.phase == Phase::Running;
+            if running {
+                activity.transfers += 1;
+            }
+            running
+        });
+        began.then(|| Self(activity))
+    }
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.0.send_modify(|activity| activity.transfers -= 1);
+    }
+}
+
+/// File body being streamed into a sink, followed by an optional checksum trailer.
+struct IncomingBody<'a, S: StorageSink> {
+    storage: &'a S,
+    staged: S::Staged,
+    resumable: bool,
+    ranged: bool,
+    checksum: bool,
+    hasher: blake3::Hasher,
+    /// Where the body ends, or `None` if it runs until the end of the stream.
+    expected: Option<u64>,
+    /// Length of the stored copy a delta body is applied to.
+    base_len: Option<u64>,
+    /// Decompresses the body if the client compresses it.
+    decoder: Option<Decoder>,
+    /// Most bytes an upload of unknown size may have, if limited.
+    room: Option<u64>,
+    /// Room held against the quotas until the upload is over.
+    reservation: Option<Reservation<'a>>,
+    written: u64,
+    trailer: Vec<u8>,
+}
+
+impl<'a, S: StorageSink> IncomingBody<'a, S> {
+    /// Open staging for `header`, picking up a partial upload if one exists.
+    ///
+    /// A plain resumable upload continues from what is already staged.
+    /// Ranges of a ranged upload share one staged file and are only skipped
+    /// once they have been verified as a whole, since the staged length says
+    /// nothing about which ranges arrived.
+    async fn open(receiver: &'a Receiver<S>, header: &FileHeader, flags: u32) -> Result<Self> {
+        let storage = &receiver.storage;
+        let ranged = flags & FLAG_RANGES != 0;
+        let streaming = flags & FLAG_STREAMING != 0;
+        let resumable = ranged || flags & FLAG_RESUME != 0;
+        let checksum = flags & FLAG_CHECKSUM != 0;
+        let mut hasher = blake3::Hasher::new();
+
+        let resume = resumable.then_some(&header.transfer_id);
+        let (mut staged, held) = storage.open(&header.file_name, resume).await?;
+        let written = if !resumable {
+            0
+        } else if ranged {
+            let range = (header.range_start, header.range_end);
+            let completed = storage
+                .completed_ranges(&header.transfer_id)
+                .await?
+                .contains(&range);
+            if completed && header.offset >= header.range_end {
+                header.range_end
+            } else {
+                header.range_start
+            }
+        } else {
+            let offset = held.min(header.offset).min(header.file_size);
+            storage.truncate(&mut staged, offset).await?;
+            offset
+        };
+
+        if checksum && written > header.range_start {
+            hash_range(
+                storage,
+                &mut staged,
+                header.range_start,
+                written,
+                &mut hasher,
+            )
+            .await?;
+        }
+
+        Ok(Self {
+            storage,
+            staged,
+            resumable,
+            ranged,
+            checksum,
+            hasher,
+            expected: (!streaming).then_some(header.range_end),
+            base_len: None,
+            decoder: Decoder::for_flags(flags),
+            room: None,
+            reservation: None,
+            written,
+            trailer: Vec::new(),
+        })
+    }
+
+    /// Write the part of `data` that belongs to the body and keep the rest as trailer.
+    async fn push(&mut self, data: &[u8]) -> Result<()> {
+        let Some(expected) = self.expected else {
+            return self.push_streaming(data).await;
+        };
+        let body_len = (expected - self.written).min(data.len() as u64) as usize;
+        let (body, trailer) = data.split_at(body_len);
+
+        self.write(body).await?;
+        // Anything longer than a checksum is invalid, so there is no point buffering it all.
+        let room = (CHECKSUM_LEN + 1).saturating_sub(self.trailer.len());
+        self.trailer
+            .extend_from_slice(&trailer[..trailer.len().min(room)]);
+        Ok(())
+    }
+
+    /// Write `data` of a body that runs until the end of the stream, holding
+    /// back the last bytes seen in case they turn out to be the checksum.
+    async fn push_streaming(&mut self, data: &[u8]) -> Result<()> {
+        let held = if self.checksum { CHECKSUM_LEN } else { 0 };
+        let flush = (self.trailer.len() + data.len()).saturating_sub(held);
+        let from_trailer = flush.min(self.trailer.len());
+        if from_trailer > 0 {
+            let released: Vec<u8> = self.trailer.drain(..from_trailer).collect();
+            self.write(&released).await?;
+        }
+        let (body, trailer) = data.split_at(flush - from_trailer);
+        self.write(body).await?;
+        self.trailer.extend_from_slice(trailer);
+        Ok(())
+    }
+
+    async fn write(&mut self, body: &[u8]) -> Result<()> {
+        if let Some(room) = self.room
+            && self.written + body.len() as u64 > room
+        {
+            return Err(Error::Limit(format!(
+                "upload exceeds the {room} bytes the server has room for"
+            )));
+        }
+        if self.expected.is_none()
+            && let Some(reservation) = &mut self.reservation
+            && reservation.grow(body.len() as u64).is_err()
+        {
+            return Err(Error::Limit("upload exceeds the storage quota".into()));
+        }
+        if !body.is_empty() {
+            self.storage
+                .write_at(&mut self.staged, self.written, body)
+                .await?;
+            self.hasher.update(body);
+            self.written += body.len() as u64;
+        }
+        Ok(())
+    }
+
+    fn verify(&self) -> TransferStatus {
+        let trailer_len = if self.checksum { CHECKSUM_LEN } else { 0 };
+        let complete = self
+            .expected
+            .is_none_or(|expected| self.written == expected);
+        if !complete || self.trailer.len() != trailer_len {
+            return TransferStatus::SizeMismatch;
+        }
+        if self.checksum && self.hasher.finalize().as_bytes()[..] != self.trailer[..] {
+            return TransferStatus::ChecksumMismatch;
+        }
+        TransferStatus::Ok
+    }
+}
+
+/// Feed the bytes already staged between `start` and `end` into `hasher`.
+async fn hash_range<S: StorageSink>(
+    storage: &S,
+    staged: &mut S::Staged,
+    start: u64,
+    end: u64,
+    hasher: &mut blake3::Hasher,
+) -> Result<()> {
+    let mut buffer = vec![0u8; 64 * 1024];
+    let mut offset = start;
+    while offset < end {
+        let want = (end - offset).min(buffer.len() as u64) as usize;
+        let bytes_read = storage.read_at(staged, offset, &mut buffer[..want]).await?;
+        if bytes_read == 0 {
+            return Err(Error::Storage(anyhow::anyhow!(
+                "staged data ends at byte {offset}, expected {end}"
+            )));
+        }
+        hasher.update(&buffer[..bytes_read]);
+        offset += bytes_read as u64;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sender::{SendOptions, Sender, SyncOptions, SyncReport, Upload, next_frame};
+    use crate::source::BytesSource;
+    use crate::storage::{MemoryStorage, ObjectStoreSink};
+    use crate::{PROTOCOL_VERSION, encode_header, try_decode_negotiation, try_decode_response};
+    use s2n_quic::Client;
+    use s2n_quic::client::Connect;
+
+    /// A receiver storing into memory, served on a local port, and a
+    /// connection to it.
+    struct Harness<S: StorageSink = MemoryStorage> {
+        receiver: Arc<Receiver<S>>,
+        client: Client,
+        addr: SocketAddr,
+        connection: Connection,
+    }
+
+    impl Harness {
+        async fn start(options: ReceiverOptions) -> Self {
+            Self::start_with(MemoryStorage::new(), options).await
+        }
+    }
+
+    impl<S: StorageSink> Harness<S> {
+        async fn start_with(storage: S, options: ReceiverOptions) -> Self {
+            let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+            let cert_pem = cert.serialize_pem().unwrap();
+            let key_pem = cert.serialize_private_key_pem();
+            let server = Server::builder()
+                .with_tls((cert_pem.as_str(), key_pem.as_str()))
+                .unwrap()
+                .with_event(ClientIdentities)
+                .unwrap()
+                .with_io("127.0.0.1:0")
+                .unwrap()
+                .start()
+                .unwrap();
+            let addr = server.local_addr().unwrap();
+            let receiver = Arc::new(Receiver::new(storage, options));
+            tokio::spawn(Arc::clone(&receiver).serve(server));
+
+            let client = Client::builder()
+                .with_tls(cert_pem.as_str())
+                .unwrap()
+                .with_io("127.0.0.1:0")
+                .unwrap()
+                .start()
+                .unwrap();
+            let connect = Connect::new(addr).with_server_name("localhost");
+            let connection = client.connect(connect).await.unwrap();
+            Self {
+                receiver,
+                client,
+                addr,
+                connection,
+            }
+        }
+
+        /// A sender of its own connection to the receiver.
+        fn sender(&self) -> Arc<Sender> {
+            let client = self.client.clone();
+            let options = SendOptions::default();
+            Arc::new(Sender::new(client, self.addr, "localhost", options))
+        }
+
+        fn storage(&self) -> &S {
+            self.receiver.storage()
+        }
+
+        /// Open a stream and send `header` on it.
+        async fn open(&mut self, header: &FileHeader) -> BidirectionalStream {
+            let mut stream = self.connection.open_bidirectional_stream().await.unwrap();
+            let encoded = encode_header(header).unwrap();
+            stream.send(Bytes::from(encoded)).await.unwrap();
+            stream
+        }
+
+        /// Wait until the receiver has begun shutting down.
+        async fn draining(&self) {
+            let mut activity = self.receiver.activity.subscribe();
+            let draining = |activity: &Activity| activity.phase >= Phase::Draining;
+            activity.wait_for(draining).await.unwrap();
+        }
+
+        /// Send `header` followed by the part of `body` the server does not
+        /// hold yet and `trailer`, returning the negotiation reply and, if
+        /// the upload was accepted, the server's verdict.
+        async fn send(
+            &mut self,
+            header: &FileHeader,
+            body: &[u8],
+            trailer: &[u8],
+        ) -> (Negotiation, Option<TransferResponse>) {
+            let mut stream = self.open(header).await;
+            let mut buffer = Vec::new();
+            let negotiation = next_frame(&mut stream, &mut buffer, try_decode_negotiation)
+                .await
+                .unwrap();
+            if negotiation.status != NegotiationStatus::Accepted {
+                return (negotiation, None);
+            }
+
+            let skip = (negotiation.offset - header.range_start) as usize;
+            stream
+                .send(Bytes::copy_from_slice(&body[skip..]))
+                .await
+                .unwrap();
+            stream.send(Bytes::copy_from_slice(trailer)).await.unwrap();
+            stream.finish().unwrap();
+            let response = next_frame(&mut stream, &mut buffer, try_decode_response)
+                .await
+                .unwrap();
+            (negotiation, Some(response))
+        }
+
+        /// Upload `data` as `name` in one piece, with its checksum.
+        async fn upload(&mut self, name: &str, data: &[u8], flags: u32) -> TransferResponse {
+            let header = header(name, data.len() as u64, FLAG_CHECKSUM | flags);
+            let checksum = blake3::hash(data);
+            let (negotiation, response) = self.send(&header, data, checksum.as_bytes()).await;
+            assert_eq!(negotiation.status, NegotiationStatus::Accepted);
+            response.unwrap()
+        }
+    }
+
+    fn header(name: &str, file_size: u64, flags: u32) -> FileHeader {
+        let mut transfer_id = [0u8; TRANSFER_ID_LEN];
+        transfer_id.copy_from_slice(&blake3::hash(name.as_bytes()).as_bytes()[..TRANSFER_ID_LEN]);
+        FileHeader {
+            version: PROTOCOL_VERSION,
+            flags,
+            request: Request::Upload,
+            file_name: name.to_string(),
+            file_size,
+            offset: 0,
+            range_start: 0,
+            range_end: file_size,
+            transfer_id,
+            metadata: None,
+        }
+    }
+
+    fn with_policy(on_conflict: ConflictPolicy) -> ReceiverOptions {
+        ReceiverOptions {
+            on_conflict,
+            ..ReceiverOptions::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn stores_a_whole_file() {
+        let mut harness = Harness::start(ReceiverOptions::default()).await;
+        let response = harness.upload("dir/a.txt", b"hello world", 0).await;
+        assert_eq!(response.status, TransferStatus::Ok);
+        assert_eq!(response.placement, Some(Placement::Created));
+        assert_eq!(response.bytes_stored, 11);
+        assert_eq!(harness.storage().file("dir/a.txt").unwrap(), b"hello world");
+    }
+
+    #[tokio::test]
+    async fn assembles_ranges_sent_on_separate_streams() {
+        let mut harness = Harness::start(ReceiverOptions::default()).await;
+        let data: Vec<u8> = (0..1000u32).map(|i| i as u8).collect();
+        let flags = FLAG_CHECKSUM | FLAG_RESUME | FLAG_RANGES;
+        let mut responses = Vec::new();
+        for (start, end) in [(600, 1000), (0, 600)] {
+            let mut header = header("ranged.bin", 1000, flags);
+            header.range_start = start;
+            header.range_end = end;
+            let range = &data[start as usize..end as usize];
+            let checksum = blake3::hash(range);
+            let (_, response) = harness.send(&header, range, checksum.as_bytes()).await;
+            responses.push(response.unwrap());
+        }
+
+        assert_eq!(responses[0].status, TransferStatus::RangeStored);
+        assert_eq!(responses[0].bytes_stored, 400);
+        assert_eq!(responses[1].status, TransferStatus::Ok);
+        assert_eq!(responses[1].bytes_stored, 1000);
+        assert_eq!(harness.storage().file("ranged.bin").unwrap(), data);
+    }
+
+    #[tokio::test]
+    async fn resumes_from_the_staged_bytes() {
+        let mut harness = Harness::start(ReceiverOptions::default()).await;
+        let data = b"the first half, then the second half".to_vec();
+        let mut header = header(
+            "resumed.txt",
+            data.len() as u64,
+            FLAG_CHECKSUM | FLAG_RESUME,
+        );
+        // What an interrupted attempt left behind.
+        let storage = harness.storage();
+        let (mut staged, _) = storage
+            .open("resumed.txt", Some(&staging_id(&header, None)))
+            .await
+            .unwrap();
+        storage.write_at(&mut staged, 0, &data[..15]).await.unwrap();
+        storage.abort(staged, true).await.unwrap();
+
+        header.offset = header.file_size;
+        let checksum = blake3::hash(&data);
+        let (negotiation, response) = harness.send(&header, &data, checksum.as_bytes()).await;
+        assert_eq!(negotiation.offset, 15);
+        assert_eq!(response.unwrap().status, TransferStatus::Ok);
+        assert_eq!(harness.storage().file("resumed.txt").unwrap(), data);
+    }
+
+    #[tokio::test]
+    async fn partials_are_not_resumed_under_another_name() {
+        let mut harness = Harness::start(ReceiverOptions::default()).await;
+        let flags = FLAG_CHECKSUM | FLAG_RESUME;
+        let staged_header = header("secret.txt", 20, flags);
+        let storage = harness.storage();
+        let (mut staged, _) = storage
+            .open("secret.txt", Some(&staging_id(&staged_header, None)))
+            .await
+            .unwrap();
+        storage
+            .write_at(&mut staged, 0, b"private data")
+            .await
+            .unwrap();
+        storage.abort(staged, true).await.unwrap();
+
+        // Same transfer ID, different name: nothing is picked up.
+        let data = b"someone else's file!".to_vec();
+        let mut header = header("other.txt", data.len() as u64, flags);
+        header.transfer_id = staged_header.transfer_id;
+        header.offset = header.file_size;
+        let checksum = blake3::hash(&data);
+        let (negotiation, response) = harness.send(&header, &data, checksum.as_bytes()).await;
+        assert_eq!(negotiation.offset, 0);
+        assert_eq!(response.unwrap().status, TransferStatus::Ok);
+        assert_eq!(harness.storage().file("other.txt").unwrap(), data);
+
+        let identity = |subject: &str| ClientIdentity {
+            subject: subject.into(),
+            alt_names: Vec::new(),
+        };
+        let alice = staging_id(&staged_header, Some(&identity("CN=alice")));
+        assert_ne!(alice, staging_id(&staged_header, Some(&identity("CN=bob"))));
+        assert_ne!(alice, staging_id(&staged_header, None));
+    }
+
+    #[tokio::test]
+    async fn discards_a_file_with_the_wrong_checksum() {
+        let mut harness = Harness::start(ReceiverOptions::default()).await;
+        let header = header("bad.txt", 5, FLAG_CHECKSUM);
+        let checksum = blake3::hash(b"other");
+        let (_, response) = harness.send(&header, b"hello", checksum.as_bytes()).await;
+        let response = response.unwrap();
+        assert_eq!(response.status, TransferStatus::ChecksumMismatch);
+        assert_eq!(response.placement, None);
+        assert_eq!(response.bytes_stored, 0);
+        assert!(harness.storage().names().is_empty());
+    }
+
+    #[tokio::test]
+    async fn discards_a_body_of_the_wrong_size() {
+        let mut harness = Harness::start(ReceiverOptions::default()).await;
+        let header = header("short.txt", 10, FLAG_CHECKSUM);
+        let checksum = blake3::hash(b"hello");
+        let (_, response) = harness.send(&header, b"hello", checksum.as_bytes()).await;
+        assert_eq!(response.unwrap().status, TransferStatus::SizeMismatch);
+        assert!(harness.storage().names().is_empty());
+    }
+
+    #[tokio::test]
+    async fn reject_policy_keeps_the_stored_file() {
+        let mut harness = Harness::start(with_policy(ConflictPolicy::Reject)).await;
+        harness.upload("a.txt", b"old", 0).await;
+        let header = header("a.txt", 3, FLAG_CHECKSUM);
+        let (negotiation, response) = harness.send(&header, b"new", &[]).await;
+        assert_eq!(negotiation.status, NegotiationStatus::AlreadyExists);
+        assert!(response.is_none());
+        assert_eq!(harness.storage().file("a.txt").unwrap(), b"old");
+        let upload = Upload::new("a.txt", BytesSource::new("new"));
+        let err = harness.sender().send(&upload).await.unwrap_err();
+        assert!(matches!(
+            err,
+            Error::Rejected {
+                status: NegotiationStatus::AlreadyExists,
+                ..
+            }
+        ));
+
+        // Asking to overwrite wins over the policy.
+        let response = harness.upload("a.txt", b"new", FLAG_OVERWRITE).await;
+        assert_eq!(response.placement, Some(Placement::Overwritten));
+        assert_eq!(harness.storage().file("a.txt").unwrap(), b"new");
+    }
+
+    #[tokio::test]
+    async fn overwrite_policy_replaces_the_stored_file() {
+        let mut harness = Harness::start(with_policy(ConflictPolicy::Overwrite)).await;
+        harness.upload("a.txt", b"old", 0).await;
+        let response = harness.upload("a.txt", b"new", 0).await;
+        assert_eq!(response.placement, Some(Placement::Overwritten));
+        assert_eq!(harness.storage().names(), ["a.txt"]);
+        assert_eq!(harness.storage().file("a.txt").unwrap(), b"new");
+    }
+
+    #[tokio::test]
+    async fn rename_policy_stores_under_a_free_name() {
+        let mut harness = Harness::start(with_policy(ConflictPolicy::Rename)).await;
+        harness.upload("dir/a.txt", b"old", 0).await;
+        for contents in [&b"second"[..], b"third"] {
+            let response = harness.upload("dir/a.txt", contents, 0).await;
+            assert_eq!(response.placement, Some(Placement::Renamed));
+        }
+        let storage = harness.storage();
+        assert_eq!(storage.names(), ["dir/a-1.txt", "dir/a-2.txt", "dir/a.txt"]);
+        assert_eq!(storage.file("dir/a.txt").unwrap(), b"old");
+        assert_eq!(storage.file("dir/a-2.txt").unwrap(), b"third");
+    }
+
+    #[tokio::test]
+    async fn version_policy_keeps_older_copies() {
+        let mut harness = Harness::start(with_policy(ConflictPolicy::Version)).await;
+        harness.upload("a.txt", b"first", 0).await;
+        harness.upload("a.txt", b"second", 0).await;
+        let response = harness.upload("a.txt", b"third", FLAG_OVERWRITE).await;
+        assert_eq!(response.placement, Some(Placement::Versioned));
+        let storage = harness.storage();
+        assert_eq!(storage.names(), ["a.txt", "a.txt.~1~", "a.txt.~2~"]);
+        assert_eq!(storage.file("a.txt").unwrap(), b"third");
+        assert_eq!(storage.file("a.txt.~1~").unwrap(), b"first");
+        assert_eq!(storage.file("a.txt.~2~").unwrap(), b"second");
+    }
+
+    #[tokio::test]
+    async fn streaming_uploads_are_held_to_the_quota() {
+        let options = ReceiverOptions {
+            limits: Limits {
+                quota: Some(16),
+                ..Limits::default()
+            },
+            ..ReceiverOptions::default()
+        };
+        let mut harness = Harness::start(options).await;
+        let response = harness.upload("small", b"0123456789", FLAG_STREAMING).await;
+        assert_eq!(response.status, TransferStatus::Ok);
+
+        let data = [7u8; 10];
+        let header = header("large", 0, FLAG_CHECKSUM | FLAG_STREAMING);
+        let checksum = blake3::hash(&data);
+        let (_, response) = harness.send(&header, &data, checksum.as_bytes()).await;
+        assert_eq!(response.unwrap().status, TransferStatus::Failed);
+        assert_eq!(harness.storage().names(), ["small"]);
+    }
+
+    async fn sync(harness: &Harness, path: &std::path::Path) -> SyncReport {
+        let options = SyncOptions::default();
+        harness.sender().sync(path, &options, 2).await.unwrap()
+    }
+
+    fn set_modified(path: &std::path::Path, modified: SystemTime) {
+        let file = std::fs::File::options().write(true).open(path).unwrap();
+        file.set_modified(modified).unwrap();
+    }
+
+    #[tokio::test]
+    async fn sync_sends_only_changed_files() {
+        let harness = Harness::start(ReceiverOptions::default()).await;
+        let root = tempfile::tempdir().unwrap();
+        let dir = root.path().join("photos");
+        std::fs::create_dir_all(dir.join("trip")).unwrap();
+        std::fs::write(dir.join("a.jpg"), b"first").unwrap();
+        std::fs::write(dir.join("trip/b.jpg"), b"second").unwrap();
+
+        let report = sync(&harness, &dir).await;
+        assert_eq!((report.sent.len(), report.unchanged), (2, 0));
+        let report = sync(&harness, &dir).await;
+        assert_eq!((report.sent.len(), report.unchanged), (0, 2));
+
+        // A change that keeps the size is found by its modification time.
+        std::fs::write(dir.join("a.jpg"), b"FIRST").unwrap();
+        set_modified(
+            &dir.join("a.jpg"),
+            SystemTime::now() + Duration::from_secs(10),
+        );
+        let report = sync(&harness, &dir).await;
+        assert_eq!((report.sent.len(), report.unchanged), (1, 1));
+        assert_eq!(harness.storage().file("photos/a.jpg").unwrap(), b"FIRST");
+    }
+
+    #[tokio::test]
+    async fn sync_compares_digests_of_copies_without_times() {
+        let mut harness = Harness::start(ReceiverOptions::default()).await;
+        let root = tempfile::tempdir().unwrap();
+        let dir = root.path().join("docs");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("same.txt"), b"unchanged").unwrap();
+        std::fs::write(dir.join("edited.txt"), b"version 2").unwrap();
+        // Stored by plain uploads, which send no modification time.
+        harness.upload("docs/same.txt", b"unchanged", 0).await;
+        harness.upload("docs/edited.txt", b"version 1", 0).await;
+
+        let report = sync(&harness, &dir).await;
+        assert_eq!(report.unchanged, 1);
+        let sent: Vec<_> = report
+            .sent
+            .iter()
+            .map(|(upload, _)| upload.file_name.as_str())
+            .collect();
+        assert_eq!(sent, ["docs/edited.txt"]);
+        assert_eq!(
+            harness.storage().file("docs/edited.txt").unwrap(),
+            b"version 2"
+        );
+        let report = sync(&harness, &dir).await;
+        assert_eq!((report.sent.len(), report.unchanged), (0, 2));
+    }
+
+    #[test]
+    fn times_match_as_precisely_as_they_were_stored() {
+        let at = |secs: u64, nanos: u32| SystemTime::UNIX_EPOCH + Duration::new(secs, nanos);
+        assert!(same_time(at(100, 123_456_789), at(100, 123_456_789)));
+        assert!(!same_time(at(100, 123_456_789), at(100, 123_456_788)));
+        // Microseconds, whole seconds and FAT's two seconds.
+        assert!(same_time(at(100, 123_456_789), at(100, 123_456_000)));
+        assert!(!same_time(at(100, 123_457_789), at(100, 123_456_000)));
+        assert!(same_time(at(100, 999_999_999), at(101, 0)));
+        assert!(same_time(at(99, 500_000_000), at(100, 0)));
+        assert!(!same_time(at(97, 0), at(100, 0)));
+    }
+
+    async fn object_harness() -> Harness<ObjectStoreSink> {
+        let store = Arc::new(object_store::memory::InMemory::new());
+        let sink = ObjectStoreSink::new(store, "uploads".into());
+        Harness::start_with(sink, ReceiverOptions::default()).await
+    }
+
+    #[tokio::test]
+    async fn object_store_keeps_whole_files() {
+        let mut harness = object_harness().await;
+        let response = harness.upload("dir/a.txt", b"hello world", 0).await;
+        assert_eq!(response.status, TransferStatus::Ok);
+        assert_eq!(response.detail, "uploads/dir/a.txt");
+
+        // Larger than a part, so the upload is split.
+        let large: Vec<u8> = (0..9 * 1024 * 1024u32).map(|i| (i % 251) as u8).collect();
+        let response = harness.upload("large.bin", &large, 0).await;
+        assert_eq!(response.status, TransferStatus::Ok);
+        let mut buf = vec![0; large.len()];
+        let mut filled = 0;
+        while filled < buf.len() {
+            let offset = filled as u64;
+            let read = harness
+                .storage()
+                .read_committed("large.bin", offset, &mut buf[filled..]);
+            filled += read.await.unwrap();
+        }
+        assert_eq!(buf, large);
+    }
+
+    #[tokio::test]
+    async fn object_store_lists_and_describes_files() {
+        let mut harness = object_harness().await;
+        harness.upload("dir/a.txt", b"hello", 0).await;
+        harness.upload("dir/sub/b.txt", b"hi", 0).await;
+        harness.upload("other.txt", b"!", 0).await;
+
+        let sender = harness.sender();
+        let entries = sender.list("dir", true).await.unwrap();
+        let names: Vec<_> = entries.iter().map(|entry| entry.name.as_str()).collect();
+        assert_eq!(names, ["dir/a.txt", "dir/sub/b.txt"]);
+        assert_eq!(entries[0].size, 5);
+        assert_eq!(
+            entries[0].checksum,
+            Some(*blake3::hash(b"hello").as_bytes())
+        );
+        assert_eq!(sender.list("", false).await.unwrap().len(), 3);
+
+        let entry = sender.stat("dir/sub/b.txt", false).await.unwrap().unwrap();
+        assert_eq!((entry.size, entry.checksum), (2, None));
+        assert!(entry.modified.is_some());
+        assert!(sender.stat("missing.txt", false).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn object_store_drops_failed_uploads() {
+        let mut harness = object_harness().await;
+        let header = header("bad.txt", 5, FLAG_CHECKSUM);
+        let checksum = blake3::hash(b"other");
+        let (_, response) = harness.send(&header, b"hello", checksum.as_bytes()).await;
+        assert_eq!(response.unwrap().status, TransferStatus::ChecksumMismatch);
+        let header = self::header("short.txt", 10, FLAG_CHECKSUM);
+        let checksum = blake3::hash(b"hello");
+        let (_, response) = harness.send(&header, b"hello", checksum.as_bytes()).await;
+        assert_eq!(response.unwrap().status, TransferStatus::SizeMismatch);
+
+        assert!(harness.storage().list("", false).await.unwrap().is_empty());
+        assert!(
+            harness
+                .storage()
+                .stat("bad.txt", false)
+                .await
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    fn exporting(dir: &std::path::Path) -> ReceiverOptions {
+        ReceiverOptions {
+            export_dir: Some(dir.to_path_buf()),
+            ..ReceiverOptions::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn serves_an_exported_file() {
+        let exported = tempfile::tempdir().unwrap();
+        let local = tempfile::tempdir().unwrap();
+        std::fs::create_dir(exported.path().join("dir")).unwrap();
+        let mut data = vec![0u8; 100_000];
+        blake3::Hasher::new().finalize_xof().fill(&mut data);
+        std::fs::write(exported.path().join("dir/a.bin"), &data).unwrap();
+
+        let harness = Harness::start(exporting(exported.path())).await;
+        let dest = local.path().join("a.bin");
+        let download = harness.sender().get("dir/a.bin", &dest).await.unwrap();
+        assert_eq!(download.path, dest);
+        assert_eq!(download.size, data.len() as u64);
+        assert_eq!(download.received, data.len() as u64);
+        assert_eq!(std::fs::read(&dest).unwrap(), data);
+    }
+
+    #[tokio::test]
+    async fn refuses_downloads_it_cannot_serve() {
+        let exported = tempfile::tempdir().unwrap();
+        let outside = tempfile::tempdir().unwrap();
+        std::fs::write(outside.path().join("secret.txt"), b"secret").unwrap();
+        std::fs::write(exported.path().join("public.txt"), b"public").unwrap();
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(
+            outside.path().join("secret.txt"),
+            exported.path().join("link.txt"),
+        )
+        .unwrap();
+
+        let local = tempfile::tempdir().unwrap();
+        let dest = local.path().join("fetched");
+        let refusal = |name: &'static str, options: ReceiverOptions| {
+            let dest = dest.clone();
+            async move {
+                let harness = Harness::start(options).await;
+                match harness.sender().get(name, &dest).await {
+                    Err(Error::Rejected { status, .. }) => status,
+                    other => panic!("download of '{name}' was not refused: {other:?}"),
+                }
+            }
+        };
+
+        let options = || exporting(exported.path());
+        assert_eq!(
+            refusal("missing.txt", options()).await,
+            NegotiationStatus::NotFound
+        );
+        assert_eq!(
+            refusal("../secret.txt", options()).await,
+            NegotiationStatus::InvalidPath
+        );
+        #[cfg(unix)]
+        assert_eq!(
+            refusal("link.txt", options()).await,
+            NegotiationStatus::InvalidPath
+        );
+        assert_eq!(
+            refusal("public.txt", ReceiverOptions::default()).await,
+            NegotiationStatus::UnsupportedRequest
+        );
+        assert!(!dest.exists());
+    }
+
+    /// Start uploading `data` as `name` and wait until the receiver has
+    /// accepted it, leaving the body to be sent.
+    async fn begin_upload(
+        harness: &mut Harness,
+        name: &str,
+        data: &[u8],
+    ) -> (BidirectionalStream, Vec<u8>) {
+        let mut stream = harness
+            .open(&header(name, data.len() as u64, FLAG_CHECKSUM))
+            .await;
+        let mut buffer = Vec::new();
+        let negotiation = next_frame(&mut stream, &mut buffer, try_decode_negotiation)
+            .await
+            .unwrap();
+        assert_eq!(negotiation.status, NegotiationStatus::Accepted);
+        (stream, buffer)
+    }
+
+    #[tokio::test]
+    async fn shutdown_lets_transfers_in_progress_finish() {
+        let mut harness = Harness::start(ReceiverOptions::default()).await;
+        let data = b"sent before the shutdown began";
+        let (mut stream, mut buffer) = begin_upload(&mut harness, "a.txt", data).await;
+
+        let receiver = Arc::clone(&harness.receiver);
+        let shutdown =
+            tokio::spawn(async move { receiver.shut_down(Duration::from_secs(30)).await });
+        harness.draining().await;
+
+        stream.send(Bytes::from_static(data)).await.unwrap();
+        stream
+            .send(Bytes::copy_from_slice(blake3::hash(data).as_bytes()))
+            .await
+            .unwrap();
+        stream.finish().unwrap();
+        let response = next_frame(&mut stream, &mut buffer, try_decode_response)
+            .await
+            .unwrap();
+        assert_eq!(response.status, TransferStatus::Ok);
+        assert_eq!(harness.storage().file("a.txt").unwrap(), data);
+
+        let summary = shutdown.await.unwrap();
+        assert_eq!(
+            summary,
+            ShutdownSummary {
+                finished: 1,
+                aborted: 0
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn shutdown_refuses_new_streams_while_draining() {
+        let mut harness = Harness::start(ReceiverOptions::default()).await;
+        // Keeps the receiver draining until it is cut off.
+        let (_held, _) = begin_upload(&mut harness, "held.txt", b"never sent").await;
+
+        let receiver = Arc::clone(&harness.receiver);
+        let shutdown =
+            tokio::spawn(async move { receiver.shut_down(Duration::from_secs(30)).await });
+        harness.draining().await;
+
+        let mut stream = harness.open(&header("late.txt", 4, FLAG_CHECKSUM)).await;
+        let err = next_frame(&mut stream, &mut Vec::new(), try_decode_negotiation)
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(err, Error::Aborted(ErrorCode::ShuttingDown)),
+            "{err:?}"
+        );
+        assert!(!shutdown.is_finished());
+        assert!(harness.storage().file("late.txt").is_none());
+        shutdown.abort();
+    }
+
+    #[tokio::test]
+    async fn shutdown_aborts_transfers_after_the_grace_period() {
+        let mut harness = Harness::start(ReceiverOptions::default()).await;
+        let (mut stream, mut buffer) = begin_upload(&mut harness, "slow.txt", b"never sent").await;
+
+        let summary = harness.receiver.shut_down(Duration::from_millis(100)).await;
+        assert_eq!(
+            summary,
+            ShutdownSummary {
+                finished: 0,
+                aborted: 1
+            }
+        );
+
+        // The body is cut off with the abort code, and the reason follows.
+        let response = next_frame(&mut stream, &mut buffer, try_decode_response)
+            .await
+            .unwrap();
+        assert_eq!(response.status, TransferStatus::Failed);
+        assert_eq!(response.detail, Error::ShuttingDown.to_string());
+        let err = Error::from(
+            stream
+                .send(Bytes::from_static(b"never sent"))
+                .await
+                .unwrap_err(),
+        );
+        assert!(
+            matches!(err, Error::Aborted(ErrorCode::ShuttingDown)),
+            "{err:?}"
+        );
+        assert!(harness.storage().names().is_empty());
+    }
+
+    #[tokio::test]
+    async fn object_store_refuses_resume_and_ranges() {
+        let mut harness = object_harness().await;
+        assert!(!harness.storage().supports_resume());
+        let storage = harness.storage();
+        assert!(
+            storage
+                .open("a.txt", Some(&[0; TRANSFER_ID_LEN]))
+                .await
+                .is_err()
+        );
+
+        // Resuming is not negotiated, so the upload starts from the beginning.
+        let mut header = header("a.txt", 5, FLAG_CHECKSUM | FLAG_RESUME);
+        header.offset = 5;
+        let checksum = blake3::hash(b"hello");
+        let (negotiation, response) = harness.send(&header, b"hello", checksum.as_bytes()).await;
+        assert_eq!(negotiation.flags & (FLAG_RESUME | FLAG_RANGES), 0);
+        assert_eq!(negotiation.offset, 0);
+        assert_eq!(response.unwrap().status, TransferStatus::Ok);
+
+        // Nor is uploading part of a file.
+        let mut header = self::header("b.txt", 10, FLAG_CHECKSUM | FLAG_RESUME | FLAG_RANGES);
+        header.range_end = 5;
+        let (negotiation, response) = harness.send(&header, b"hello", &[]).await;
+        assert_eq!(negotiation.status, NegotiationStatus::InvalidRange);
+        assert!(response.is_none());
+    }
+}
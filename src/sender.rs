@@ -0,0 +1,1275 @@
+//! Client side of the protocol: opens streams on a connection, negotiates
+//! with the server and streams ranges of each upload's source with their
+//! checksums, fetches files the server exports, or syncs a directory by
+//! sending only what the server is missing.
+
+use crate::compression::{Compression, Encoder, worth_compressing};
+use crate::delta;
+use crate::source::{FileSource, TransferSource, read_metadata, transfer_id_for};
+use crate::storage::hash_file;
+use crate::{
+    CHECKSUM_LEN, CodecError, Error, FLAG_CHECKSUM, FLAG_DELETE, FLAG_DELTA, FLAG_METADATA,
+    FLAG_OVERWRITE, FLAG_RANGES, FLAG_RESUME, FLAG_STREAMING, FileEntry, FileHeader, FileMetadata,
+    NegotiationStatus, PROTOCOL_VERSION, Request, Result, TRANSFER_ID_LEN, TransferId,
+    TransferResponse, TransferStatus, encode_entry, encode_header, format_transfer_id,
+    try_decode_entry, try_decode_header, try_decode_negotiation, try_decode_response,
+};
+use bytes::Bytes;
+use s2n_quic::Connection;
+use s2n_quic::client::{Client, Connect};
+use s2n_quic::connection::Handle;
+use s2n_quic::stream::BidirectionalStream;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::io::SeekFrom;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::fs::{self, OpenOptions};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+
+/// Upper bound for the delay between two attempts.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+/// Smallest part of a file worth sending on its own stream.
+const MIN_RANGE_LEN: u64 = 1024 * 1024;
+
+/// Smallest file worth offering as a delta against the server's copy.
+const MIN_DELTA_LEN: u64 = 1024 * 1024;
+
+/// How much of a file is test-compressed to decide whether to compress it.
+const COMPRESSION_SAMPLE_LEN: u64 = 128 * 1024;
+
+/// A file to upload.
+#[derive(Clone)]
+pub struct Upload {
+    pub source: Arc<dyn TransferSource>,
+    /// Relative `/`-separated name the server stores the file under.
+    pub file_name: String,
+    pub transfer_id: TransferId,
+    /// Ask the server to replace a file already stored under `file_name`
+    /// whatever its conflict policy; see [`crate::FLAG_OVERWRITE`].
+    pub overwrite: bool,
+    /// Attributes of the local file, sent when [`SendOptions::metadata`] is
+    /// set. [`collect_uploads`] reads them from the file.
+    pub metadata: Option<FileMetadata>,
+}
+
+impl Upload {
+    /// Upload `source` as `file_name` under a fresh transfer ID.
+    ///
+    /// The ID is unique to this upload, so a later upload of the same data
+    /// does not resume it; [`collect_uploads`] derives stable IDs for files.
+    pub fn new(file_name: impl Into<String>, source: impl TransferSource + 'static) -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        let file_name = file_name.into();
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(file_name.as_bytes());
+        hasher.update(&now.as_nanos().to_le_bytes());
+        hasher.update(&std::process::id().to_le_bytes());
+        hasher.update(&NEXT_ID.fetch_add(1, Ordering::Relaxed).to_le_bytes());
+
+        let mut transfer_id = [0u8; TRANSFER_ID_LEN];
+        transfer_id.copy_from_slice(&hasher.finalize().as_bytes()[..TRANSFER_ID_LEN]);
+        Self {
+            source: Arc::new(source),
+            file_name,
+            transfer_id,
+            overwrite: false,
+            metadata: None,
+        }
+    }
+}
+
+impl fmt::Debug for Upload {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Upload")
+            .field("file_name", &self.file_name)
+            .field("size", &self.source.size())
+            .field("transfer_id", &crate::format_transfer_id(&self.transfer_id))
+            .finish()
+    }
+}
+
+/// A file the server confirmed it stored.
+#[derive(Debug)]
+pub struct Delivery {
+    pub sent: u64,
+    /// Bytes the server already held from an earlier attempt.
+    pub skipped: u64,
+    /// Bytes put on the wire for the body, if it was compressed.
+    pub compressed: Option<u64>,
+    pub response: TransferResponse,
+}
+
+/// A file fetched from the server.
+#[derive(Debug)]
+pub struct Download {
+    /// Where the file was written.
+    pub path: PathBuf,
+    pub size: u64,
+    pub received: u64,
+    /// Bytes kept from an earlier attempt.
+    pub skipped: u64,
+}
+
+/// Result of a single attempt that did not fail in a retryable way.
+#[derive(Debug)]
+pub enum Outcome<T = Delivery> {
+    Delivered(T),
+    /// The server refused the request for good; retrying would not help.
+    Rejected {
+        status: NegotiationStatus,
+        reason: String,
+    },
+}
+
+/// A download interrupted by a lost connection, kept so the next attempt
+/// can continue where it stopped.
+struct PartialDownload {
+    path: PathBuf,
+    transfer_id: TransferId,
+}
+
+/// Settings of a [`Sender::sync`].
+#[derive(Debug, Clone, Default)]
+pub struct SyncOptions {
+    /// Compare the checksums of files whose size matches instead of their
+    /// modification times, which reads every such file on both sides.
+    pub checksum: bool,
+    /// Remove stored files below the directory that no longer exist locally.
+    pub delete: bool,
+}
+
+/// What a [`Sender::sync`] did.
+#[derive(Debug)]
+pub struct SyncReport {
+    /// Files the server was missing or held an outdated copy of, with the
+    /// result of sending each.
+    pub sent: Vec<(Upload, Result<Delivery>)>,
+    /// Number of files the server already held.
+    pub unchanged: usize,
+    /// Stored files the server removed because they no longer exist locally.
+    pub removed: Vec<String>,
+}
+
+/// Settings of a [`Sender`].
+#[derive(Debug, Clone)]
+pub struct SendOptions {
+    /// How many times to reconnect and resume after a failed attempt.
+    pub retries: u32,
+    /// Discard any partial upload the server holds and start from byte zero.
+    pub restart: bool,
+    /// Number of concurrent streams a large file is split across.
+    pub streams: usize,
+    /// Send only the changed blocks of files the server already holds a copy
+    /// of. Such files are sent on a single stream and are not resumed.
+    pub delta: bool,
+    /// Compress upload bodies with this codec, except for files that are
+    /// compressed already.
+    pub compression: Option<Compression>,
+    /// Send the permissions, times, owner and extended attributes of each
+    /// upload that has them, for the server to apply as far as it is
+    /// configured to.
+    pub metadata: bool,
+}
+
+impl Default for SendOptions {
+    fn default() -> Self {
+        Self {
+            retries: 5,
+            restart: false,
+            streams: 1,
+            delta: false,
+            compression: None,
+            metadata: false,
+        }
+    }
+}
+
+/// Sends files to one server over a connection shared by every upload and
+/// re-established once it has been lost.
+pub struct Sender {
+    client: Client,
+    server: SocketAddr,
+    server_name: String,
+    options: SendOptions,
+    current: tokio::sync::Mutex<Option<Connection>>,
+}
+
+impl Sender {
+    /// Create a sender for `server`; no connection is made until the first upload.
+    pub fn new(
+        client: Client,
+        server: SocketAddr,
+        server_name: impl Into<String>,
+        options: SendOptions,
+    ) -> Self {
+        Self {
+            client,
+            server,
+            server_name: server_name.into(),
+            options,
+            current: tokio::sync::Mutex::new(None),
+        }
+    }
+
+    /// Handle to the current connection, reconnecting if it has closed.
+    pub async fn connection(&self) -> Result<Handle> {
+        let mut current = self.current.lock().await;
+        if let Some(connection) = current.as_mut()
+            && connection.ping().is_ok()
+        {
+            return Ok(connection.handle());
+        }
+
+        let connect = Connect::new(self.server).with_server_name(self.server_name.clone());
+        let connection = self.client.connect(connect).await?;
+        let handle = connection.handle();
+        *current = Some(connection);
+        Ok(handle)
+    }
+
+    /// Send `upload`, reconnecting and resuming up to `retries` times.
+    ///
+    /// Uploads from one-shot sources such as standard input are attempted once.
+    pub async fn send(&self, upload: &Upload) -> Result<Delivery> {
+        self.send_with(upload, &self.options).await
+    }
+
+    async fn send_with(&self, upload: &Upload, options: &SendOptions) -> Result<Delivery> {
+        let retries = if upload.source.replayable() {
+            options.retries
+        } else {
+            0
+        };
+        let mut attempt = 0;
+        let mut delay = Duration::from_secs(1);
+        let mut restart = options.restart;
+        loop {
+            attempt += 1;
+            let result = match self.connection().await {
+                Ok(mut handle) => send_file(&mut handle, upload, restart, options).await,
+                Err(err) => Err(err),
+            };
+
+            match result {
+                Ok(Outcome::Delivered(delivery)) => {
+                    let mut detail = format!("{} bytes", delivery.sent);
+                    if delivery.skipped > 0 {
+                        detail.push_str(&format!(", {} already on the server", delivery.skipped));
+                    }
+                    if let Some(compressed) = delivery.compressed {
+                        detail.push_str(&format!(", {compressed} compressed"));
+                    }
+                    println!("Sent '{}' ({detail}) to {}", upload.file_name, self.server);
+                    return Ok(delivery);
+                }
+                Ok(Outcome::Rejected { status, reason }) => {
+                    return Err(Error::Rejected {
+                        status,
+                        reason: format!("server rejected the transfer: {reason}"),
+                    });
+                }
+                Err(err) if attempt <= retries => {
+                    eprintln!(
+                        "Attempt {attempt} for '{}' failed: {err}; retrying in {delay:?}",
+                        upload.file_name
+                    );
+                    tokio::time::sleep(delay).await;
+                    delay = (delay * 2).min(MAX_RETRY_DELAY);
+                    restart = false;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    /// Fetch the file the server exports as `name` into `dest`, reconnecting
+    /// and resuming up to `retries` times.
+    pub async fn get(&self, name: &str, dest: &Path) -> Result<Download> {
+        let mut attempt = 0;
+        let mut delay = Duration::from_secs(1);
+        let mut partial = None;
+        let result = loop {
+            attempt += 1;
+            let result = match self.connection().await {
+                Ok(mut handle) => fetch_file(&mut handle, name, dest, &mut partial).await,
+                Err(err) => Err(err),
+            };
+
+            match result {
+                Ok(Outcome::Delivered(download)) => {
+                    if download.skipped > 0 {
+                        println!(
+                            "Fetched '{name}' ({} bytes, {} kept from an earlier attempt) to {}",
+                            download.received,
+                            download.skipped,
+                            download.path.display()
+                        );
+                    } else {
+                        println!(
+                            "Fetched '{name}' ({} bytes) to {}",
+                            download.received,
+                            download.path.display()
+                        );
+                    }
+                    break Ok(download);
+                }
+                Ok(Outcome::Rejected { status, reason }) => {
+                    break Err(Error::Rejected {
+                        status,
+                        reason: format!("server refused the download: {reason}"),
+                    });
+                }
+                Err(err) if attempt <= self.options.retries => {
+                    eprintln!(
+                        "Attempt {attempt} for '{name}' failed: {err}; retrying in {delay:?}"
+                    );
+                    tokio::time::sleep(delay).await;
+                    delay = (delay * 2).min(MAX_RETRY_DELAY);
+                }
+                Err(err) => break Err(err),
+            }
+        };
+
+        if let Some(partial) = partial {
+            let _ = fs::remove_file(&partial.path).await;
+        }
+        result
+    }
+
+    /// Files the server has stored below the directory `prefix`, or all of
+    /// them if it is empty. Checksums are included if `checksum` is set.
+    pub async fn list(&self, prefix: &str, checksum: bool) -> Result<Vec<FileEntry>> {
+        let mut handle = self.connection().await?;
+        let flags = if checksum { FLAG_CHECKSUM } else { 0 };
+        let reply = query(&mut handle, Request::List, prefix, flags, &[]).await?;
+        Ok(reply.map(|(_, entries)| entries).unwrap_or_default())
+    }
+
+    /// The file the server has stored as `name`, or `None` if there is none.
+    pub async fn stat(&self, name: &str, checksum: bool) -> Result<Option<FileEntry>> {
+        let mut handle = self.connection().await?;
+        let flags = if checksum { FLAG_CHECKSUM } else { 0 };
+        let reply = query(&mut handle, Request::Stat, name, flags, &[]).await?;
+        Ok(reply.and_then(|(_, entries)| entries.into_iter().next()))
+    }
+
+    /// Bring the server's copy of the file or directory at `path` up to date,
+    /// sending only the files it is missing or holds an older or different
+    /// copy of, at most `concurrency` at a time.
+    ///
+    /// The server compares a manifest of the local files with what it has
+    /// stored under the same names as [`collect_uploads`] would give them.
+    pub async fn sync(
+        self: &Arc<Self>,
+        path: &Path,
+        options: &SyncOptions,
+        concurrency: usize,
+    ) -> Result<SyncReport> {
+        let path = &named_path(path).await?;
+        let uploads = collect_uploads(path).await?;
+        let prefix = root_name(path)?;
+        let base = path.parent().unwrap_or(Path::new(""));
+        let mut manifest = Vec::with_capacity(uploads.len());
+        for upload in &uploads {
+            let local = base.join(&upload.file_name);
+            let metadata = fs::metadata(&local).await?;
+            let checksum = if options.checksum {
+                Some(hash_file(&local).await?)
+            } else {
+                None
+            };
+            manifest.push(FileEntry {
+                name: upload.file_name.clone(),
+                size: metadata.len(),
+                modified: metadata.modified().ok(),
+                checksum,
+            });
+        }
+
+        let mut flags = 0;
+        if options.checksum {
+            flags |= FLAG_CHECKSUM;
+        }
+        if options.delete {
+            flags |= FLAG_DELETE;
+        }
+        let mut handle = self.connection().await?;
+        let (negotiated, reply) = query(&mut handle, Request::Sync, &prefix, flags, &manifest)
+            .await?
+            .ok_or_else(|| Error::Rejected {
+                status: NegotiationStatus::NotFound,
+                reason: format!("server refused to sync '{prefix}'"),
+            })?;
+        if options.delete && negotiated & FLAG_DELETE == 0 {
+            eprintln!("Server does not allow deletions; no files were removed");
+        }
+
+        // Entries for files in the manifest are wanted, any others were removed.
+        let listed: HashMap<&str, &FileEntry> = manifest
+            .iter()
+            .map(|entry| (entry.name.as_str(), entry))
+            .collect();
+        let mut wanted = HashSet::new();
+        let mut removed = Vec::new();
+        for entry in reply {
+            let Some(local) = listed.get(entry.name.as_str()) else {
+                removed.push(entry.name);
+                continue;
+            };
+            // The server could not tell from the modification time and sent
+            // the digest of its copy instead.
+            if let Some(held) = entry.checksum {
+                let digest = match local.checksum {
+                    Some(digest) => digest,
+                    None => hash_file(&base.join(&entry.name)).await?,
+                };
+                if digest == held {
+                    continue;
+                }
+            }
+            wanted.insert(entry.name);
+        }
+        let total = uploads.len();
+        // Syncing exists to replace outdated copies, so it always overwrites,
+        // and sends modification times for the server to compare next time.
+        let pending: Vec<_> = uploads
+            .into_iter()
+            .filter(|upload| wanted.contains(&upload.file_name))
+            .map(|upload| Upload {
+                overwrite: true,
+                metadata: if self.options.metadata {
+                    upload.metadata
+                } else {
+                    upload.metadata.map(|metadata| FileMetadata {
+                        modified: metadata.modified,
+                        ..FileMetadata::default()
+                    })
+                },
+                ..upload
+            })
+            .collect();
+        let unchanged = total - pending.len();
+
+        let options = SendOptions {
+            metadata: true,
+            ..self.options.clone()
+        };
+        let sent = self.send_each(pending, concurrency, options).await?;
+        Ok(SyncReport {
+            sent,
+            unchanged,
+            removed,
+        })
+    }
+
+    /// Send every upload, at most `concurrency` at a time, and return each
+    /// upload with its result in the original order.
+    pub async fn send_all(
+        self: &Arc<Self>,
+        uploads: Vec<Upload>,
+        concurrency: usize,
+    ) -> Result<Vec<(Upload, Result<Delivery>)>> {
+        self.send_each(uploads, concurrency, self.options.clone())
+            .await
+    }
+
+    async fn send_each(
+        self: &Arc<Self>,
+        uploads: Vec<Upload>,
+        concurrency: usize,
+        options: SendOptions,
+    ) -> Result<Vec<(Upload, Result<Delivery>)>> {
+        let options = Arc::new(options);
+        let limit = Arc::new(Semaphore::new(concurrency.max(1)));
+        let mut tasks = JoinSet::new();
+        for (index, upload) in uploads.into_iter().enumerate() {
+            let permit = Arc::clone(&limit)
+                .acquire_owned()
+                .await
+                .expect("the semaphore is never closed");
+            let sender = Arc::clone(self);
+            let options = Arc::clone(&options);
+            tasks.spawn(async move {
+                let result = sender.send_with(&upload, &options).await;
+                drop(permit);
+                (index, upload, result)
+            });
+        }
+
+        let mut results = Vec::with_capacity(tasks.len());
+        while let Some(joined) = tasks.join_next().await {
+            results.push(joined?);
+        }
+        results.sort_by_key(|(index, ..)| *index);
+        Ok(results
+            .into_iter()
+            .map(|(_, upload, result)| (upload, result))
+            .collect())
+    }
+}
+
+/// Make one attempt at delivering `upload`, resuming wherever the server left off.
+///
+/// Large files are split into up to `options.streams` ranges sent
+/// concurrently on the same connection, unless `options.delta` asks for only
+/// the changes to the server's copy to be sent; sources of unknown size are
+/// streamed until they end. `options.retries` is ignored: use [`Sender`] to
+/// have lost connections re-established and interrupted uploads resumed.
+pub async fn send_file(
+    connection: &mut Handle,
+    upload: &Upload,
+    restart: bool,
+    options: &SendOptions,
+) -> Result<Outcome> {
+    let replayable = upload.source.replayable();
+    let size = upload.source.size();
+    let delta = options.delta && replayable && size.is_some_and(|size| size >= MIN_DELTA_LEN);
+    let compression = match options.compression {
+        Some(compression) if compressible(upload).await? => Some(compression),
+        _ => None,
+    };
+    let ranges = match size {
+        Some(size) if replayable && !delta => split_ranges(size, options.streams),
+        Some(size) => vec![(0, size)],
+        None => vec![(0, 0)],
+    };
+    let mut flags = FLAG_CHECKSUM | FLAG_RESUME | FLAG_RANGES;
+    if delta {
+        flags |= FLAG_DELTA;
+    }
+    if let Some(compression) = compression {
+        flags |= compression.flag();
+    }
+    if upload.overwrite {
+        flags |= FLAG_OVERWRITE;
+    }
+    if options.metadata && upload.metadata.is_some() {
+        flags |= FLAG_METADATA;
+    }
+    if ranges.len() == 1 {
+        flags &= !FLAG_RANGES;
+    }
+    if !replayable {
+        flags &= !(FLAG_RESUME | FLAG_RANGES);
+    }
+    if upload.source.size().is_none() {
+        flags |= FLAG_STREAMING;
+    }
+
+    let mut tasks = JoinSet::new();
+    for range in ranges {
+        let stream = connection.open_bidirectional_stream().await?;
+        let upload = upload.clone();
+        tasks.spawn(send_range(
+            stream,
+            upload,
+            range,
+            restart,
+            flags,
+            compression,
+        ));
+    }
+
+    let mut sent = 0;
+    let mut skipped = 0;
+    let mut compressed = None;
+    let mut committed = None;
+    while let Some(result) = tasks.join_next().await {
+        match result?? {
+            Outcome::Delivered(range) => {
+                sent += range.sent;
+                skipped += range.skipped;
+                if let Some(wire) = range.compressed {
+                    *compressed.get_or_insert(0) += wire;
+                }
+                if range.response.status == TransferStatus::Ok {
+                    committed = Some(range.response);
+                }
+            }
+            rejected @ Outcome::Rejected { .. } => return Ok(rejected),
+        }
+    }
+
+    match committed {
+        Some(response) => Ok(Outcome::Delivered(Delivery {
+            sent,
+            skipped,
+            compressed,
+            response,
+        })),
+        None => Err(Error::Protocol(
+            "server staged every range but did not commit the file".into(),
+        )),
+    }
+}
+
+/// Send one range of `upload` on its own stream.
+async fn send_range(
+    mut stream: BidirectionalStream,
+    upload: Upload,
+    (range_start, range_end): (u64, u64),
+    restart: bool,
+    flags: u32,
+    compression: Option<Compression>,
+) -> Result<Outcome> {
+    let header = encode_header(&FileHeader {
+        version: PROTOCOL_VERSION,
+        flags,
+        request: Request::Upload,
+        file_name: upload.file_name.clone(),
+        file_size: upload.source.size().unwrap_or(0),
+        offset: if restart { range_start } else { range_end },
+        range_start,
+        range_end,
+        transfer_id: upload.transfer_id,
+        metadata: upload
+            .metadata
+            .clone()
+            .filter(|_| flags & FLAG_METADATA != 0),
+    })?;
+    stream.send(Bytes::from(header)).await?;
+
+    let mut buffer = Vec::new();
+    let negotiation = next_frame(&mut stream, &mut buffer, try_decode_negotiation).await?;
+    match negotiation.status {
+        NegotiationStatus::Accepted => {}
+        NegotiationStatus::UnsupportedVersion => {
+            return Ok(Outcome::Rejected {
+                status: negotiation.status,
+                reason: format!(
+                    "server speaks protocol version {}, we speak {PROTOCOL_VERSION}",
+                    negotiation.version
+                ),
+            });
+        }
+        status @ NegotiationStatus::TransferInProgress => {
+            return Err(Error::Rejected {
+                status,
+                reason: "server is still receiving an earlier attempt".into(),
+            });
+        }
+        NegotiationStatus::InvalidPath => {
+            return Ok(Outcome::Rejected {
+                status: negotiation.status,
+                reason: format!("'{}' is not a valid path on the server", upload.file_name),
+            });
+        }
+        NegotiationStatus::InvalidRange
+            if flags & FLAG_RANGES != negotiation.flags & FLAG_RANGES =>
+        {
+            return Ok(Outcome::Rejected {
+                status: negotiation.status,
+                reason: format!(
+                    "server does not accept multi-stream uploads of '{}'",
+                    upload.file_name
+                ),
+            });
+        }
+        NegotiationStatus::InvalidRange => {
+            return Ok(Outcome::Rejected {
+                status: negotiation.status,
+                reason: format!(
+                    "server refused range {range_start}..{range_end} of '{}'",
+                    upload.file_name
+                ),
+            });
+        }
+        NegotiationStatus::AlreadyExists => {
+            return Ok(Outcome::Rejected {
+                status: negotiation.status,
+                reason: format!("'{}' already exists on the server", upload.file_name),
+            });
+        }
+        NegotiationStatus::TooLarge => {
+            return Ok(Outcome::Rejected {
+                status: negotiation.status,
+                reason: format!("'{}' is larger than the server accepts", upload.file_name),
+            });
+        }
+        NegotiationStatus::QuotaExceeded => {
+            return Ok(Outcome::Rejected {
+                status: negotiation.status,
+                reason: format!(
+                    "storing '{}' would exceed the server's quota",
+                    upload.file_name
+                ),
+            });
+        }
+        NegotiationStatus::InsufficientStorage => {
+            return Ok(Outcome::Rejected {
+                status: negotiation.status,
+                reason: format!(
+                    "the server does not have enough free space for '{}'",
+                    upload.file_name
+                ),
+            });
+        }
+        status @ NegotiationStatus::Busy => return Err(busy(status)),
+        status @ (NegotiationStatus::UnsupportedRequest | NegotiationStatus::NotFound) => {
+            return Err(Error::Protocol(format!(
+                "unexpected reply to an upload request: {status:?}"
+            )));
+        }
+    }
+    if negotiation.offset < range_start || negotiation.offset > range_end {
+        return Err(Error::Protocol(format!(
+            "server asked to resume at byte {} of range {range_start}..{range_end}",
+            negotiation.offset
+        )));
+    }
+
+    let streaming = flags & FLAG_STREAMING != 0;
+    if streaming && negotiation.flags & FLAG_STREAMING == 0 {
+        return Ok(Outcome::Rejected {
+            status: NegotiationStatus::UnsupportedRequest,
+            reason: format!(
+                "server does not accept uploads of unknown size such as '{}'",
+                upload.file_name
+            ),
+        });
+    }
+
+    if negotiation.flags & FLAG_DELTA != 0 {
+        let file_size = upload.source.size().unwrap_or(0);
+        let signature = next_frame(&mut stream, &mut buffer, |buf| {
+            delta::try_decode_signature(buf, file_size)
+        })
+        .await?;
+        let reader = upload.source.open(0).await?;
+        let stats = delta::send_delta(&mut stream, reader, &signature).await?;
+        let response = finish_upload(&mut stream).await?;
+        return Ok(Outcome::Delivered(Delivery {
+            sent: stats.literal,
+            skipped: stats.copied,
+            compressed: None,
+            response,
+        }));
+    }
+
+    let checksum = negotiation.flags & FLAG_CHECKSUM != 0;
+    let open_at = if checksum {
+        range_start
+    } else {
+        negotiation.offset
+    };
+    let mut reader = upload.source.open(open_at).await?;
+    let mut buffer = vec![0u8; 64 * 1024];
+    let mut hasher = blake3::Hasher::new();
+
+    if checksum {
+        // The digest covers the whole range, so the skipped prefix still has to be hashed.
+        let mut prefix = (&mut reader).take(negotiation.offset - range_start);
+        loop {
+            let bytes_read = prefix.read(&mut buffer).await?;
+            if bytes_read == 0 {
+                break;
+            }
+            hasher.update(&buffer[..bytes_read]);
+        }
+    }
+
+    let body_len = if streaming {
+        u64::MAX
+    } else {
+        range_end - negotiation.offset
+    };
+    let mut encoder = compression
+        .filter(|compression| negotiation.flags & compression.flag() != 0)
+        .map(Encoder::new);
+    let mut body = reader.take(body_len);
+    let mut total_sent: u64 = 0;
+    let mut wire: u64 = 0;
+    loop {
+        let bytes_read = body.read(&mut buffer).await?;
+        if bytes_read == 0 {
+            break;
+        }
+
+        hasher.update(&buffer[..bytes_read]);
+        wire += send_body(&mut stream, &mut encoder, &buffer[..bytes_read]).await?;
+        total_sent += bytes_read as u64;
+    }
+
+    if checksum {
+        let digest = hasher.finalize();
+        wire += send_body(&mut stream, &mut encoder, digest.as_bytes()).await?;
+    }
+    if let Some(encoder) = encoder.as_mut() {
+        let rest = encoder.finish()?;
+        wire += rest.len() as u64;
+        stream.send(Bytes::from(rest)).await?;
+    }
+
+    let response = finish_upload(&mut stream).await?;
+    Ok(Outcome::Delivered(Delivery {
+        sent: total_sent,
+        skipped: negotiation.offset - range_start,
+        compressed: encoder.is_some().then_some(wire),
+        response,
+    }))
+}
+
+/// Send `data` of an upload body, through `encoder` if it is compressed,
+/// and return how many bytes went on the wire.
+async fn send_body(
+    stream: &mut BidirectionalStream,
+    encoder: &mut Option<Encoder>,
+    data: &[u8],
+) -> Result<u64> {
+    let wire = match encoder {
+        Some(encoder) => Bytes::from(encoder.encode(data)?),
+        None => Bytes::copy_from_slice(data),
+    };
+    let len = wire.len() as u64;
+    if !wire.is_empty() {
+        stream.send(wire).await?;
+    }
+    Ok(len)
+}
+
+/// Whether `upload` is worth compressing, judging by its name and, for
+/// sources that can be read again, a sample of its contents.
+async fn compressible(upload: &Upload) -> Result<bool> {
+    let mut sample = Vec::new();
+    if upload.source.replayable() {
+        let reader = upload.source.open(0).await?;
+        reader
+            .take(COMPRESSION_SAMPLE_LEN)
+            .read_to_end(&mut sample)
+            .await?;
+    }
+    Ok(worth_compressing(&upload.file_name, &sample))
+}
+
+/// Close our half of an upload stream and wait for the server's verdict.
+async fn finish_upload(stream: &mut BidirectionalStream) -> Result<TransferResponse> {
+    stream.close().await?;
+    let response = receive_frame(stream, try_decode_response).await?;
+    if !matches!(
+        response.status,
+        TransferStatus::Ok | TransferStatus::RangeStored
+    ) {
+        return Err(Error::NotStored {
+            status: response.status,
+            detail: response.detail,
+        });
+    }
+    Ok(response)
+}
+
+/// Make one attempt at fetching the exported file `name` into `dest`.
+///
+/// The body is staged in a hidden file next to `dest`, which `partial`
+/// remembers across attempts so that a retry only fetches what is missing.
+async fn fetch_file(
+    connection: &mut Handle,
+    name: &str,
+    dest: &Path,
+    partial: &mut Option<PartialDownload>,
+) -> Result<Outcome<Download>> {
+    let (offset, transfer_id) = match partial {
+        Some(partial) => {
+            let held = fs::metadata(&partial.path).await.map_or(0, |m| m.len());
+            (held, partial.transfer_id)
+        }
+        None => (0, [0u8; TRANSFER_ID_LEN]),
+    };
+
+    let mut stream = connection.open_bidirectional_stream().await?;
+    let request = encode_header(&FileHeader {
+        version: PROTOCOL_VERSION,
+        flags: FLAG_CHECKSUM | FLAG_RESUME,
+        request: Request::Download,
+        file_name: name.to_string(),
+        file_size: 0,
+        offset,
+        range_start: 0,
+        range_end: 0,
+        transfer_id,
+        metadata: None,
+    })?;
+    stream.send(Bytes::from(request)).await?;
+    stream.close().await?;
+
+    let mut buffer = Vec::new();
+    let negotiation = next_frame(&mut stream, &mut buffer, try_decode_negotiation).await?;
+    match negotiation.status {
+        NegotiationStatus::Accepted => {}
+        NegotiationStatus::UnsupportedVersion => {
+            return Ok(Outcome::Rejected {
+                status: negotiation.status,
+                reason: format!(
+                    "server speaks protocol version {}, we speak {PROTOCOL_VERSION}",
+                    negotiation.version
+                ),
+            });
+        }
+        NegotiationStatus::UnsupportedRequest => {
+            return Ok(Outcome::Rejected {
+                status: negotiation.status,
+                reason: "server does not export files".to_string(),
+            });
+        }
+        NegotiationStatus::NotFound => {
+            return Ok(Outcome::Rejected {
+                status: negotiation.status,
+                reason: format!("'{name}' is not exported by the server"),
+            });
+        }
+        NegotiationStatus::InvalidPath => {
+            return Ok(Outcome::Rejected {
+                status: negotiation.status,
+                reason: format!("'{name}' is not a valid path on the server"),
+            });
+        }
+        status @ NegotiationStatus::Busy => return Err(busy(status)),
+        status @ (NegotiationStatus::TransferInProgress
+        | NegotiationStatus::InvalidRange
+        | NegotiationStatus::AlreadyExists
+        | NegotiationStatus::TooLarge
+        | NegotiationStatus::QuotaExceeded
+        | NegotiationStatus::InsufficientStorage) => {
+            return Err(Error::Protocol(format!(
+                "unexpected reply to a download request: {status:?}"
+            )));
+        }
+    }
+
+    let remote = next_frame(&mut stream, &mut buffer, try_decode_header).await?;
+    let offset = negotiation.offset;
+    if offset > remote.file_size {
+        return Err(Error::Protocol(format!(
+            "server offered to resume at byte {offset} of a {} byte file",
+            remote.file_size
+        )));
+    }
+
+    // Anything staged for another version of the file is useless now.
+    let staging_path = match partial.take() {
+        Some(held) if held.transfer_id == remote.transfer_id && offset > 0 => held.path,
+        stale => {
+            if let Some(stale) = stale {
+                let _ = fs::remove_file(&stale.path).await;
+            }
+            let dir = dest.parent().unwrap_or(Path::new("."));
+            fs::create_dir_all(dir).await?;
+            dir.join(format!(
+                ".quic3-{}.download",
+                format_transfer_id(&remote.transfer_id)
+            ))
+        }
+    };
+    *partial = Some(PartialDownload {
+        path: staging_path.clone(),
+        transfer_id: remote.transfer_id,
+    });
+
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&staging_path)
+        .await?;
+    file.set_len(offset).await?;
+    let checksum = negotiation.flags & FLAG_CHECKSUM != 0;
+    let mut hasher = blake3::Hasher::new();
+    if checksum && offset > 0 {
+        let mut prefix = (&mut file).take(offset);
+        let mut chunk = vec![0u8; 64 * 1024];
+        loop {
+            let bytes_read = prefix.read(&mut chunk).await?;
+            if bytes_read == 0 {
+                break;
+            }
+            hasher.update(&chunk[..bytes_read]);
+        }
+    }
+    file.seek(SeekFrom::Start(offset)).await?;
+
+    let mut written = offset;
+    let mut trailer = Vec::new();
+    let mut data = Bytes::from(buffer);
+    loop {
+        let body_len = (remote.file_size - written).min(data.len() as u64) as usize;
+        file.write_all(&data[..body_len]).await?;
+        hasher.update(&data[..body_len]);
+        written += body_len as u64;
+        // Anything longer than a checksum is invalid, so there is no point buffering it all.
+        let rest = &data[body_len..];
+        let room = (CHECKSUM_LEN + 1).saturating_sub(trailer.len());
+        trailer.extend_from_slice(&rest[..rest.len().min(room)]);
+
+        match stream.receive().await? {
+            Some(chunk) => data = chunk,
+            None => break,
+        }
+    }
+    file.flush().await?;
+    file.sync_all().await?;
+    drop(file);
+
+    let trailer_len = if checksum { CHECKSUM_LEN } else { 0 };
+    let verified = written == remote.file_size
+        && trailer.len() == trailer_len
+        && (!checksum || hasher.finalize().as_bytes()[..] == trailer[..]);
+    if !verified {
+        *partial = None;
+        let _ = fs::remove_file(&staging_path).await;
+        return Err(Error::Protocol(format!(
+            "'{name}' did not match the size and checksum sent by the server"
+        )));
+    }
+
+    fs::rename(&staging_path, dest).await?;
+    *partial = None;
+    Ok(Outcome::Delivered(Download {
+        path: dest.to_path_buf(),
+        size: remote.file_size,
+        received: written - offset,
+        skipped: offset,
+    }))
+}
+
+/// Send a list, stat or sync request, followed by `manifest`, and collect
+/// the entries the server replies with together with the flags in effect,
+/// or `None` if it has no such file.
+async fn query(
+    connection: &mut Handle,
+    request: Request,
+    name: &str,
+    flags: u32,
+    manifest: &[FileEntry],
+) -> Result<Option<(u32, Vec<FileEntry>)>> {
+    let mut stream = connection.open_bidirectional_stream().await?;
+    let header = encode_header(&FileHeader {
+        version: PROTOCOL_VERSION,
+        flags,
+        request,
+        file_name: name.to_string(),
+        file_size: 0,
+        offset: 0,
+        range_start: 0,
+        range_end: 0,
+        transfer_id: [0u8; TRANSFER_ID_LEN],
+        metadata: None,
+    })?;
+    stream.send(Bytes::from(header)).await?;
+    for entry in manifest {
+        stream.send(Bytes::from(encode_entry(entry)?)).await?;
+    }
+    stream.close().await?;
+
+    let mut buffer = Vec::new();
+    let negotiation = next_frame(&mut stream, &mut buffer, try_decode_negotiation).await?;
+    match negotiation.status {
+        NegotiationStatus::Accepted => {}
+        NegotiationStatus::NotFound => return Ok(None),
+        status => {
+            let reason = match status {
+                NegotiationStatus::UnsupportedVersion => format!(
+                    "server speaks protocol version {}, we speak {PROTOCOL_VERSION}",
+                    negotiation.version
+                ),
+                NegotiationStatus::InvalidPath => {
+                    format!("'{name}' is not a valid path on the server")
+                }
+                NegotiationStatus::Busy => "server is busy".to_string(),
+                status => format!("server refused the request: {status:?}"),
+            };
+            return Err(Error::Rejected { status, reason });
+        }
+    }
+
+    let mut entries = Vec::new();
+    loop {
+        if let Some((entry, used)) = try_decode_entry(&buffer)? {
+            buffer.drain(..used);
+            entries.push(entry);
+            continue;
+        }
+        match stream.receive().await? {
+            Some(data) => buffer.extend_from_slice(&data),
+            None if buffer.is_empty() => break,
+            None => {
+                return Err(Error::Protocol(
+                    "server closed the stream in the middle of an entry".into(),
+                ));
+            }
+        }
+    }
+    Ok(Some((negotiation.flags, entries)))
+}
+
+/// The error for a server too busy to take the request, which is worth
+/// retrying later.
+fn busy(status: NegotiationStatus) -> Error {
+    Error::Rejected {
+        status,
+        reason: "server is busy".into(),
+    }
+}
+
+/// Split a file into at most `streams` ranges of roughly equal length, none
+/// shorter than [`MIN_RANGE_LEN`] unless the file itself is.
+fn split_ranges(file_size: u64, streams: usize) -> Vec<(u64, u64)> {
+    let count = (file_size / MIN_RANGE_LEN).clamp(1, streams.max(1) as u64);
+    let len = file_size.div_ceil(count);
+    (0..count)
+        .map(|index| (index * len, ((index + 1) * len).min(file_size)))
+        .collect()
+}
+
+/// Collect the files to send for `path`: the file itself, or every regular
+/// file below a directory, named by its path relative to the directory's parent.
+pub async fn collect_uploads(path: &Path) -> Result<Vec<Upload>> {
+    let path = &named_path(path).await?;
+    let metadata = fs::metadata(path).await?;
+    let root_name = root_name(path)?;
+
+    if !metadata.is_dir() {
+        return Ok(vec![Upload {
+            transfer_id: transfer_id_for(path, &metadata).await?,
+            source: Arc::new(FileSource::new(path, metadata.len())),
+            file_name: root_name,
+            overwrite: false,
+            metadata: Some(read_metadata(path).await?),
+        }]);
+    }
+
+    let mut uploads = Vec::new();
+    let mut pending = vec![(path.to_path_buf(), root_name)];
+    while let Some((dir, prefix)) = pending.pop() {
+        let mut entries = fs::read_dir(&dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let entry_path = entry.path();
+            let name = format!("{prefix}/{}", entry.file_name().to_string_lossy());
+            let file_type = entry.file_type().await?;
+            if file_type.is_dir() {
+                pending.push((entry_path, name));
+            } else if file_type.is_file() {
+                let metadata = entry.metadata().await?;
+                uploads.push(Upload {
+                    transfer_id: transfer_id_for(&entry_path, &metadata).await?,
+                    metadata: Some(read_metadata(&entry_path).await?),
+                    source: Arc::new(FileSource::new(entry_path, metadata.len())),
+                    file_name: name,
+                    overwrite: false,
+                });
+            } else {
+                eprintln!("Skipping '{}': not a regular file", entry_path.display());
+            }
+        }
+    }
+
+    uploads.sort_by(|a, b| a.file_name.cmp(&b.file_name));
+    Ok(uploads)
+}
+
+/// `path`, resolved to its canonical form if it does not end in a name, as
+/// with `.` or `photos/..`, so that it has a last component and a parent.
+async fn named_path(path: &Path) -> Result<PathBuf> {
+    if path.file_name().is_some() {
+        return Ok(path.to_path_buf());
+    }
+    Ok(fs::canonicalize(path).await?)
+}
+
+/// The name `path` is sent under: its last component.
+fn root_name(path: &Path) -> Result<String> {
+    let name = path.file_name().ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("'{}' does not name a file", path.display()),
+        )
+    })?;
+    Ok(name.to_string_lossy().to_string())
+}
+
+/// Wait for a complete reply frame from the server on `stream`.
+async fn receive_frame<T, F>(stream: &mut BidirectionalStream, decode: F) -> Result<T>
+where
+    F: Fn(&[u8]) -> Result<Option<(T, usize)>, CodecError>,
+{
+    next_frame(stream, &mut Vec::new(), decode).await
+}
+
+/// Wait for the next frame from the server on `stream`, leaving whatever
+/// follows it in `buffer`.
+pub(crate) async fn next_frame<T, F>(
+    stream: &mut BidirectionalStream,
+    buffer: &mut Vec<u8>,
+    decode: F,
+) -> Result<T>
+where
+    F: Fn(&[u8]) -> Result<Option<(T, usize)>, CodecError>,
+{
+    loop {
+        if let Some((frame, used)) = decode(buffer)? {
+            buffer.drain(..used);
+            return Ok(frame);
+        }
+        match stream.receive().await? {
+            Some(data) => buffer.extend_from_slice(&data),
+            None => {
+                return Err(Error::Protocol(
+                    "server closed the stream before replying".into(),
+                ));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(uploads: &[Upload]) -> Vec<&str> {
+        uploads
+            .iter()
+            .map(|upload| upload.file_name.as_str())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn directories_are_named_after_their_last_component() {
+        let root = tempfile::tempdir().unwrap();
+        let photos = root.path().join("photos");
+        std::fs::create_dir_all(photos.join("trip")).unwrap();
+        std::fs::write(photos.join("a.jpg"), b"a").unwrap();
+        std::fs::write(photos.join("trip/b.jpg"), b"bb").unwrap();
+
+        let uploads = collect_uploads(&photos).await.unwrap();
+        assert_eq!(names(&uploads), ["photos/a.jpg", "photos/trip/b.jpg"]);
+        let uploads = collect_uploads(&photos.join("a.jpg")).await.unwrap();
+        assert_eq!(names(&uploads), ["a.jpg"]);
+    }
+
+    #[tokio::test]
+    async fn paths_without_a_last_component_use_the_directory_name() {
+        let root = tempfile::tempdir().unwrap();
+        let photos = root.path().join("photos");
+        std::fs::create_dir_all(photos.join("trip")).unwrap();
+        std::fs::write(photos.join("a.jpg"), b"a").unwrap();
+
+        for path in [photos.join("."), photos.join("trip/..")] {
+            let uploads = collect_uploads(&path).await.unwrap();
+            assert_eq!(names(&uploads), ["photos/a.jpg"], "{}", path.display());
+        }
+    }
+
+    #[test]
+    fn the_filesystem_root_has_no_name() {
+        assert!(root_name(Path::new("/")).is_err());
+    }
+}
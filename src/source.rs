@@ -0,0 +1,202 @@
+//! Where uploaded bytes come from.
+//!
+//! Every [`crate::sender::Upload`] reads its body from a [`TransferSource`]:
+//! a file, an in-memory buffer, or any reader such as standard input or a
+//! pipe from `tar`, whose length may only be known once it is exhausted.
+
+use crate::{FileMetadata, Result, TRANSFER_ID_LEN, TransferId};
+use bytes::Bytes;
+use std::future::Future;
+use std::io::{Cursor, ErrorKind, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::time::UNIX_EPOCH;
+use tokio::fs::{self, File};
+use tokio::io::{AsyncRead, AsyncSeekExt, BufReader};
+
+/// Reader returned by [`TransferSource::open`].
+pub type SourceReader = Box<dyn AsyncRead + Send + Unpin>;
+
+/// Future returned by [`TransferSource::open`].
+pub type OpenFuture<'a> = Pin<Box<dyn Future<Output = Result<SourceReader>> + Send + 'a>>;
+
+/// Contents of one upload.
+///
+/// Sources that can be read more than once from any offset can be resumed
+/// after a lost connection and split across several streams; one-shot
+/// sources are sent front to back in a single attempt.
+pub trait TransferSource: Send + Sync {
+    /// Length in bytes, or `None` if it is only known once the source is
+    /// exhausted. Such uploads are streamed until the end of the source.
+    fn size(&self) -> Option<u64>;
+
+    /// Whether [`open`](TransferSource::open) may be called again, at any offset.
+    fn replayable(&self) -> bool {
+        true
+    }
+
+    /// Read the source starting `offset` bytes in.
+    fn open(&self, offset: u64) -> OpenFuture<'_>;
+}
+
+/// A file on the local filesystem.
+#[derive(Debug, Clone)]
+pub struct FileSource {
+    path: PathBuf,
+    size: u64,
+}
+
+impl FileSource {
+    /// Send the file at `path`, which is expected to stay `size` bytes long.
+    pub fn new(path: impl Into<PathBuf>, size: u64) -> Self {
+        Self {
+            path: path.into(),
+            size,
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+/// Read the permissions, times, owner and extended attributes of the file at
+/// `path`. Extended attributes are left out where the filesystem has none,
+/// as are those whose names are not UTF-8.
+pub async fn read_metadata(path: &Path) -> Result<FileMetadata> {
+    let path = path.to_path_buf();
+    tokio::task::spawn_blocking(move || {
+        let stat = std::fs::metadata(&path)?;
+        let mut metadata = FileMetadata {
+            modified: stat.modified().ok(),
+            accessed: stat.accessed().ok(),
+            ..FileMetadata::default()
+        };
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            metadata.mode = Some(stat.mode() & 0o7777);
+            metadata.owner = Some((stat.uid(), stat.gid()));
+            if let Ok(names) = xattr::list(&path) {
+                for name in names {
+                    if let (Some(utf8), Ok(Some(value))) = (name.to_str(), xattr::get(&path, &name))
+                    {
+                        metadata.xattrs.push((utf8.to_string(), value));
+                    }
+                }
+            }
+        }
+        Ok(metadata)
+    })
+    .await?
+}
+
+/// Derive a transfer ID that stays the same for as long as the file is unchanged.
+pub(crate) async fn transfer_id_for(
+    path: &Path,
+    metadata: &std::fs::Metadata,
+) -> Result<TransferId> {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(fs::canonicalize(path).await?.as_os_str().as_encoded_bytes());
+    hasher.update(&metadata.len().to_le_bytes());
+    if let Ok(modified) = metadata.modified() {
+        let since_epoch = modified.duration_since(UNIX_EPOCH).unwrap_or_default();
+        hasher.update(&since_epoch.as_nanos().to_le_bytes());
+    }
+
+    let mut transfer_id = [0u8; TRANSFER_ID_LEN];
+    transfer_id.copy_from_slice(&hasher.finalize().as_bytes()[..TRANSFER_ID_LEN]);
+    Ok(transfer_id)
+}
+
+impl TransferSource for FileSource {
+    fn size(&self) -> Option<u64> {
+        Some(self.size)
+    }
+
+    fn open(&self, offset: u64) -> OpenFuture<'_> {
+        Box::pin(async move {
+            let mut file = File::open(&self.path).await?;
+            file.seek(SeekFrom::Start(offset)).await?;
+            Ok(Box::new(BufReader::new(file)) as SourceReader)
+        })
+    }
+}
+
+/// An in-memory buffer.
+#[derive(Debug, Clone)]
+pub struct BytesSource(Bytes);
+
+impl BytesSource {
+    pub fn new(data: impl Into<Bytes>) -> Self {
+        Self(data.into())
+    }
+}
+
+impl TransferSource for BytesSource {
+    fn size(&self) -> Option<u64> {
+        Some(self.0.len() as u64)
+    }
+
+    fn open(&self, offset: u64) -> OpenFuture<'_> {
+        let start = usize::try_from(offset).map_or(self.0.len(), |start| start.min(self.0.len()));
+        let data = self.0.slice(start..);
+        Box::pin(async move { Ok(Box::new(Cursor::new(data)) as SourceReader) })
+    }
+}
+
+/// A reader that can only be consumed once, such as standard input, a pipe
+/// or a generator of data.
+pub struct ReaderSource {
+    reader: Mutex<Option<SourceReader>>,
+    size: Option<u64>,
+}
+
+impl ReaderSource {
+    /// Send everything `reader` produces; pass its length as `size` if known.
+    pub fn new(reader: impl AsyncRead + Send + Unpin + 'static, size: Option<u64>) -> Self {
+        Self {
+            reader: Mutex::new(Some(Box::new(reader))),
+            size,
+        }
+    }
+
+    /// Standard input, read until end of file.
+    pub fn stdin() -> Self {
+        Self::new(tokio::io::stdin(), None)
+    }
+}
+
+impl TransferSource for ReaderSource {
+    fn size(&self) -> Option<u64> {
+        self.size
+    }
+
+    fn replayable(&self) -> bool {
+        false
+    }
+
+    fn open(&self, offset: u64) -> OpenFuture<'_> {
+        let reader = self
+            .reader
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .take();
+        Box::pin(async move {
+            match reader {
+                Some(reader) if offset == 0 => Ok(reader),
+                Some(_) => Err(std::io::Error::new(
+                    ErrorKind::Unsupported,
+                    "a one-shot source can only be read from the start",
+                )
+                .into()),
+                None => Err(std::io::Error::new(
+                    ErrorKind::Unsupported,
+                    "a one-shot source can only be read once",
+                )
+                .into()),
+            }
+        })
+    }
+}
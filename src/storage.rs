@@ -0,0 +1,188 @@
+//! Where received files are written.
+//!
+//! A [`crate::receiver::Receiver`] streams every upload into a
+//! [`StorageSink`]: the sink stages the bytes while they arrive and only
+//! publishes them under their final name once the receiver has verified them.
+
+mod local;
+mod memory;
+mod object;
+
+pub use local::{LocalStaged, LocalStorage};
+pub(crate) use local::{find_file, hash_file};
+pub use memory::{MemoryStaged, MemoryStorage};
+pub use object::{ObjectStoreSink, ObjectUpload};
+
+use crate::{FileEntry, FileMetadata, TransferId};
+use anyhow::{Result, anyhow};
+use std::future::Future;
+use std::time::Duration;
+
+/// Destination for received files.
+///
+/// Every upload is [opened](StorageSink::open), written with
+/// [`write_at`](StorageSink::write_at) and finally either
+/// [committed](StorageSink::commit) under its name or
+/// [aborted](StorageSink::abort). Names are the relative `/`-separated paths
+/// sent by the client and must be checked with
+/// [`validate`](StorageSink::validate) first.
+pub trait StorageSink: Send + Sync + 'static {
+    /// A file being received, from `open` until `commit` or `abort`.
+    type Staged: Send;
+
+    /// Whether staged uploads can outlive a failed attempt and be written out
+    /// of order, as resumable and ranged uploads require. The receiver does
+    /// not offer either feature to clients of sinks that return `false`, so
+    /// those only ever see whole files written front to back.
+    fn supports_resume(&self) -> bool {
+        false
+    }
+
+    /// Check that `name` can be stored, without changing anything.
+    /// Errors are reported to the client as an invalid path.
+    fn validate(&self, name: &str) -> impl Future<Output = Result<()>> + Send;
+
+    /// Start staging the file stored as `name`, preparing its location if
+    /// needed.
+    ///
+    /// With `resume` set the staged bytes are keyed by the transfer ID, are
+    /// shared by every range of that transfer and survive an aborted attempt;
+    /// the returned length is how many bytes are already staged. Otherwise
+    /// staging starts empty.
+    fn open(
+        &self,
+        name: &str,
+        resume: Option<&TransferId>,
+    ) -> impl Future<Output = Result<(Self::Staged, u64)>> + Send;
+
+    /// Write `data` at `offset` into the staged file.
+    fn write_at(
+        &self,
+        staged: &mut Self::Staged,
+        offset: u64,
+        data: &[u8],
+    ) -> impl Future<Output = Result<()>> + Send;
+
+    /// Read staged bytes starting at `offset`, returning how many were read.
+    fn read_at(
+        &self,
+        _staged: &mut Self::Staged,
+        _offset: u64,
+        _buf: &mut [u8],
+    ) -> impl Future<Output = Result<usize>> + Send {
+        async { Err(unsupported()) }
+    }
+
+    /// Discard staged bytes past `len`.
+    fn truncate(
+        &self,
+        _staged: &mut Self::Staged,
+        _len: u64,
+    ) -> impl Future<Output = Result<()>> + Send {
+        async { Err(unsupported()) }
+    }
+
+    /// Make everything written so far durable.
+    fn sync(&self, _staged: &mut Self::Staged) -> impl Future<Output = Result<()>> + Send {
+        async { Ok(()) }
+    }
+
+    /// Publish the staged file under `name`, returning where it was stored.
+    /// If this fails, resumable staging is kept so a retry can commit it
+    /// without resending; anything else is discarded.
+    fn commit(
+        &self,
+        staged: Self::Staged,
+        name: &str,
+    ) -> impl Future<Output = Result<String>> + Send;
+
+    /// Give up on a staged file. Resumable staging is kept for a later
+    /// attempt when `keep` is set and discarded otherwise.
+    fn abort(&self, staged: Self::Staged, keep: bool) -> impl Future<Output = Result<()>> + Send;
+
+    /// Committed files below the directory `prefix`, or every file if it is
+    /// empty, sorted by name. Checksums are only computed if `checksum` is set.
+    fn list(
+        &self,
+        prefix: &str,
+        checksum: bool,
+    ) -> impl Future<Output = Result<Vec<FileEntry>>> + Send;
+
+    /// The committed file `name`, or `None` if there is none.
+    fn stat(
+        &self,
+        name: &str,
+        checksum: bool,
+    ) -> impl Future<Output = Result<Option<FileEntry>>> + Send;
+
+    /// Read the committed file `name` starting at `offset`, returning how many
+    /// bytes were read. Delta uploads need this to reuse parts of the stored
+    /// copy; sinks that cannot read back what they stored never get them.
+    fn read_committed(
+        &self,
+        _name: &str,
+        _offset: u64,
+        _buf: &mut [u8],
+    ) -> impl Future<Output = Result<usize>> + Send {
+        async { Err(anyhow!("this storage cannot read stored files")) }
+    }
+
+    /// Delete the committed file `name`, as a sync request with
+    /// [`crate::FLAG_DELETE`] does for files the client no longer has.
+    fn remove(&self, name: &str) -> impl Future<Output = Result<()>> + Send;
+
+    /// Move the committed file `from` to `to`, replacing anything stored
+    /// there, as the receiver does to keep older versions of a file.
+    fn rename(&self, from: &str, to: &str) -> impl Future<Output = Result<()>> + Send;
+
+    /// Bytes available for new files, or `None` if that is unknown. The
+    /// receiver uses it to keep a minimum of free space.
+    fn free_space(&self) -> impl Future<Output = Result<Option<u64>>> + Send {
+        async { Ok(None) }
+    }
+
+    /// Whether committed files can be given the attributes sent with an
+    /// upload. The receiver does not negotiate [`crate::FLAG_METADATA`] with
+    /// clients of sinks that return `false`.
+    fn supports_metadata(&self) -> bool {
+        false
+    }
+
+    /// Give the committed file `name` the attributes set in `metadata`,
+    /// leaving the others alone.
+    fn set_metadata(
+        &self,
+        _name: &str,
+        _metadata: &FileMetadata,
+    ) -> impl Future<Output = Result<()>> + Send {
+        async { Err(anyhow!("this storage does not keep file metadata")) }
+    }
+
+    /// Ranges of a ranged upload that have been verified so far.
+    fn completed_ranges(
+        &self,
+        _transfer_id: &TransferId,
+    ) -> impl Future<Output = Result<Vec<(u64, u64)>>> + Send {
+        async { Ok(Vec::new()) }
+    }
+
+    /// Persist that a range of a ranged upload has been verified. The log is
+    /// dropped when the upload is committed.
+    fn record_range(
+        &self,
+        _transfer_id: &TransferId,
+        _range: (u64, u64),
+    ) -> impl Future<Output = Result<()>> + Send {
+        async { Err(unsupported()) }
+    }
+
+    /// Remove staging left behind by uploads that never completed, keeping
+    /// resumable ones younger than `partial_ttl`. Returns how many were removed.
+    fn remove_stale(&self, _partial_ttl: Duration) -> impl Future<Output = Result<usize>> + Send {
+        async { Ok(0) }
+    }
+}
+
+fn unsupported() -> anyhow::Error {
+    anyhow!("this storage does not support resumable uploads")
+}
@@ -0,0 +1,569 @@
+use super::StorageSink;
+use crate::{
+    CHECKSUM_LEN, FileEntry, FileMetadata, TransferId, format_transfer_id, sanitize_relative_path,
+};
+use anyhow::Result;
+use std::fs::Metadata;
+use std::io::{ErrorKind, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::fs::{self, File, OpenOptions};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+/// Staging files are hidden and carry a suffix no complete transfer would use.
+const TEMP_PREFIX: &str = ".quic3-";
+const TEMP_SUFFIX: &str = ".tmp";
+const PARTIAL_SUFFIX: &str = ".partial";
+const RANGES_SUFFIX: &str = ".ranges";
+
+/// Stores files in a directory on the local filesystem.
+///
+/// Uploads are staged as hidden files inside the directory so that committing
+/// one is a same-filesystem rename.
+pub struct LocalStorage {
+    root: PathBuf,
+}
+
+/// A file being staged by [`LocalStorage`].
+pub struct LocalStaged {
+    path: PathBuf,
+    file: File,
+    position: u64,
+    transfer_id: Option<TransferId>,
+}
+
+impl LocalStorage {
+    /// Store files under `root`, creating the directory if needed.
+    pub async fn new(root: impl Into<PathBuf>) -> Result<Self> {
+        let root = root.into();
+        fs::create_dir_all(&root).await?;
+        Ok(Self { root })
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Map a peer-supplied relative path onto a location under the root.
+    ///
+    /// With `create`, missing parent directories are created one component at
+    /// a time so that a symlink planted inside the root can never redirect the
+    /// write elsewhere; existing symlinks and non-directories along the way are
+    /// refused either way.
+    async fn resolve(&self, name: &str, create: bool) -> Result<PathBuf> {
+        let relative = sanitize_relative_path(name)?;
+        let mut components = relative.components().peekable();
+        if let Some(first) = components.peek()
+            && first.as_os_str().to_string_lossy().starts_with(TEMP_PREFIX)
+        {
+            anyhow::bail!("names starting with '{TEMP_PREFIX}' are reserved for staging files");
+        }
+
+        let mut target = self.root.clone();
+        while let Some(component) = components.next() {
+            target.push(component);
+            if components.peek().is_none() {
+                break;
+            }
+
+            match fs::symlink_metadata(&target).await {
+                Ok(metadata) if metadata.file_type().is_symlink() => {
+                    anyhow::bail!("'{}' is a symlink", target.display())
+                }
+                Ok(metadata) if metadata.is_dir() => {}
+                Ok(_) => anyhow::bail!("'{}' is not a directory", target.display()),
+                Err(err) if err.kind() == ErrorKind::NotFound => {
+                    if create
+                        && let Err(err) = fs::create_dir(&target).await
+                        && err.kind() != ErrorKind::AlreadyExists
+                    {
+                        return Err(err.into());
+                    }
+                }
+                Err(err) => return Err(err.into()),
+            }
+        }
+        Ok(target)
+    }
+
+    /// Pick a fresh hidden staging path inside the root.
+    fn temp_path(&self) -> PathBuf {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        self.root.join(format!(
+            "{TEMP_PREFIX}{}-{id}{TEMP_SUFFIX}",
+            std::process::id()
+        ))
+    }
+
+    /// Staging path under which a resumable transfer is kept between attempts.
+    fn partial_path(&self, transfer_id: &TransferId) -> PathBuf {
+        self.root.join(format!(
+            "{TEMP_PREFIX}{}{PARTIAL_SUFFIX}",
+            format_transfer_id(transfer_id)
+        ))
+    }
+
+    /// Log of verified ranges for a ranged upload, kept next to its partial file.
+    fn ranges_path(&self, transfer_id: &TransferId) -> PathBuf {
+        self.root.join(format!(
+            "{TEMP_PREFIX}{}{RANGES_SUFFIX}",
+            format_transfer_id(transfer_id)
+        ))
+    }
+}
+
+impl StorageSink for LocalStorage {
+    type Staged = LocalStaged;
+
+    fn supports_resume(&self) -> bool {
+        true
+    }
+
+    async fn validate(&self, name: &str) -> Result<()> {
+        self.resolve(name, false).await.map(drop)
+    }
+
+    async fn open(&self, name: &str, resume: Option<&TransferId>) -> Result<(LocalStaged, u64)> {
+        self.resolve(name, true).await?;
+        let (path, file) = match resume {
+            Some(transfer_id) => {
+                let path = self.partial_path(transfer_id);
+                let file = OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .create(true)
+                    .truncate(false)
+                    .open(&path)
+                    .await?;
+                (path, file)
+            }
+            None => {
+                let path = self.temp_path();
+                let file = OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .create_new(true)
+                    .open(&path)
+                    .await?;
+                (path, file)
+            }
+        };
+
+        let held = file.metadata().await?.len();
+        let staged = LocalStaged {
+            path,
+            file,
+            position: 0,
+            transfer_id: resume.copied(),
+        };
+        Ok((staged, held))
+    }
+
+    async fn write_at(&self, staged: &mut LocalStaged, offset: u64, data: &[u8]) -> Result<()> {
+        if staged.position != offset {
+            staged.file.seek(SeekFrom::Start(offset)).await?;
+        }
+        staged.file.write_all(data).await?;
+        staged.position = offset + data.len() as u64;
+        Ok(())
+    }
+
+    async fn read_at(
+        &self,
+        staged: &mut LocalStaged,
+        offset: u64,
+        buf: &mut [u8],
+    ) -> Result<usize> {
+        staged.file.seek(SeekFrom::Start(offset)).await?;
+        let bytes_read = staged.file.read(buf).await?;
+        staged.position = offset + bytes_read as u64;
+        Ok(bytes_read)
+    }
+
+    async fn truncate(&self, staged: &mut LocalStaged, len: u64) -> Result<()> {
+        staged.file.set_len(len).await?;
+        Ok(())
+    }
+
+    async fn sync(&self, staged: &mut LocalStaged) -> Result<()> {
+        staged.file.flush().await?;
+        staged.file.sync_all().await?;
+        Ok(())
+    }
+
+    /// Move the staging file to its final location and make the rename durable.
+    async fn commit(&self, staged: LocalStaged, name: &str) -> Result<String> {
+        drop(staged.file);
+        let renamed = match self.resolve(name, true).await {
+            Ok(target) => fs::rename(&staged.path, &target)
+                .await
+                .map(|()| target)
+                .map_err(Into::into),
+            Err(err) => Err(err),
+        };
+        let target = match renamed {
+            Ok(target) => target,
+            Err(err) => {
+                if staged.transfer_id.is_none() {
+                    let _ = fs::remove_file(&staged.path).await;
+                }
+                return Err(err);
+            }
+        };
+        if let Some(dir) = target.parent() {
+            File::open(dir).await?.sync_all().await?;
+        }
+        if let Some(transfer_id) = &staged.transfer_id {
+            let _ = fs::remove_file(self.ranges_path(transfer_id)).await;
+        }
+        Ok(target.display().to_string())
+    }
+
+    async fn abort(&self, staged: LocalStaged, keep: bool) -> Result<()> {
+        if keep && staged.transfer_id.is_some() {
+            staged.file.sync_all().await?;
+            return Ok(());
+        }
+        drop(staged.file);
+        fs::remove_file(&staged.path).await?;
+        Ok(())
+    }
+
+    async fn list(&self, prefix: &str, checksum: bool) -> Result<Vec<FileEntry>> {
+        let mut pending = Vec::new();
+        if prefix.is_empty() {
+            pending.push((self.root.clone(), String::new()));
+        } else if let Some((dir, metadata)) = find(&self.root, prefix).await?
+            && metadata.is_dir()
+        {
+            let relative = sanitize_relative_path(prefix)?;
+            let name = relative.to_string_lossy().replace('\\', "/");
+            pending.push((dir, format!("{name}/")));
+        }
+
+        let mut entries = Vec::new();
+        while let Some((dir, prefix)) = pending.pop() {
+            let mut children = fs::read_dir(&dir).await?;
+            while let Some(child) = children.next_entry().await? {
+                let file_name = child.file_name().to_string_lossy().to_string();
+                if file_name.starts_with(TEMP_PREFIX) {
+                    continue;
+                }
+                let name = format!("{prefix}{file_name}");
+                let metadata = fs::symlink_metadata(child.path()).await?;
+                if metadata.is_dir() {
+                    pending.push((child.path(), format!("{name}/")));
+                } else if metadata.is_file() {
+                    entries.push(entry(name, &child.path(), &metadata, checksum).await?);
+                }
+            }
+        }
+        entries.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(entries)
+    }
+
+    async fn stat(&self, name: &str, checksum: bool) -> Result<Option<FileEntry>> {
+        let Some(path) = find_file(&self.root, name).await? else {
+            return Ok(None);
+        };
+        let metadata = fs::metadata(&path).await?;
+        let name = sanitize_relative_path(name)?
+            .to_string_lossy()
+            .replace('\\', "/");
+        Ok(Some(entry(name, &path, &metadata, checksum).await?))
+    }
+
+    async fn read_committed(&self, name: &str, offset: u64, buf: &mut [u8]) -> Result<usize> {
+        let Some(path) = find_file(&self.root, name).await? else {
+            anyhow::bail!("'{name}' does not exist");
+        };
+        let mut file = File::open(&path).await?;
+        file.seek(SeekFrom::Start(offset)).await?;
+        let mut filled = 0;
+        while filled < buf.len() {
+            let bytes_read = file.read(&mut buf[filled..]).await?;
+            if bytes_read == 0 {
+                break;
+            }
+            filled += bytes_read;
+        }
+        Ok(filled)
+    }
+
+    async fn remove(&self, name: &str) -> Result<()> {
+        let Some(path) = find_file(&self.root, name).await? else {
+            return Ok(());
+        };
+        fs::remove_file(&path).await?;
+
+        // Drop directories the file leaves empty, but never the root itself.
+        let mut dir = path.parent();
+        while let Some(parent) = dir.filter(|parent| *parent != self.root) {
+            if fs::remove_dir(parent).await.is_err() {
+                break;
+            }
+            dir = parent.parent();
+        }
+        Ok(())
+    }
+
+    async fn rename(&self, from: &str, to: &str) -> Result<()> {
+        let Some(source) = find_file(&self.root, from).await? else {
+            anyhow::bail!("'{from}' is not stored");
+        };
+        let target = self.resolve(to, true).await?;
+        fs::rename(&source, &target).await?;
+        Ok(())
+    }
+
+    async fn free_space(&self) -> Result<Option<u64>> {
+        #[cfg(unix)]
+        {
+            let stats = rustix::fs::statvfs(&self.root)?;
+            Ok(Some(stats.f_bavail.saturating_mul(stats.f_frsize)))
+        }
+        #[cfg(not(unix))]
+        {
+            Ok(None)
+        }
+    }
+
+    fn supports_metadata(&self) -> bool {
+        true
+    }
+
+    async fn set_metadata(&self, name: &str, metadata: &FileMetadata) -> Result<()> {
+        let Some(path) = find_file(&self.root, name).await? else {
+            anyhow::bail!("'{name}' is not stored");
+        };
+        let metadata = metadata.clone();
+        tokio::task::spawn_blocking(move || apply_metadata(&path, &metadata)).await?
+    }
+
+    async fn completed_ranges(&self, transfer_id: &TransferId) -> Result<Vec<(u64, u64)>> {
+        let ranges_path = self.ranges_path(transfer_id);
+        let contents = match fs::read_to_string(&ranges_path).await {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(err.into()),
+        };
+
+        let mut ranges = Vec::new();
+        for line in contents.lines() {
+            let parsed = line
+                .split_once(' ')
+                .and_then(|(start, end)| Some((start.parse().ok()?, end.parse().ok()?)));
+            match parsed {
+                Some(range) => ranges.push(range),
+                None => anyhow::bail!("corrupt range log {}", ranges_path.display()),
+            }
+        }
+        Ok(ranges)
+    }
+
+    async fn record_range(&self, transfer_id: &TransferId, range: (u64, u64)) -> Result<()> {
+        let mut log = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.ranges_path(transfer_id))
+            .await?;
+        log.write_all(format!("{} {}\n", range.0, range.1).as_bytes())
+            .await?;
+        log.sync_all().await?;
+        Ok(())
+    }
+
+    async fn remove_stale(&self, partial_ttl: Duration) -> Result<usize> {
+        let mut removed = 0;
+        let mut entries = fs::read_dir(&self.root).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            if !name.starts_with(TEMP_PREFIX) {
+                continue;
+            }
+
+            let stale = if name.ends_with(TEMP_SUFFIX) {
+                true
+            } else if name.ends_with(PARTIAL_SUFFIX) || name.ends_with(RANGES_SUFFIX) {
+                let modified = entry.metadata().await?.modified()?;
+                modified.elapsed().unwrap_or_default() > partial_ttl
+            } else {
+                false
+            };
+            if stale {
+                fs::remove_file(entry.path()).await?;
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+}
+
+/// Find the regular file `name` under `root`, returning `None` if it does not exist.
+///
+/// Names are sanitized like upload names; paths through symlinks and staging
+/// files are refused.
+pub(crate) async fn find_file(root: &Path, name: &str) -> Result<Option<PathBuf>> {
+    match find(root, name).await? {
+        Some((path, metadata)) if metadata.is_file() => Ok(Some(path)),
+        Some((path, _)) => anyhow::bail!("'{}' is not a regular file", path.display()),
+        None => Ok(None),
+    }
+}
+
+/// Locate `name` under `root` without following symlinks.
+async fn find(root: &Path, name: &str) -> Result<Option<(PathBuf, Metadata)>> {
+    let relative = sanitize_relative_path(name)?;
+    let mut path = root.to_path_buf();
+    let mut found = None;
+    for component in relative.components() {
+        if component
+            .as_os_str()
+            .to_string_lossy()
+            .starts_with(TEMP_PREFIX)
+        {
+            anyhow::bail!("names starting with '{TEMP_PREFIX}' are reserved for staging files");
+        }
+        path.push(component);
+        match fs::symlink_metadata(&path).await {
+            Ok(metadata) if metadata.file_type().is_symlink() => {
+                anyhow::bail!("'{}' is a symlink", path.display())
+            }
+            Ok(metadata) => found = Some(metadata),
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        }
+    }
+    Ok(found.map(|metadata| (path, metadata)))
+}
+
+/// Describe the file at `path`, hashing its contents if `checksum` is set.
+async fn entry(
+    name: String,
+    path: &Path,
+    metadata: &Metadata,
+    checksum: bool,
+) -> Result<FileEntry> {
+    let checksum = if checksum {
+        Some(hash_file(path).await?)
+    } else {
+        None
+    };
+    Ok(FileEntry {
+        name,
+        size: metadata.len(),
+        modified: metadata.modified().ok(),
+        checksum,
+    })
+}
+
+/// BLAKE3 digest of the contents of the file at `path`.
+pub(crate) async fn hash_file(path: &Path) -> std::io::Result<[u8; CHECKSUM_LEN]> {
+    let mut file = File::open(path).await?;
+    let mut hasher = blake3::Hasher::new();
+    let mut buffer = vec![0u8; 64 * 1024];
+    loop {
+        let bytes_read = file.read(&mut buffer).await?;
+        if bytes_read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..bytes_read]);
+    }
+    Ok(*hasher.finalize().as_bytes())
+}
+
+/// Give the file at `path` the attributes set in `metadata`. Every attribute
+/// is attempted even if an earlier one fails. The mode is set last, so that
+/// neither a new owner nor a read-only mode gets in the way of the others.
+fn apply_metadata(path: &Path, metadata: &FileMetadata) -> Result<()> {
+    let mut failures = Vec::new();
+    #[cfg(unix)]
+    {
+        if let Some((uid, gid)) = metadata.owner
+            && let Err(err) = std::os::unix::fs::chown(path, Some(uid), Some(gid))
+        {
+            failures.push(format!("owner: {err}"));
+        }
+        for (name, value) in &metadata.xattrs {
+            if let Err(err) = xattr::set(path, name, value) {
+                failures.push(format!("attribute '{name}': {err}"));
+            }
+        }
+    }
+
+    let mut times = std::fs::FileTimes::new();
+    if let Some(modified) = metadata.modified {
+        times = times.set_modified(modified);
+    }
+    if let Some(accessed) = metadata.accessed {
+        times = times.set_accessed(accessed);
+    }
+    if metadata.modified.is_some() || metadata.accessed.is_some() {
+        let result = std::fs::File::open(path).and_then(|file| file.set_times(times));
+        if let Err(err) = result {
+            failures.push(format!("times: {err}"));
+        }
+    }
+
+    #[cfg(unix)]
+    if let Some(mode) = metadata.mode {
+        use std::os::unix::fs::PermissionsExt;
+        let permissions = std::fs::Permissions::from_mode(mode & 0o7777);
+        if let Err(err) = std::fs::set_permissions(path, permissions) {
+            failures.push(format!("mode: {err}"));
+        }
+    }
+
+    if failures.is_empty() {
+        Ok(())
+    } else {
+        anyhow::bail!("could not set {}", failures.join(", "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn validate_leaves_the_root_alone() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = LocalStorage::new(dir.path()).await.unwrap();
+        storage.validate("a/b/c.txt").await.unwrap();
+        assert!(!dir.path().join("a").exists());
+
+        std::fs::write(dir.path().join("file"), b"").unwrap();
+        assert!(storage.validate("file/c.txt").await.is_err());
+        assert!(storage.validate(".quic3-x/c.txt").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn open_creates_parent_directories() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = LocalStorage::new(dir.path()).await.unwrap();
+        let (mut staged, held) = storage.open("a/b/c.txt", None).await.unwrap();
+        assert_eq!(held, 0);
+        assert!(dir.path().join("a/b").is_dir());
+
+        storage.write_at(&mut staged, 0, b"hello").await.unwrap();
+        storage.commit(staged, "a/b/c.txt").await.unwrap();
+        assert_eq!(
+            std::fs::read(dir.path().join("a/b/c.txt")).unwrap(),
+            b"hello"
+        );
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn symlinked_directories_are_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let outside = tempfile::tempdir().unwrap();
+        std::os::unix::fs::symlink(outside.path(), dir.path().join("link")).unwrap();
+        let storage = LocalStorage::new(dir.path()).await.unwrap();
+        assert!(storage.validate("link/c.txt").await.is_err());
+        assert!(storage.open("link/c.txt", None).await.is_err());
+    }
+}
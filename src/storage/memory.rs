@@ -0,0 +1,252 @@
+use super::StorageSink;
+use crate::{FileEntry, FileMetadata, TransferId, sanitize_relative_path};
+use anyhow::Result;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Mutex, MutexGuard};
+use std::time::SystemTime;
+
+/// Keeps received files in memory, for tests and for services that process
+/// uploads without storing them.
+#[derive(Default)]
+pub struct MemoryStorage {
+    inner: Mutex<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    files: BTreeMap<String, Vec<u8>>,
+    /// Modification times given to committed files; the only metadata kept.
+    modified: HashMap<String, SystemTime>,
+    partials: HashMap<TransferId, Vec<u8>>,
+    ranges: HashMap<TransferId, Vec<(u64, u64)>>,
+}
+
+/// A file being staged by [`MemoryStorage`]. Resumable uploads are staged in
+/// the storage itself so that every range of a transfer shares one buffer.
+pub struct MemoryStaged {
+    transfer_id: Option<TransferId>,
+    data: Vec<u8>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Contents of the committed file `name`.
+    pub fn file(&self, name: &str) -> Option<Vec<u8>> {
+        let key = normalize(name).ok()?;
+        self.lock().files.get(&key).cloned()
+    }
+
+    /// Names of all committed files, sorted.
+    pub fn names(&self) -> Vec<String> {
+        self.lock().files.keys().cloned().collect()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Inner> {
+        self.inner.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    /// Run `f` on the bytes staged for `staged`.
+    fn with_data<T>(&self, staged: &mut MemoryStaged, f: impl FnOnce(&mut Vec<u8>) -> T) -> T {
+        match &staged.transfer_id {
+            Some(transfer_id) => f(self.lock().partials.entry(*transfer_id).or_default()),
+            None => f(&mut staged.data),
+        }
+    }
+}
+
+/// The canonical `/`-separated form of a peer-supplied name.
+fn normalize(name: &str) -> Result<String> {
+    let relative = sanitize_relative_path(name)?;
+    let parts: Vec<_> = relative
+        .components()
+        .map(|component| component.as_os_str().to_string_lossy())
+        .collect();
+    Ok(parts.join("/"))
+}
+
+impl StorageSink for MemoryStorage {
+    type Staged = MemoryStaged;
+
+    fn supports_resume(&self) -> bool {
+        true
+    }
+
+    async fn validate(&self, name: &str) -> Result<()> {
+        normalize(name).map(drop)
+    }
+
+    async fn open(&self, _name: &str, resume: Option<&TransferId>) -> Result<(MemoryStaged, u64)> {
+        let held = match resume {
+            Some(transfer_id) => self.lock().partials.entry(*transfer_id).or_default().len(),
+            None => 0,
+        };
+        let staged = MemoryStaged {
+            transfer_id: resume.copied(),
+            data: Vec::new(),
+        };
+        Ok((staged, held as u64))
+    }
+
+    async fn write_at(&self, staged: &mut MemoryStaged, offset: u64, data: &[u8]) -> Result<()> {
+        let start = usize::try_from(offset)?;
+        self.with_data(staged, |buffer| {
+            let end = start + data.len();
+            if buffer.len() < end {
+                buffer.resize(end, 0);
+            }
+            buffer[start..end].copy_from_slice(data);
+        });
+        Ok(())
+    }
+
+    async fn read_at(
+        &self,
+        staged: &mut MemoryStaged,
+        offset: u64,
+        buf: &mut [u8],
+    ) -> Result<usize> {
+        let start = usize::try_from(offset)?;
+        Ok(self.with_data(staged, |buffer| {
+            let available = buffer.get(start..).unwrap_or_default();
+            let len = available.len().min(buf.len());
+            buf[..len].copy_from_slice(&available[..len]);
+            len
+        }))
+    }
+
+    async fn truncate(&self, staged: &mut MemoryStaged, len: u64) -> Result<()> {
+        let len = usize::try_from(len)?;
+        self.with_data(staged, |buffer| buffer.truncate(len));
+        Ok(())
+    }
+
+    async fn commit(&self, staged: MemoryStaged, name: &str) -> Result<String> {
+        let key = normalize(name)?;
+        let mut inner = self.lock();
+        let data = match &staged.transfer_id {
+            Some(transfer_id) => {
+                inner.ranges.remove(transfer_id);
+                inner.partials.remove(transfer_id).unwrap_or_default()
+            }
+            None => staged.data,
+        };
+        inner.modified.remove(&key);
+        inner.files.insert(key.clone(), data);
+        Ok(format!("memory:{key}"))
+    }
+
+    async fn abort(&self, staged: MemoryStaged, keep: bool) -> Result<()> {
+        if let Some(transfer_id) = &staged.transfer_id
+            && !keep
+        {
+            self.lock().partials.remove(transfer_id);
+        }
+        Ok(())
+    }
+
+    async fn list(&self, prefix: &str, checksum: bool) -> Result<Vec<FileEntry>> {
+        let dir = if prefix.is_empty() {
+            String::new()
+        } else {
+            format!("{}/", normalize(prefix)?)
+        };
+        let inner = self.lock();
+        Ok(inner
+            .files
+            .iter()
+            .filter(|(name, _)| name.starts_with(&dir))
+            .map(|(name, data)| inner.entry(name, data, checksum))
+            .collect())
+    }
+
+    async fn stat(&self, name: &str, checksum: bool) -> Result<Option<FileEntry>> {
+        let name = normalize(name)?;
+        let inner = self.lock();
+        Ok(inner
+            .files
+            .get(&name)
+            .map(|data| inner.entry(&name, data, checksum)))
+    }
+
+    async fn read_committed(&self, name: &str, offset: u64, buf: &mut [u8]) -> Result<usize> {
+        let name = normalize(name)?;
+        let inner = self.lock();
+        let Some(data) = inner.files.get(&name) else {
+            anyhow::bail!("'{name}' does not exist");
+        };
+        let start = usize::try_from(offset).map_or(data.len(), |start| start.min(data.len()));
+        let len = buf.len().min(data.len() - start);
+        buf[..len].copy_from_slice(&data[start..start + len]);
+        Ok(len)
+    }
+
+    async fn remove(&self, name: &str) -> Result<()> {
+        let name = normalize(name)?;
+        let mut inner = self.lock();
+        inner.files.remove(&name);
+        inner.modified.remove(&name);
+        Ok(())
+    }
+
+    async fn rename(&self, from: &str, to: &str) -> Result<()> {
+        let (from, to) = (normalize(from)?, normalize(to)?);
+        let mut state = self.lock();
+        let Some(contents) = state.files.remove(&from) else {
+            anyhow::bail!("'{from}' is not stored");
+        };
+        match state.modified.remove(&from) {
+            Some(modified) => state.modified.insert(to.clone(), modified),
+            None => state.modified.remove(&to),
+        };
+        state.files.insert(to, contents);
+        Ok(())
+    }
+
+    fn supports_metadata(&self) -> bool {
+        true
+    }
+
+    async fn set_metadata(&self, name: &str, metadata: &FileMetadata) -> Result<()> {
+        let name = normalize(name)?;
+        let mut inner = self.lock();
+        if !inner.files.contains_key(&name) {
+            anyhow::bail!("'{name}' is not stored");
+        }
+        if let Some(modified) = metadata.modified {
+            inner.modified.insert(name, modified);
+        }
+        Ok(())
+    }
+
+    async fn completed_ranges(&self, transfer_id: &TransferId) -> Result<Vec<(u64, u64)>> {
+        Ok(self
+            .lock()
+            .ranges
+            .get(transfer_id)
+            .cloned()
+            .unwrap_or_default())
+    }
+
+    async fn record_range(&self, transfer_id: &TransferId, range: (u64, u64)) -> Result<()> {
+        self.lock()
+            .ranges
+            .entry(*transfer_id)
+            .or_default()
+            .push(range);
+        Ok(())
+    }
+}
+
+impl Inner {
+    fn entry(&self, name: &str, data: &[u8], checksum: bool) -> FileEntry {
+        FileEntry {
+            name: name.to_string(),
+            size: data.len() as u64,
+            modified: self.modified.get(name).copied(),
+            checksum: checksum.then(|| *blake3::hash(data).as_bytes()),
+        }
+    }
+}
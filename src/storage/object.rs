@@ -0,0 +1,200 @@
+use super::StorageSink;
+use crate::{CHECKSUM_LEN, FileEntry, TransferId, sanitize_relative_path};
+use anyhow::{Context, Result};
+use futures::TryStreamExt;
+use object_store::path::Path as ObjectPath;
+use object_store::{ObjectMeta, ObjectStore, WriteMultipart};
+use std::sync::Arc;
+use url::Url;
+
+/// Size of the parts a file is uploaded in; S3 requires at least 5 MiB.
+const PART_SIZE: usize = 8 * 1024 * 1024;
+
+/// Parts of one file being uploaded at the same time.
+const MAX_CONCURRENT_PARTS: usize = 4;
+
+/// Streams received files straight into an object store such as S3.
+///
+/// Each file is written as a multipart upload to its final key, which only
+/// becomes visible once the upload completes, so a file that fails
+/// verification never appears. Object stores cannot write at arbitrary
+/// offsets, so resumable and ranged uploads are not offered.
+pub struct ObjectStoreSink {
+    store: Arc<dyn ObjectStore>,
+    prefix: ObjectPath,
+    /// Prepended to object keys when reporting where a file was stored.
+    base_url: String,
+}
+
+/// A file being uploaded by [`ObjectStoreSink`].
+pub struct ObjectUpload {
+    writer: WriteMultipart,
+    location: ObjectPath,
+    position: u64,
+}
+
+impl ObjectStoreSink {
+    /// Store files in `store` below `prefix`.
+    pub fn new(store: Arc<dyn ObjectStore>, prefix: ObjectPath) -> Self {
+        Self {
+            store,
+            prefix,
+            base_url: String::new(),
+        }
+    }
+
+    /// Open the store named by a URL such as `s3://bucket/prefix`.
+    ///
+    /// Credentials, region and endpoint are read from the usual `AWS_*`
+    /// environment variables; point `AWS_ENDPOINT` at an S3-compatible server
+    /// and set `AWS_ALLOW_HTTP=true` to use a local stand-in. `file://` and
+    /// `memory://` URLs are accepted as well.
+    pub fn from_url(url: &str) -> Result<Self> {
+        let url = Url::parse(url).with_context(|| format!("invalid store URL '{url}'"))?;
+        let options = std::env::vars()
+            .filter(|(key, _)| key.starts_with("AWS_"))
+            .map(|(key, value)| (key.to_ascii_lowercase(), value));
+        let (store, prefix) = object_store::parse_url_opts(&url, options)?;
+        let mut base_url = url.clone();
+        base_url.set_path("/");
+        Ok(Self {
+            base_url: base_url.to_string(),
+            ..Self::new(Arc::from(store), prefix)
+        })
+    }
+
+    /// Key under which the file `name` is stored.
+    fn location(&self, name: &str) -> Result<ObjectPath> {
+        let relative = sanitize_relative_path(name)?;
+        let mut location = self.prefix.clone();
+        for component in relative.components() {
+            location = location.child(component.as_os_str().to_string_lossy().as_ref());
+        }
+        Ok(location)
+    }
+
+    /// Describe a stored object, downloading it to hash it if `checksum` is set.
+    async fn entry(&self, meta: ObjectMeta, checksum: bool) -> Result<Option<FileEntry>> {
+        let Some(parts) = meta.location.prefix_match(&self.prefix) else {
+            return Ok(None);
+        };
+        let name = parts
+            .map(|part| part.as_ref().to_string())
+            .collect::<Vec<_>>()
+            .join("/");
+        let checksum = if checksum {
+            Some(self.hash(&meta.location).await?)
+        } else {
+            None
+        };
+        Ok(Some(FileEntry {
+            name,
+            size: meta.size,
+            modified: Some(meta.last_modified.into()),
+            checksum,
+        }))
+    }
+
+    async fn hash(&self, location: &ObjectPath) -> Result<[u8; CHECKSUM_LEN]> {
+        let mut chunks = self.store.get(location).await?.into_stream();
+        let mut hasher = blake3::Hasher::new();
+        while let Some(chunk) = chunks.try_next().await? {
+            hasher.update(&chunk);
+        }
+        Ok(*hasher.finalize().as_bytes())
+    }
+}
+
+impl StorageSink for ObjectStoreSink {
+    type Staged = ObjectUpload;
+
+    async fn validate(&self, name: &str) -> Result<()> {
+        self.location(name).map(drop)
+    }
+
+    async fn open(&self, name: &str, resume: Option<&TransferId>) -> Result<(ObjectUpload, u64)> {
+        if resume.is_some() {
+            anyhow::bail!("object storage does not support resumable uploads");
+        }
+        let location = self.location(name)?;
+        let upload = self.store.put_multipart(&location).await?;
+        let staged = ObjectUpload {
+            writer: WriteMultipart::new_with_chunk_size(upload, PART_SIZE),
+            location,
+            position: 0,
+        };
+        Ok((staged, 0))
+    }
+
+    async fn write_at(&self, staged: &mut ObjectUpload, offset: u64, data: &[u8]) -> Result<()> {
+        if offset != staged.position {
+            anyhow::bail!(
+                "object storage only accepts sequential writes (expected offset {}, got {offset})",
+                staged.position
+            );
+        }
+        staged
+            .writer
+            .wait_for_capacity(MAX_CONCURRENT_PARTS)
+            .await?;
+        staged.writer.write(data);
+        staged.position += data.len() as u64;
+        Ok(())
+    }
+
+    async fn commit(&self, staged: ObjectUpload, _name: &str) -> Result<String> {
+        staged.writer.finish().await?;
+        Ok(format!("{}{}", self.base_url, staged.location))
+    }
+
+    async fn abort(&self, staged: ObjectUpload, _keep: bool) -> Result<()> {
+        staged.writer.abort().await?;
+        Ok(())
+    }
+
+    async fn list(&self, prefix: &str, checksum: bool) -> Result<Vec<FileEntry>> {
+        let dir = if prefix.is_empty() {
+            self.prefix.clone()
+        } else {
+            self.location(prefix)?
+        };
+        let objects: Vec<ObjectMeta> = self.store.list(Some(&dir)).try_collect().await?;
+        let mut entries = Vec::with_capacity(objects.len());
+        for meta in objects {
+            entries.extend(self.entry(meta, checksum).await?);
+        }
+        entries.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(entries)
+    }
+
+    async fn stat(&self, name: &str, checksum: bool) -> Result<Option<FileEntry>> {
+        match self.store.head(&self.location(name)?).await {
+            Ok(meta) => self.entry(meta, checksum).await,
+            Err(object_store::Error::NotFound { .. }) => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    async fn read_committed(&self, name: &str, offset: u64, buf: &mut [u8]) -> Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let range = offset..offset + buf.len() as u64;
+        let data = self.store.get_range(&self.location(name)?, range).await?;
+        buf[..data.len()].copy_from_slice(&data);
+        Ok(data.len())
+    }
+
+    async fn remove(&self, name: &str) -> Result<()> {
+        match self.store.delete(&self.location(name)?).await {
+            Ok(()) | Err(object_store::Error::NotFound { .. }) => Ok(()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    async fn rename(&self, from: &str, to: &str) -> Result<()> {
+        let (from, to) = (self.location(from)?, self.location(to)?);
+        self.store.rename(&from, &to).await?;
+        Ok(())
+    }
+}